@@ -33,6 +33,7 @@ let
           name = "rt." + (lib.removeSuffix ".nix" file);
           imports = [ (libDir + "/${file}") ];
           desc = getDescription file;
+          origin = "upstream";
         })
         nixFiles
     else
@@ -76,15 +77,33 @@ let
     # Force evaluation by accessing the derivation path
     builtins.seq eval.config.system.build.toplevel.drvPath true;
 
-  # Generate the modules.json content
-  modulesJson = builtins.toJSON {
+  # Generate the modules.json file; written to the store so quotes in health
+  # checks or descriptions never meet the shell
+  modulesJson = pkgs.writeText "runtime-modules.json" (builtins.toJSON {
     modules = map
       (module: {
         inherit (module) name desc;
+        deps = module.deps or [ ];
+        conflicts = module.conflicts or [ ];
+        protected = module.protected or false;
+        keep_on_reset = module.keepOnReset or false;
+        origin = module.origin or "user";
+        tags = module.tags or [ ];
+        health_check = module.healthCheck or null;
+        # Lets path arguments name the module; only a single file import has one
+        path =
+          let
+            inherit (module) imports;
+            single = builtins.head imports;
+          in
+          if builtins.length imports == 1 && (builtins.isPath single || builtins.isString single)
+          then toString single
+          else "";
         state = "Disabled";
       })
       allModules;
-  };
+    inherit (cfg) sets;
+  });
 
   # Build the Rust program
   moduleManagerRust = pkgs.callPackage ../package.nix {
@@ -109,6 +128,8 @@ let
             stateFile = ${dataDir}/state.json;
             state = builtins.fromJSON (builtins.readFile stateFile);
             enabledNames = state.enabled or [];
+            enabledSettings = state.settings or {};
+            enabledPaths = state.paths or {};
 
             # Get all module definitions from base config
             baseConfig = base.nixosConfigurations.${config.networking.hostName}.config;
@@ -116,15 +137,33 @@ let
 
             # Filter to only enabled modules and collect their imports
             enabledModules = builtins.filter (m: builtins.elem m.name enabledNames) allModules;
-            enabledImports = builtins.concatMap (m: m.imports) enabledModules;
+            moduleImports = builtins.concatMap (m: m.imports) enabledModules;
+
+            # Add files of enabled modules added with 'enable --path', resolving the
+            # ones recorded with --import-base from this flake
+            pathNames = builtins.filter (name: builtins.hasAttr name enabledPaths) enabledNames;
+            importPath = path:
+              if builtins.substring 0 2 path == "./"
+              then ./. + builtins.substring 1 (-1) path
+              else /. + path;
+            pathImports = map (name: importPath (builtins.getAttr name enabledPaths)) pathNames;
+            enabledImports = moduleImports ++ pathImports;
           in
           {
             nixosConfigurations.runtime = base.nixosConfigurations.${config.networking.hostName}.extendModules {
               modules = enabledImports ++ [
                 # Add a marker file to detect systems built using runtime-modules
                 { environment.etc."runtime-modules-enabled".text = "true"; }
-              ];
-            };
+                # Expose per-module settings recorded with 'enable --set'
+                { _module.args.runtimeModuleSettings = enabledSettings; }
+              ]${lib.optionalString cfg.exposeImports '' ++ [
+                # Expose the same list that is imported above for reuse
+                { _module.args.runtimeModuleImports = enabledImports; }
+              ]''};
+            };${lib.optionalString cfg.exposeImports ''
+
+            # Imports of the enabled modules, kept in sync with the configuration
+            runtimeModuleImports = enabledImports;''}
           };
       }
     '';
@@ -141,6 +180,12 @@ in
 
     builtinModules.enable = lib.mkEnableOption "Enable built-in module library";
 
+    exposeImports = lib.mkOption {
+      type = lib.types.bool;
+      default = false;
+      description = "Expose the enabled modules' imports as the runtimeModuleImports module argument and flake output";
+    };
+
     modules = lib.mkOption {
       type = lib.types.listOf (lib.types.submodule {
         options = {
@@ -167,6 +212,49 @@ in
             description = "Description of what the module provides";
           };
 
+          deps = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [ ];
+            description = "Names of modules this module depends on";
+          };
+
+          conflicts = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [ ];
+            description = "Names of modules this module conflicts with";
+          };
+
+          tags = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [ ];
+            description = "Labels for enabling or disabling groups of modules with --tag";
+          };
+
+          origin = lib.mkOption {
+            type = lib.types.enum [ "user" "upstream" ];
+            default = "user";
+            description = "Whether the module is listed as a user or an upstream module";
+          };
+
+          protected = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = "Keep this module enabled on disable/reset unless --force-protected is given";
+          };
+
+          keepOnReset = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = "Keep this module enabled on reset unless --keep-none is given, while still allowing disable";
+          };
+
+          healthCheck = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "systemctl is-active --quiet sshd";
+            description = "Shell command run after enabling this module, reporting whether it works";
+          };
+
           skipValidation = lib.mkOption {
             type = lib.types.bool;
             default = false;
@@ -178,6 +266,13 @@ in
       description = "Runtime modules definition";
     };
 
+    sets = lib.mkOption {
+      type = lib.types.attrsOf (lib.types.listOf lib.types.str);
+      default = { };
+      example = { dev = [ "rust" "go" ]; };
+      description = "Named sets of modules, enabled or disabled together as @name";
+    };
+
     _allModules = lib.mkOption {
       type = lib.types.listOf lib.types.unspecified;
       internal = true;
@@ -225,7 +320,7 @@ in
       chmod 644 ${dataDir}/flake.nix
 
       # Write the modules.json file
-      echo '${modulesJson}' > ${dataDir}/modules.json
+      cp -f ${modulesJson} ${dataDir}/modules.json
       chmod 644 ${dataDir}/modules.json
 
       # Auto-reset state if not running on a runtime-modules system
@@ -234,6 +329,7 @@ in
           echo "[runtime-modules] standard system detected, cleaning runtime state..."
           rm ${stateJson}
         fi
+        rm -f ${dataDir}/applied.json
       fi
     '';
   };
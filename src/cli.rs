@@ -1,15 +1,69 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::builder::NonEmptyStringValueParser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use regex::Regex;
 use serde::Serialize;
-use std::process::exit;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio, exit};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
 
-use crate::module_manager::ModuleManager;
-use crate::system::require_sudo;
-use runtime_modules::{ModuleState, ModuleStatus};
+use crate::env_vars::{self, flag_env};
+use crate::lock::StateLock;
+use crate::module_manager::{ModuleManager, ToggleOutcome, init_file_permissions};
+use crate::paths;
+use crate::picker::{ensure_interactive, pick_modules};
+use crate::system::{
+    ApplyOptions, HealthCheckFailed, ResolvedConfig, check_nix_available, check_runtime_flake,
+    eval_flake_json, hostname, in_ssh_session, init_escalation, require_clean_flake, require_sudo,
+    resolve_group,
+};
+use runtime_modules::{
+    CheckResult, ColorChoice, DEFAULT_FILE_MODE, Module, ModuleFile, ModuleRegistry, ModuleState,
+    ModuleStatus, RebuildAction, ResetKeep, StateChanges, StateDiff, StateFile,
+    check_system_toplevel, format_timestamp, parse_duration, parse_file_mode, parse_setting,
+    registry_schema, render_metrics, render_table, resolve_flake_ref, resolve_module_indices,
+    resolve_module_prefixes, status_check_passes, to_json, validate_module_name, write_atomic,
+};
+
+// Cookbook printed by --examples
+const EXAMPLES: &str = "\
+Check all available modules and their status:
+  runtime-modules list
+
+Enable one or more modules:
+  sudo runtime-modules enable media-production
+  sudo runtime-modules enable gaming virtualization
+
+Disable a module:
+  sudo runtime-modules disable gaming
+
+Check whether modules are enabled (exits 1 if any is not):
+  runtime-modules status gaming virtualization
+
+Revert to the base system:
+  sudo runtime-modules reset
+
+Re-apply the currently enabled modules:
+  sudo runtime-modules --force rebuild";
 
 // CLI arguments parsing structure
 #[derive(Parser)]
-#[command(author, version, about, long_about = None, disable_help_subcommand = true)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    disable_help_subcommand = true,
+    arg_required_else_help = true
+)]
 pub struct Cli {
     /// Output results in JSON format
     #[arg(short = 'j', long)]
@@ -19,8 +73,133 @@ pub struct Cli {
     #[arg(short = 'f', long)]
     pub force: bool,
 
+    /// Directory holding all runtime state
+    #[arg(long, value_name = "DIR", overrides_with = "state_dir")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Evaluate the configuration before applying it, aborting on failure
+    #[arg(long)]
+    pub check: bool,
+
+    /// Show the full `nix flake update` output instead of saving it to flake-update.log
+    #[arg(short = 'v', long)]
+    pub verbose: bool,
+
+    /// Flake providing `nixosConfigurations.runtime` [default: the state directory]
+    ///
+    /// Relative paths are resolved against the current directory.
+    #[arg(long, value_name = "REF")]
+    pub flake: Option<String>,
+
+    /// Number of cores each build may use, passed to nixos-rebuild
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub cores: Option<u32>,
+
+    /// Maximum number of parallel build jobs, passed to nixos-rebuild
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_jobs: Option<u32>,
+
+    /// How to activate the configuration: test, switch to also make it the boot default,
+    /// or boot to only activate it on the next reboot
+    #[arg(long, value_name = "ACTION")]
+    pub action: Option<RebuildAction>,
+
+    /// Permissions of the state file, in octal
+    #[arg(long, value_name = "MODE", value_parser = parse_file_mode)]
+    pub file_mode: Option<u32>,
+
+    /// Group to own the state file, by name or ID
+    #[arg(long, value_name = "GROUP", value_parser = NonEmptyStringValueParser::new())]
+    pub file_group: Option<String>,
+
+    /// Host to build on, passed to nixos-rebuild as --build-host
+    #[arg(long, value_name = "USER@HOST", value_parser = NonEmptyStringValueParser::new())]
+    pub build_host: Option<String>,
+
+    /// Host to activate on, passed to nixos-rebuild as --target-host
+    #[arg(long, value_name = "USER@HOST", value_parser = NonEmptyStringValueParser::new())]
+    pub target_host: Option<String>,
+
+    /// Include the machine's hostname as a top-level `host` field in JSON output
+    #[arg(long)]
+    pub with_host: bool,
+
+    /// Host name to report in JSON output (implies --with-host)
+    #[arg(long, value_name = "NAME")]
+    pub host: Option<String>,
+
+    /// Write JSON output to a file instead of stdout (requires --json)
+    #[arg(short = 'o', long, value_name = "PATH", requires = "json")]
+    pub output: Option<PathBuf>,
+
+    /// Print JSON on a single line instead of pretty-printed (requires --json)
+    #[arg(long, requires = "json")]
+    pub compact: bool,
+
+    /// Skip `nix flake update` before rebuilding
+    #[arg(long, conflicts_with = "update_if_older")]
+    pub no_update: bool,
+
+    /// Only run `nix flake update` if flake.lock is older than this, e.g. 24h (--force always updates)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub update_if_older: Option<Duration>,
+
+    /// Abort before rebuilding if `nix flake update` fails, instead of only warning
+    #[arg(long, conflicts_with = "no_update")]
+    pub strict_update: bool,
+
+    /// Do not record the flake's git revision in the state file, for reproducible output
+    #[arg(long)]
+    pub no_revision: bool,
+
+    /// Kill the rebuild if it runs longer than this, e.g. 30m, keeping its output so far
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Explain why a rebuild will or won't happen before acting
+    #[arg(long)]
+    pub explain: bool,
+
+    /// Read the module registry from stdin instead of the state directory, for commands
+    /// that don't change state
+    #[arg(long, conflicts_with = "state_dir")]
+    pub registry_from_stdin: bool,
+
+    /// Activate over SSH without asking first (see --action boot for a safer route)
+    #[arg(long)]
+    pub yes: bool,
+
+    /// When to color output: auto (only on a terminal), always or never; NO_COLOR means never
+    #[arg(long, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Rewrite the state file even if it was edited by hand, dropping its formatting and comments
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// Set when re-run with elevated privileges from an SSH session
+    #[arg(long, hide = true)]
+    pub ssh_session: bool,
+
+    /// Program used to re-run commands with elevated privileges, such as doas or run0
+    #[arg(long, value_name = "PROGRAM")]
+    pub reexec_with: Option<String>,
+
+    /// Accept module names with spaces, quotes or shell metacharacters
+    #[arg(long)]
+    pub allow_unsafe_names: bool,
+
+    /// Do not pipe long output through a pager ($RUNTIME_MODULES_PAGER, else $PAGER, else
+    /// less -FRX)
+    #[arg(long)]
+    pub no_pager: bool,
+
+    /// Print common usage examples
+    #[arg(long, exclusive = true)]
+    pub examples: bool,
+
     #[command(subcommand)]
-    pub command: Commands,
+    pub command: Option<Commands>,
 }
 
 // Structure for categorized output
@@ -30,101 +209,1057 @@ struct CategorizedModules {
     upstream_modules: Vec<ModuleStatus>,
 }
 
+#[derive(Subcommand)]
+pub enum DebugCommands {
+    /// Print the parsed module registry including its lookup map
+    Registry,
+    /// Overwrite a module's recorded state (maintenance only: no rebuild, state file untouched)
+    SetState {
+        /// Module to change
+        module: String,
+
+        /// New state: enabled, disabled or uncertain
+        state: ModuleState,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SchemaCommands {
+    /// Print the JSON Schema of the module registry (modules.json)
+    Registry,
+}
+
+// Output formats for the list command
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum ListFormat {
+    #[default]
+    Plain,
+    Table,
+}
+
+// Groupings for the list command
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ListGrouping {
+    Tag,
+}
+
+// Output formats for the graph command
+#[derive(Clone, Copy, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Build and enable one or more modules
+    #[command(after_help = "Examples:\n  \
+        sudo runtime-modules enable media-production\n  \
+        sudo runtime-modules enable gpu --set vendor=nvidia\n  \
+        sudo runtime-modules enable --replace old-audio new-audio\n  \
+        sudo runtime-modules enable --path ./foo.nix --as temp-foo")]
     Enable {
-        /// Module names to enable
-        #[arg(required = true)]
+        /// Module names or indices from 'list' to enable (`@SET` for a registry set, `@FILE` for names in a file);
+        /// without any, pick them interactively
         modules: Vec<String>,
+
+        /// Also enable all modules carrying this tag
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// Module setting to record, exposed to Nix as `runtimeModuleSettings` (repeatable)
+        #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_setting)]
+        settings: Vec<(String, String)>,
+
+        /// Roll back automatically unless confirmed with 'confirm' (only with the 'test' action)
+        #[arg(long)]
+        safe: bool,
+
+        /// Abort if the flake to build (--flake, or else the base flake) has uncommitted changes
+        #[arg(long)]
+        require_clean: bool,
+
+        /// Seconds to wait for confirmation when using --safe
+        #[arg(long, value_name = "SECONDS", default_value_t = 90, requires = "safe")]
+        confirm_within: u64,
+
+        /// Disable OLD in the same rebuild, swapping it for the given modules
+        #[arg(long, value_name = "OLD")]
+        replace: Option<String>,
+
+        /// Allow --replace to swap out a module marked as protected
+        #[arg(long, requires = "replace")]
+        force_protected: bool,
+
+        /// Print the changes and the resulting state, without applying
+        #[arg(long, conflicts_with = "safe")]
+        dry_run: bool,
+
+        /// Save the change to the state file but leave applying it to 'rebuild'
+        #[arg(long, conflicts_with_all = ["safe", "dry_run"])]
+        no_rebuild: bool,
+
+        /// Don't run the health checks of newly enabled modules
+        #[arg(long)]
+        skip_health_checks: bool,
+
+        /// Also enable the module file at PATH, which doesn't need to be in the registry
+        #[arg(
+            long,
+            value_name = "PATH",
+            requires = "as_name",
+            conflicts_with = "dry_run"
+        )]
+        path: Option<PathBuf>,
+
+        /// Name for the module given with --path
+        #[arg(long = "as", value_name = "NAME", requires = "path")]
+        as_name: Option<String>,
+
+        /// Keep the module given with --path in the registry after it is disabled
+        #[arg(long, requires = "path")]
+        persist: bool,
+
+        /// Record the file given with --path relative to the state directory if it lies
+        /// under this directory inside it, e.g. as ./modules/foo.nix; DIR must be inside the
+        /// state directory, so registry paths in /nix/store are left as they are
+        #[arg(long, value_name = "DIR", requires = "path")]
+        import_base: Option<PathBuf>,
+
+        /// Build with 'test' and keep the newly enabled modules only until the next reboot
+        #[arg(long, conflicts_with_all = ["no_rebuild", "replace"])]
+        ephemeral: bool,
     },
+    /// Keep changes applied with 'enable --safe'
+    Confirm,
+    /// Roll back unconfirmed changes once their window expires
+    #[command(hide = true)]
+    ConfirmWatch,
     /// Disable one or more specific modules
+    #[command(after_help = "Examples:\n  \
+        sudo runtime-modules disable media-production\n  \
+        sudo runtime-modules disable --tag media")]
     Disable {
-        /// Module names to disable
-        #[arg(required = true)]
+        /// Module names or indices from 'list' to disable (`@SET` for a registry set, `@FILE` for names in a file);
+        /// without any, pick them interactively
         modules: Vec<String>,
+
+        /// Also disable all enabled modules carrying this tag
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// Also disable modules marked as protected
+        #[arg(long)]
+        force_protected: bool,
+
+        /// Save the change to the state file but leave applying it to 'rebuild'
+        #[arg(long)]
+        no_rebuild: bool,
     },
     /// Disable all modules (revert to base system)
-    Reset,
+    #[command(
+        after_help = "Examples:\n  sudo runtime-modules reset\n  runtime-modules reset --dry-run"
+    )]
+    Reset {
+        /// Also disable modules marked as protected
+        #[arg(long)]
+        force_protected: bool,
+
+        /// Keep this module enabled, on top of those marked to be kept on reset (repeatable)
+        #[arg(long, value_name = "MODULE")]
+        keep: Vec<String>,
+
+        /// Disable modules marked to be kept on reset too; protected modules still need
+        /// --force-protected
+        #[arg(long, conflicts_with = "keep")]
+        keep_none: bool,
+
+        /// Print the modules that would be disabled and the resulting state, without applying
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Save the change to the state file but leave applying it to 'rebuild'
+        #[arg(long, conflicts_with = "dry_run")]
+        no_rebuild: bool,
+    },
     /// Show module status (enabled/disabled)
+    #[command(after_help = "Examples:\n  \
+        runtime-modules status media-production\n  \
+        runtime-modules --json status gaming virtualization\n  \
+        runtime-modules status --exit-zero gaming\n  \
+        runtime-modules status --fail-under 3")]
     Status {
-        /// Module names to check status
-        #[arg(required = true)]
+        /// Module names to check status (all modules when omitted with --fail-under or --name-filter)
+        #[arg(required_unless_present_any = ["fail_under", "name_filter"])]
         modules: Vec<String>,
+
+        /// Only check modules whose names match this regular expression
+        #[arg(long, value_name = "REGEX")]
+        name_filter: Option<Regex>,
+
+        /// Exit 0 even if some modules are not enabled
+        #[arg(long)]
+        exit_zero: bool,
+
+        /// Fail only if fewer than N of the modules are enabled
+        #[arg(long, value_name = "N")]
+        fail_under: Option<usize>,
     },
     /// List all available modules
-    List,
+    #[command(after_help = "Examples:\n  \
+        runtime-modules list\n  \
+        runtime-modules list --format table\n  \
+        runtime-modules --json list")]
+    List {
+        /// Output format; tables fall back to plain when the terminal width is unknown
+        #[arg(long, value_enum, default_value_t = ListFormat::Plain)]
+        format: ListFormat,
+
+        /// Draw tables with ASCII instead of box-drawing characters (implied by NO_COLOR)
+        #[arg(long)]
+        plain: bool,
+
+        /// Only list modules whose names match this regular expression
+        #[arg(long, value_name = "REGEX")]
+        name_filter: Option<Regex>,
+
+        /// List modules under headings, e.g. one per tag, instead of a flat list
+        #[arg(long, value_enum, value_name = "FIELD", conflicts_with = "format")]
+        group_by: Option<ListGrouping>,
+    },
     /// Rebuild the system with currently enabled modules
+    #[command(after_help = "Examples:\n  sudo runtime-modules --force rebuild")]
     Rebuild,
+    /// Activate a pre-built system closure instead of building one
+    #[command(after_help = "Examples:\n  \
+        sudo runtime-modules activate /nix/store/...-nixos-system-host")]
+    Activate {
+        /// System toplevel store path, built from the current state file
+        toplevel: PathBuf,
+    },
+    /// Apply a complete desired state from a JSON file
+    ApplyState {
+        /// State file listing `enabled` modules and optional `settings`
+        file: PathBuf,
+    },
+    /// Rewrite the state file from tracked state without rebuilding
+    Regenerate {
+        /// Print the content instead of writing it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Write the content to PATH for review instead, leaving the state file alone
+        #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+        to: Option<PathBuf>,
+    },
+    /// Deduplicate and sort the saved state file without rebuilding
+    Normalize {
+        /// Print what would change and the resulting content, without writing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show details of a module, including when it was last enabled
+    Info {
+        /// Module to look up
+        module: String,
+    },
+    /// List modules that depend on a module
+    Dependents {
+        /// Module to look up
+        module: String,
+
+        /// Only list enabled dependents
+        #[arg(long)]
+        enabled_only: bool,
+    },
+    /// Rebuild the module registry from a flake output
+    #[command(after_help = "Examples:\n  \
+        sudo runtime-modules sync --from-flake /etc/nixos\n  \
+        sudo runtime-modules sync --from-flake .#runtimeModules")]
+    Sync {
+        /// Flake to evaluate, optionally with a `#attribute` suffix
+        #[arg(long, value_name = "FLAKE")]
+        from_flake: String,
+
+        /// Flake output describing the modules, used when FLAKE has no `#attribute`
+        #[arg(long, default_value = "runtimeModules")]
+        attr: String,
+    },
+    /// Export module dependency and conflict relationships
+    Graph {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Debugging helpers
+    Debug {
+        #[command(subcommand)]
+        command: DebugCommands,
+    },
+    /// Print JSON Schemas for the files runtime-modules reads
+    Schema {
+        #[command(subcommand)]
+        command: SchemaCommands,
+    },
+    /// Show modules enabled or disabled since the last successful rebuild (exits 1 if any)
+    Pending,
+    /// Show the recorded rebuilds, oldest first
+    #[command(after_help = "Examples:\n  \
+        runtime-modules history --since 7d\n  \
+        runtime-modules --json history --limit 10")]
+    History {
+        /// Only show operations within this long ago, e.g. 24h or 7d
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        since: Option<Duration>,
+
+        /// Only show the most recent N operations
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Print module counts in the Prometheus text format, e.g. for the node exporter
+    #[command(after_help = "Examples:\n  \
+        runtime-modules metrics\n  \
+        runtime-modules metrics --output /var/lib/node-exporter/runtime-modules.prom")]
+    Metrics {
+        /// Write the metrics to this file, replacing it atomically, instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+
+        /// Add a gauge per module, labelled with its name
+        #[arg(long)]
+        per_module: bool,
+    },
+    /// Show modules whose recorded state disagrees with the saved state file
+    Reconcile {
+        /// Trust that the running system matches the state file and confirm all states,
+        /// clearing uncertain and pending states without rebuilding
+        #[arg(long)]
+        assume_applied: bool,
+    },
+    /// Check the runtime state and environment for problems (exits 1 if any check fails)
+    Doctor,
+    /// Validate a module registry file without touching the system (exits 1 on problems)
+    CheckRegistry {
+        /// Registry file to check, such as modules.json
+        path: PathBuf,
+    },
+    /// Print the saved state file
+    Show {
+        /// Print the content generated from the current state instead
+        #[arg(long)]
+        generated: bool,
+    },
+}
+
+impl Commands {
+    // Whether the command saves state, the registry or the state file, so it can't
+    // take a registry piped in with --registry-from-stdin
+    fn changes_state(&self) -> bool {
+        match self {
+            Self::Enable { dry_run, .. }
+            | Self::Reset { dry_run, .. }
+            | Self::Normalize { dry_run } => !dry_run,
+            Self::Regenerate { dry_run, to } => !dry_run && to.is_none(),
+            Self::Debug { command } => matches!(command, DebugCommands::SetState { .. }),
+            Self::Confirm
+            | Self::ConfirmWatch
+            | Self::Disable { .. }
+            | Self::Rebuild
+            | Self::Activate { .. }
+            | Self::ApplyState { .. }
+            | Self::Sync { .. }
+            | Self::Reconcile { .. } => true,
+            Self::Status { .. }
+            | Self::List { .. }
+            | Self::Info { .. }
+            | Self::Dependents { .. }
+            | Self::Graph { .. }
+            | Self::Schema { .. }
+            | Self::Pending
+            | Self::History { .. }
+            | Self::Metrics { .. }
+            | Self::Doctor
+            | Self::CheckRegistry { .. }
+            | Self::Show { .. } => false,
+        }
+    }
+}
+
+// JSON payload tagged with the host that produced it
+#[derive(Serialize)]
+struct HostOutput<'a, T: Serialize> {
+    host: &'a str,
+    #[serde(flatten)]
+    payload: &'a T,
+}
+
+// Structure for status output tagged with a host
+#[derive(Serialize)]
+struct StatusOutput<'a> {
+    modules: &'a [ModuleStatus],
+}
+
+// Structure for pending output
+#[derive(Serialize)]
+struct PendingOutput<'a> {
+    pending: bool,
+    enable: &'a [String],
+    disable: &'a [String],
+}
+
+// Structure for check-registry output
+#[derive(Serialize)]
+struct CheckRegistryOutput<'a> {
+    ok: bool,
+    problems: &'a [String],
+}
+
+// Structure for doctor output
+#[derive(Serialize)]
+struct DoctorOutput<'a> {
+    ok: bool,
+    checks: &'a [CheckResult],
+    config: &'a ResolvedConfig,
+}
+
+// Options controlling how command results are reported
+#[derive(Default)]
+struct OutputOptions {
+    json: bool,
+    compact: bool,
+    host: Option<String>,
+    output: Option<PathBuf>,
+}
+
+impl OutputOptions {
+    fn from_cli(cli: &Cli) -> Self {
+        Self {
+            json: cli.json,
+            compact: cli.compact,
+            host: json_host(cli),
+            output: cli.output.clone(),
+        }
+    }
+
+    // Serialize a JSON payload, adding a top-level `host` field when requested
+    fn render<T: Serialize>(&self, payload: &T) -> serde_json::Result<String> {
+        match &self.host {
+            Some(host) => to_json(&HostOutput { host, payload }, !self.compact),
+            None => to_json(payload, !self.compact),
+        }
+    }
+
+    // Serialize a status list, wrapping it in an object when tagged with a host
+    fn render_statuses(&self, statuses: &[ModuleStatus]) -> serde_json::Result<String> {
+        if self.host.is_some() {
+            self.render(&StatusOutput { modules: statuses })
+        } else {
+            to_json(statuses, !self.compact)
+        }
+    }
+
+    // Write rendered JSON to the output file, or stdout if none was given
+    fn emit(&self, json: &str) -> Result<()> {
+        match &self.output {
+            Some(path) => write_atomic(path, &format!("{json}\n"))
+                .with_context(|| format!("failed to write output to {}", path.display())),
+            None => {
+                println!("{json}");
+                Ok(())
+            }
+        }
+    }
+}
+
+// Resolve the host name to include in JSON output, if requested
+fn json_host(cli: &Cli) -> Option<String> {
+    if !cli.json {
+        return None;
+    }
+
+    match cli.host.clone().or_else(|| env_vars::value(env_vars::HOST)) {
+        Some(host) => Some(host),
+        // A failed hostname lookup just omits the field
+        None if cli.with_host => hostname(),
+        None => None,
+    }
+}
+
+// Resolve the rebuild action from the flag, then the environment, then the default
+fn rebuild_action(cli: &Cli) -> Result<RebuildAction> {
+    match (cli.action, env_vars::value(env_vars::ACTION)) {
+        (Some(action), _) => Ok(action),
+        (None, Some(value)) => value
+            .parse()
+            .with_context(|| format!("invalid {}", env_vars::ACTION)),
+        (None, None) => Ok(RebuildAction::default()),
+    }
+}
+
+// State file permissions and group from the flags, then the environment, then the
+// default of 644 with the group left alone
+fn file_permissions(cli: &Cli) -> Result<(u32, Option<u32>)> {
+    let mode = match (cli.file_mode, env_vars::value(env_vars::FILE_MODE)) {
+        (Some(mode), _) => mode,
+        (None, Some(value)) => {
+            parse_file_mode(&value).with_context(|| format!("invalid {}", env_vars::FILE_MODE))?
+        }
+        (None, None) => DEFAULT_FILE_MODE,
+    };
+    let group = cli
+        .file_group
+        .clone()
+        .or_else(|| env_vars::value(env_vars::FILE_GROUP))
+        .map(|group| resolve_group(&group))
+        .transpose()?;
+    Ok((mode, group))
+}
+
+// Parse the command line, noting in the help the environment variables and defaults
+// that flags fall back to
+pub fn parse_cli() -> Cli {
+    let mut command = Cli::command();
+    for flag in flag_env() {
+        command = command.mut_arg(flag.arg, |arg| {
+            let mut help = arg.get_help().map(ToString::to_string).unwrap_or_default();
+            help.push_str(&format!(" [env: {}]", flag.vars.join(", ")));
+            if let Some(default) = &flag.default {
+                help.push_str(&format!(" [default: {default}]"));
+            }
+            arg.help(help)
+        });
+    }
+    let matches = command.get_matches();
+    Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
 }
 
 // Execute the selected command
 pub fn execute_command(cli: &Cli) -> Result<()> {
-    match &cli.command {
-        Commands::List => cmd_list(cli.json),
-        Commands::Reset => {
-            require_sudo("reset", &[], cli.force)?;
-            cmd_reset(cli.force)
-        }
-        Commands::Enable { modules } => {
-            cmd_verify_modules(modules)?;
-            require_sudo("enable", modules, cli.force)?;
-            cmd_enable(modules, cli.force)
-        }
-        Commands::Disable { modules } => {
-            cmd_verify_modules(modules)?;
-            require_sudo("disable", modules, cli.force)?;
-            cmd_disable(modules, cli.force)
-        }
-        Commands::Status { modules } => {
-            cmd_verify_modules(modules)?;
-            cmd_status(modules, cli.json)
+    // Examples are static, so they don't need a registry or state
+    if cli.examples {
+        println!("{EXAMPLES}");
+        return Ok(());
+    }
+    let Some(command) = &cli.command else {
+        Cli::command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit();
+    };
+
+    paths::init_state_dir(cli.state_dir.as_deref());
+    init_escalation(cli.reexec_with.as_deref());
+    init_color(cli.color);
+    let (file_mode, file_group) = file_permissions(cli)?;
+    init_file_permissions(file_mode, file_group);
+    if cli.registry_from_stdin {
+        // Refuse before reading stdin, or escalating with it already consumed
+        if command.changes_state() {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--registry-from-stdin is only for commands that don't change state",
+                )
+                .exit();
+        }
+        ModuleManager::use_registry_from_stdin()?;
+    }
+    let options = ApplyOptions {
+        check: cli.check,
+        verbose: cli.verbose,
+        cores: cli.cores,
+        max_jobs: cli.max_jobs,
+        build_host: cli.build_host.clone(),
+        target_host: cli.target_host.clone(),
+        // Resolve relative paths now, as applying changes into the state directory
+        flake: cli.flake.as_deref().map(|flake| {
+            env::current_dir()
+                .map_or_else(|_| flake.to_string(), |cwd| resolve_flake_ref(flake, &cwd))
+        }),
+        no_revision: cli.no_revision,
+        no_update: cli.no_update,
+        // A forced rebuild also refreshes the inputs
+        update_if_older: cli.update_if_older.filter(|_| !cli.force),
+        timeout: cli.timeout,
+        strict_update: cli.strict_update,
+        explain: cli.explain,
+        toplevel: None,
+        action: rebuild_action(cli)?,
+        no_rebuild: false,
+        skip_health_checks: false,
+        remote_session: in_ssh_session(cli.ssh_session),
+        yes: cli.yes,
+        overwrite: cli.overwrite,
+        ephemeral: false,
+    };
+    let output = OutputOptions::from_cli(cli);
+
+    match command {
+        Commands::List {
+            format,
+            plain,
+            name_filter,
+            group_by,
+        } => {
+            let _lock = StateLock::shared();
+            let _pager = Pager::start(!cli.no_pager && !output.json);
+            match group_by {
+                Some(ListGrouping::Tag) => cmd_list_by_tag(name_filter.as_ref(), &output),
+                None => cmd_list(*format, *plain, name_filter.as_ref(), &output),
+            }
+        }
+        Commands::Reset {
+            force_protected,
+            keep,
+            keep_none,
+            dry_run,
+            no_rebuild,
+        } => {
+            if !keep.is_empty() {
+                cmd_verify_modules(keep, cli.allow_unsafe_names)?;
+            }
+            let keep = ResetKeep {
+                force_protected: *force_protected,
+                keep: keep.clone(),
+                keep_none: *keep_none,
+            };
+            if *dry_run {
+                let _lock = StateLock::shared();
+                return cmd_reset_dry_run(&keep, &output);
+            }
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            let options = ApplyOptions {
+                no_rebuild: *no_rebuild,
+                ..options
+            };
+            cmd_reset(cli.force, &keep, options, &output)
+        }
+        Commands::Enable {
+            modules,
+            settings,
+            safe,
+            require_clean,
+            confirm_within,
+            replace,
+            force_protected,
+            dry_run,
+            tag,
+            no_rebuild,
+            skip_health_checks,
+            path,
+            as_name,
+            persist,
+            import_base,
+            ephemeral,
+        } => {
+            let modules =
+                expand_prefixes(&expand_paths(&expand_indices(&expand_at_args(modules)?)?)?)?;
+            let mut modules = with_tagged(modules, tag.as_deref(), false)?;
+            if let Some(tag) = tag
+                .as_deref()
+                .filter(|_| modules.is_empty() && as_name.is_none())
+            {
+                println!("no modules tagged {tag}");
+                return Ok(());
+            }
+            // The module given with --path isn't in the registry yet, so verify it apart
+            let registered = modules.clone();
+            if let Some(name) = as_name {
+                if !cli.allow_unsafe_names {
+                    validate_module_name(name)?;
+                }
+                if !modules.contains(name) {
+                    modules.push(name.clone());
+                }
+            }
+            if modules.is_empty() {
+                // Pick as root up front, as sudo re-runs the command with the same arguments
+                ensure_interactive()?;
+                if !*dry_run {
+                    require_sudo()?;
+                }
+                modules = cmd_pick("Select modules to enable", &ModuleState::Enabled)?;
+                if modules.is_empty() {
+                    println!("no modules selected");
+                    return Ok(());
+                }
+            }
+            let modules = &modules;
+            cmd_verify_modules(
+                if as_name.is_some() {
+                    &registered
+                } else {
+                    modules
+                },
+                cli.allow_unsafe_names,
+            )?;
+            // Refuse before escalating, so users can't get past the policy with sudo
+            cmd_check_policy(modules)?;
+            if let Some(old) = replace {
+                cmd_check_replaceable(old, *force_protected)?;
+            }
+            if *ephemeral
+                && cli
+                    .action
+                    .is_some_and(|action| action != RebuildAction::Test)
+            {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--ephemeral builds with 'test', so it can't be combined with another \
+                         --action",
+                    )
+                    .exit();
+            }
+            // Only with 'test' does a reboot still recover if the rollback never runs
+            if *safe && options.action != RebuildAction::Test {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!(
+                            "--safe needs the 'test' action, but the action is '{}' (from --action \
+                             or {})",
+                            options.action,
+                            env_vars::ACTION
+                        ),
+                    )
+                    .exit();
+            }
+            if *dry_run {
+                let _lock = StateLock::shared();
+                return cmd_enable_dry_run(replace.as_deref(), modules, settings, &output);
+            }
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            if *require_clean {
+                require_clean_flake(&options)?;
+            }
+            let confirm_within = safe.then_some(*confirm_within);
+            let options = ApplyOptions {
+                no_rebuild: *no_rebuild,
+                skip_health_checks: *skip_health_checks,
+                ephemeral: *ephemeral,
+                action: if *ephemeral {
+                    RebuildAction::Test
+                } else {
+                    options.action
+                },
+                ..options
+            };
+            let mut manager = ModuleManager::new_writable()
+                .context("failed to initialize module manager for enabling modules")?;
+            manager.set_apply_options(options);
+            if let (Some(path), Some(name)) = (path, as_name) {
+                manager.add_path_module(name, path, *persist, import_base.as_deref())?;
+            }
+            cmd_enable(
+                manager,
+                replace.as_deref(),
+                modules,
+                settings,
+                confirm_within,
+                cli.force,
+                &output,
+            )
+        }
+        Commands::Confirm => {
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            cmd_confirm()
+        }
+        Commands::ConfirmWatch => cmd_confirm_watch(),
+        Commands::Disable {
+            modules,
+            tag,
+            force_protected,
+            no_rebuild,
+        } => {
+            let modules =
+                expand_prefixes(&expand_paths(&expand_indices(&expand_at_args(modules)?)?)?)?;
+            let mut modules = with_tagged(modules, tag.as_deref(), true)?;
+            if let Some(tag) = tag.as_deref().filter(|_| modules.is_empty()) {
+                println!("no enabled modules tagged {tag}");
+                return Ok(());
+            }
+            if modules.is_empty() {
+                ensure_interactive()?;
+                require_sudo()?;
+                modules = cmd_pick("Select modules to disable", &ModuleState::Disabled)?;
+                if modules.is_empty() {
+                    println!("no modules selected");
+                    return Ok(());
+                }
+            }
+            let modules = &modules;
+            cmd_verify_modules(modules, cli.allow_unsafe_names)?;
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            let options = ApplyOptions {
+                no_rebuild: *no_rebuild,
+                ..options
+            };
+            cmd_disable(modules, cli.force, *force_protected, options, &output)
+        }
+        Commands::Status {
+            modules,
+            exit_zero,
+            fail_under,
+            name_filter,
+        } => {
+            let modules = &expand_prefixes(modules)?;
+            cmd_verify_modules(modules, cli.allow_unsafe_names)?;
+            let _lock = StateLock::shared();
+            cmd_status(
+                modules,
+                *exit_zero,
+                *fail_under,
+                name_filter.as_ref(),
+                &output,
+            )
         }
         Commands::Rebuild => {
-            require_sudo("rebuild", &[], cli.force)?;
-            cmd_rebuild(cli.force)
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            cmd_rebuild(cli.force, options, &output)
+        }
+        Commands::Activate { toplevel } => {
+            let toplevel = check_system_toplevel(toplevel)?;
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            cmd_activate(toplevel, options, &output)
+        }
+        Commands::ApplyState { file } => {
+            let desired = StateFile::from_file(file)?;
+            cmd_verify_modules(&desired.referenced_modules(), cli.allow_unsafe_names)?;
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            cmd_apply_state(desired, cli.force, options, &output)
+        }
+        Commands::Regenerate { dry_run, to } if *dry_run || to.is_some() => {
+            let _lock = StateLock::shared();
+            ModuleManager::regenerate(true, to.as_deref(), &options)
+        }
+        Commands::Regenerate { .. } => {
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            ModuleManager::regenerate(false, None, &options)
+        }
+        Commands::Normalize { dry_run: true } => {
+            let _lock = StateLock::shared();
+            ModuleManager::normalize(true)
+        }
+        Commands::Normalize { dry_run: false } => {
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            ModuleManager::normalize(false)
+        }
+        Commands::Info { module } => {
+            let _lock = StateLock::shared();
+            cmd_info(module, &output)
+        }
+        Commands::Dependents {
+            module,
+            enabled_only,
+        } => {
+            let _lock = StateLock::shared();
+            cmd_dependents(module, *enabled_only, &output)
+        }
+        Commands::Sync { from_flake, attr } => {
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            cmd_sync(from_flake, attr, &output)
+        }
+        Commands::Graph { format } => cmd_graph(*format, &output),
+        Commands::Debug {
+            command: DebugCommands::Registry,
+        } => cmd_debug_registry(&output),
+        Commands::Debug {
+            command: DebugCommands::SetState { module, state },
+        } => {
+            require_sudo()?;
+            let _lock = StateLock::exclusive()?;
+            ModuleManager::set_recorded_state(module, state.clone())
+        }
+        Commands::Schema {
+            command: SchemaCommands::Registry,
+        } => cmd_schema_registry(&output),
+        Commands::Pending => {
+            let _lock = StateLock::shared();
+            cmd_pending(&output)
+        }
+        Commands::History { since, limit } => {
+            let _lock = StateLock::shared();
+            cmd_history(*since, *limit, &output)
+        }
+        Commands::Metrics {
+            output: path,
+            per_module,
+        } => {
+            let _lock = StateLock::shared();
+            cmd_metrics(path.as_deref(), *per_module)
+        }
+        Commands::Reconcile {
+            assume_applied: false,
+        } => {
+            cmd_drop_stale_ephemeral()?;
+            let _lock = StateLock::shared();
+            cmd_reconcile(false)
+        }
+        Commands::Reconcile {
+            assume_applied: true,
+        } => {
+            require_sudo()?;
+            cmd_drop_stale_ephemeral()?;
+            let _lock = StateLock::exclusive()?;
+            cmd_reconcile(true)
         }
+        Commands::Doctor => {
+            let _lock = StateLock::shared();
+            cmd_doctor(&options, &output)
+        }
+        Commands::CheckRegistry { path } => cmd_check_registry(path, &output),
+        Commands::Show { generated } => {
+            let _lock = StateLock::shared();
+            cmd_show(*generated)
+        }
+    }
+}
+
+// Expand `@NAME` arguments into the members of the registry set NAME, or else
+// into the module names listed in the file NAME
+fn expand_at_args(args: &[String]) -> Result<Vec<String>> {
+    let mut modules = Vec::new();
+    let mut registry = None;
+
+    for arg in args {
+        let Some(name) = arg.strip_prefix('@') else {
+            modules.push(arg.clone());
+            continue;
+        };
+
+        // Only load the registry once an `@` argument shows up
+        let registry = match &mut registry {
+            Some(registry) => registry,
+            None => registry.insert(ModuleManager::load_registry()?),
+        };
+        if registry.sets.contains_key(name) {
+            modules.extend(registry.expand_set(name)?);
+            continue;
+        }
+
+        let content = fs::read_to_string(name).with_context(|| {
+            format!("no module set named {name}, and failed to read response file {name}")
+        })?;
+
+        // One name per line, skipping blank lines and '#' comments
+        modules.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
     }
+
+    Ok(modules)
 }
 
 // Command implementations
-fn cmd_verify_modules(modules: &[String]) -> Result<()> {
+
+// Pick modules interactively, among those not in the `skip` state
+fn cmd_pick(title: &str, skip: &ModuleState) -> Result<Vec<String>> {
+    let candidates: Vec<_> = {
+        let _lock = StateLock::shared();
+        ModuleManager::new()
+            .context("failed to initialize module manager for picking modules")?
+            .get_all_status()
+            .into_iter()
+            .filter(|status| &status.state != skip)
+            .collect()
+    };
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    pick_modules(title, &candidates)
+}
+
+fn cmd_verify_modules(modules: &[String], allow_unsafe_names: bool) -> Result<()> {
+    // Names are forwarded through sudo and written into Nix, so refuse surprises
+    if !allow_unsafe_names {
+        for module in modules {
+            validate_module_name(module)?;
+        }
+    }
+
     let manager = ModuleManager::new()
         .context("failed to initialize module manager while verifying modules")?;
 
     if !manager.verify_modules_exist(modules) {
         eprintln!("error: one or more modules not found");
-        cmd_list(false)?;
+        cmd_list(ListFormat::Plain, false, None, &OutputOptions::default())?;
         exit(1);
     }
 
     Ok(())
 }
 
-fn cmd_list(json_output: bool) -> Result<()> {
+fn cmd_check_policy(modules: &[String]) -> Result<()> {
+    ModuleManager::new()
+        .context("failed to initialize module manager while checking the enable policy")?
+        .check_enable_policy(modules)
+}
+
+fn cmd_check_replaceable(old: &str, force_protected: bool) -> Result<()> {
+    ModuleManager::new()
+        .context("failed to initialize module manager while checking the module to replace")?
+        .check_replaceable(old, force_protected)
+}
+
+fn cmd_list(
+    format: ListFormat,
+    plain: bool,
+    name_filter: Option<&Regex>,
+    output: &OutputOptions,
+) -> Result<()> {
     let manager = ModuleManager::new()
         .context("failed to initialize module manager while listing modules")?;
     let modules_with_status = manager.get_all_status();
 
     // Split modules into rt modules and user modules
-    let (rt_modules, user_modules): (Vec<_>, Vec<_>) = modules_with_status
+    let (mut rt_modules, mut user_modules): (Vec<_>, Vec<_>) = modules_with_status
         .into_iter()
-        .partition(|status| status.name.starts_with("rt."));
+        .partition(|status| manager.is_upstream(&status.name));
+
+    // Modules in display order, numbered before filtering so indices stay valid
+    let listed: Vec<String> = user_modules
+        .iter()
+        .chain(rt_modules.iter())
+        .map(|status| status.name.clone())
+        .collect();
+    if let Some(filter) = name_filter {
+        user_modules.retain(|status| filter.is_match(&status.name));
+        rt_modules.retain(|status| filter.is_match(&status.name));
+    }
 
-    if json_output {
+    if output.json {
         // Output as JSON
         let categorized = CategorizedModules {
             user_modules,
             upstream_modules: rt_modules,
         };
 
-        let json = serde_json::to_string_pretty(&categorized)
+        let json = output
+            .render(&categorized)
             .context("failed to serialize module list to JSON")?;
-        println!("{json}");
+        output.emit(&json)?;
     } else {
         // Check if both module lists are empty
         if user_modules.is_empty() && rt_modules.is_empty() {
-            println!("no modules available");
+            if name_filter.is_some() {
+                println!("no modules match the filter");
+            } else {
+                println!("no modules available");
+            }
             return Ok(());
         }
 
@@ -136,22 +1271,78 @@ fn cmd_list(json_output: bool) -> Result<()> {
             .max()
             .unwrap_or(0);
 
-        println!("\u{001b}[4mAvailable modules:\u{001b}[0m");
+        // Remember the display order for index arguments
+        save_list_cache(&listed);
+        let index_width = listed.len().to_string().len();
+        let index_of = |status: &ModuleStatus| {
+            listed
+                .iter()
+                .position(|name| *name == status.name)
+                .map_or(0, |position| position + 1)
+        };
+
+        // Tables need the terminal width; without it use the plain format
+        let table_width = match format {
+            ListFormat::Table => terminal_width(),
+            ListFormat::Plain => None,
+        };
+        if let Some(width) = table_width {
+            let ascii = plain || env::var_os("NO_COLOR").is_some();
+            let sections = [
+                ("Available modules:", &user_modules),
+                ("Upstream modules:", &rt_modules),
+            ];
+            for (heading, statuses) in sections {
+                if statuses.is_empty() {
+                    continue;
+                }
+                let rows: Vec<Vec<String>> = statuses
+                    .iter()
+                    .map(|status| {
+                        vec![
+                            index_of(status).to_string(),
+                            status_marker(&status.state, ascii).to_string(),
+                            status.name.clone(),
+                            if status.pending {
+                                format!("{} (pending)", status.state)
+                            } else {
+                                status.state.to_string()
+                            },
+                            status.desc.clone(),
+                        ]
+                    })
+                    .collect();
+                println!("{heading}");
+                print!(
+                    "{}",
+                    render_table(
+                        &["#", "", "Name", "State", "Description"],
+                        &rows,
+                        width,
+                        ascii
+                    )
+                );
+            }
+            return Ok(());
+        }
+
+        print_heading("Available modules:");
 
         // Print user modules if any exist
         if !user_modules.is_empty() {
             for status in &user_modules {
-                print_module_status(status, max_name_length);
+                print_module_status(status, index_of(status), index_width, max_name_length);
             }
             if !rt_modules.is_empty() {
-                println!("\n\u{001b}[4mUpstream modules:\u{001b}[0m");
+                println!();
+                print_heading("Upstream modules:");
             }
         }
 
         // Print rt modules if any exist
         if !rt_modules.is_empty() {
             for status in &rt_modules {
-                print_module_status(status, max_name_length);
+                print_module_status(status, index_of(status), index_width, max_name_length);
             }
         }
     }
@@ -159,82 +1350,1117 @@ fn cmd_list(json_output: bool) -> Result<()> {
     Ok(())
 }
 
-// Helper function to print a module status with proper formatting
-fn print_module_status(status: &ModuleStatus, max_name_length: usize) {
-    let status_marker = match status.state {
-        ModuleState::Enabled => "[✓]",
-        ModuleState::Disabled => "[ ]",
-        ModuleState::Uncertain => "[?]",
-    };
-
-    // Create padded name for alignment
-    let padded_name = format!("{:<width$}", status.name, width = max_name_length);
+// List modules under a heading per tag, untagged ones last
+fn cmd_list_by_tag(name_filter: Option<&Regex>, output: &OutputOptions) -> Result<()> {
+    let manager = ModuleManager::new()
+        .context("failed to initialize module manager while listing modules")?;
+    let (mut groups, mut untagged) = manager.tag_groups();
+    if let Some(filter) = name_filter {
+        for members in groups.values_mut() {
+            members.retain(|name| filter.is_match(name));
+        }
+        groups.retain(|_, members| !members.is_empty());
+        untagged.retain(|name| filter.is_match(name));
+    }
 
-    // Format the output to include description
-    if status.desc.is_empty() {
-        println!("  {status_marker} {padded_name}");
-    } else {
-        println!("  {status_marker} {padded_name}  {}", status.desc);
+    if output.json {
+        // Untagged modules go under an "untagged" key, next to any tag of that name
+        if !untagged.is_empty() {
+            groups
+                .entry("untagged".to_string())
+                .or_default()
+                .extend(untagged);
+        }
+        let json = output
+            .render(&groups)
+            .context("failed to serialize module groups to JSON")?;
+        return output.emit(&json);
     }
-}
 
-fn cmd_reset(force: bool) -> Result<()> {
-    let mut manager =
-        ModuleManager::new().context("failed to initialize module manager for reset")?;
-    manager.reset(force).context("failed to reset modules")
-}
+    if groups.is_empty() && untagged.is_empty() {
+        if name_filter.is_some() {
+            println!("no modules match the filter");
+        } else {
+            println!("no modules available");
+        }
+        return Ok(());
+    }
 
-fn cmd_enable(modules: &[String], force: bool) -> Result<()> {
-    let mut manager =
-        ModuleManager::new().context("failed to initialize module manager for enabling modules")?;
-    manager
-        .enable_modules(modules, force)
-        .with_context(|| format!("failed to enable modules: {modules:?}"))?;
-    Ok(())
-}
+    // Number modules as the flat listing does, so indices stay valid
+    let statuses = manager.get_all_status();
+    let listed: Vec<String> = statuses
+        .iter()
+        .filter(|status| !manager.is_upstream(&status.name))
+        .chain(
+            statuses
+                .iter()
+                .filter(|status| manager.is_upstream(&status.name)),
+        )
+        .map(|status| status.name.clone())
+        .collect();
+    save_list_cache(&listed);
+    let index_width = listed.len().to_string().len();
+    let max_name_length = listed.iter().map(String::len).max().unwrap_or(0);
+
+    let sections = groups
+        .iter()
+        .map(|(tag, members)| (tag.as_str(), members))
+        .chain((!untagged.is_empty()).then_some(("Untagged", &untagged)));
+    for (position, (heading, members)) in sections.enumerate() {
+        if position > 0 {
+            println!();
+        }
+        print_heading(&format!("{heading}:"));
+        for name in members {
+            let Some(status) = statuses.iter().find(|status| status.name == *name) else {
+                continue;
+            };
+            let index = listed
+                .iter()
+                .position(|listed| listed == name)
+                .map_or(0, |position| position + 1);
+            print_module_status(status, index, index_width, max_name_length);
+        }
+    }
 
-fn cmd_disable(modules: &[String], force: bool) -> Result<()> {
-    let mut manager = ModuleManager::new()
-        .context("failed to initialize module manager for disabling modules")?;
-    manager
-        .disable_modules(modules, force)
-        .with_context(|| format!("failed to disable modules: {modules:?}"))?;
     Ok(())
 }
 
-fn cmd_status(modules: &[String], json_output: bool) -> Result<()> {
+// Add the modules carrying `tag`, optionally only enabled ones, to the named modules
+fn with_tagged(
+    mut modules: Vec<String>,
+    tag: Option<&str>,
+    enabled_only: bool,
+) -> Result<Vec<String>> {
+    let Some(tag) = tag else {
+        return Ok(modules);
+    };
     let manager =
-        ModuleManager::new().context("failed to initialize module manager for checking status")?;
-    let status_list = manager.get_status(modules);
-    let not_fully_enabled = status_list
+        ModuleManager::new().context("failed to initialize module manager for resolving tags")?;
+    for module in manager.tagged_modules(tag, enabled_only) {
+        if !modules.contains(&module) {
+            modules.push(module);
+        }
+    }
+    Ok(modules)
+}
+
+// Module names in the order 'list' displays and numbers them
+fn listing_order(registry: &ModuleRegistry) -> Vec<String> {
+    let (rt_modules, user_modules): (Vec<_>, Vec<_>) = registry
+        .modules
+        .iter()
+        .partition(|module| module.is_upstream());
+    user_modules
+        .into_iter()
+        .chain(rt_modules)
+        .map(|module| module.name.clone())
+        .collect()
+}
+
+// Remember the listed module order; failing to do so only disables index arguments
+fn save_list_cache(listed: &[String]) {
+    // Under sudo the cache belongs to the invoking user, so leave it alone
+    if env::var_os("SUDO_UID").is_some() {
+        return;
+    }
+    let Some(path) = paths::list_cache() else {
+        return;
+    };
+
+    let saved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(serde_json::to_string(listed)?))
+        .and_then(|json| write_atomic(&path, &json));
+    if let Err(e) = saved {
+        eprintln!("warning: failed to save module indices: {e:#}");
+    }
+}
+
+// Replace numeric arguments with the modules numbered by the last 'list'
+fn expand_indices(args: &[String]) -> Result<Vec<String>> {
+    if !args
+        .iter()
+        .any(|arg| arg.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Ok(args.to_vec());
+    }
+
+    let current = listing_order(&ModuleManager::load_registry()?);
+    let listed: Option<Vec<String>> = paths::list_cache()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok());
+    resolve_module_indices(args, listed.as_deref(), &current)
+}
+
+// Expand unique prefixes of module names into the full names
+fn expand_prefixes(args: &[String]) -> Result<Vec<String>> {
+    if args.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let names: Vec<String> = ModuleManager::load_registry()?
+        .modules
+        .into_iter()
+        .map(|module| module.name)
+        .collect();
+    resolve_module_prefixes(args, &names)
+}
+
+// Replace module paths, as found in the generated configuration, by module names
+fn expand_paths(args: &[String]) -> Result<Vec<String>> {
+    if !args.iter().any(|arg| arg.starts_with('/')) {
+        return Ok(args.to_vec());
+    }
+    // Paths recorded with --import-base are relative to the state directory
+    let state_paths = ModuleFile::from_file(paths::state_file())
+        .map(|module_file| {
+            module_file
+                .paths()
+                .iter()
+                .map(|(name, path)| {
+                    let path = path.strip_prefix("./").map_or_else(
+                        || path.clone(),
+                        |relative| paths::state_dir().join(relative).display().to_string(),
+                    );
+                    (name.clone(), path)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ModuleManager::load_registry()?.resolve_paths(args, &state_paths)
+}
+
+// Checkbox-style marker for a state, optionally restricted to ASCII
+fn status_marker(state: &ModuleState, ascii: bool) -> &'static str {
+    match state {
+        ModuleState::Enabled if ascii => "[x]",
+        ModuleState::Enabled => "[✓]",
+        ModuleState::Disabled => "[ ]",
+        ModuleState::Uncertain => "[?]",
+    }
+}
+
+// Width of the terminal on stdout, or on stderr while stdout goes to a pager,
+// else from COLUMNS
+fn terminal_width() -> Option<usize> {
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &raw mut size) } == 0 && size.ws_col > 0 {
+            return Some(size.ws_col.into());
+        }
+    }
+    env::var("COLUMNS").ok()?.parse().ok()
+}
+
+// Helper function to print a module status with proper formatting
+fn print_module_status(
+    status: &ModuleStatus,
+    index: usize,
+    index_width: usize,
+    max_name_length: usize,
+) {
+    let status_marker = status_marker(&status.state, false);
+
+    // Create padded name for alignment
+    let padded_name = format!("{:<width$}", status.name, width = max_name_length);
+    let pending = if status.pending {
+        " (pending rebuild)"
+    } else if status.ephemeral {
+        " (until reboot)"
+    } else {
+        ""
+    };
+
+    // Format the output to include description
+    if status.desc.is_empty() {
+        println!("  {index:>index_width$} {status_marker} {padded_name}{pending}");
+    } else {
+        println!(
+            "  {index:>index_width$} {status_marker} {padded_name}  {}{pending}",
+            status.desc
+        );
+    }
+}
+
+// Structured result of a state-changing command
+#[derive(Serialize)]
+struct OperationResult<'a> {
+    action: &'a str,
+    success: bool,
+    // Requested modules already in the requested state
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    unchanged: &'a [String],
+    // Health checks run for newly enabled modules
+    #[serde(skip_serializing_if = "<[CheckResult]>::is_empty")]
+    health_checks: &'a [CheckResult],
+    // Added, removed and untouched modules of 'apply-state'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<&'a StateChanges>,
+    // Enabled modules a reset left enabled
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    kept: &'a [String],
+    warnings: Vec<String>,
+}
+
+// Report the outcome of a state-changing command along with collected warnings
+fn report_operation(
+    output: &OutputOptions,
+    action: &str,
+    manager: &mut ModuleManager,
+    result: Result<()>,
+) -> Result<()> {
+    report_toggle(output, action, manager, result, &ToggleOutcome::default())
+}
+
+// Report the outcome of enabling or disabling modules, including those that were
+// already in the requested state and any health checks
+fn report_toggle(
+    output: &OutputOptions,
+    action: &str,
+    manager: &mut ModuleManager,
+    result: Result<()>,
+    outcome: &ToggleOutcome,
+) -> Result<()> {
+    let warnings = manager.take_warnings();
+
+    if output.json {
+        let json = output
+            .render(&OperationResult {
+                action,
+                success: result.is_ok(),
+                unchanged: &outcome.unchanged,
+                health_checks: &outcome.health_checks,
+                changes: outcome.changes.as_ref(),
+                kept: &outcome.kept,
+                warnings,
+            })
+            .context("failed to serialize operation result to JSON")?;
+        output.emit(&json)?;
+    } else {
+        for warning in &warnings {
+            eprintln!("warning: {warning}");
+        }
+    }
+
+    result
+}
+
+fn cmd_reset(
+    force: bool,
+    keep: &ResetKeep,
+    options: ApplyOptions,
+    output: &OutputOptions,
+) -> Result<()> {
+    let mut manager =
+        ModuleManager::new_writable().context("failed to initialize module manager for reset")?;
+    manager.set_apply_options(options);
+    let mut toggled = ToggleOutcome::default();
+    let result = manager
+        .reset(force, keep)
+        .context("failed to reset modules")
+        .map(|outcome| toggled = outcome);
+    report_toggle(output, "reset", &mut manager, result, &toggled)
+}
+
+// What 'reset --dry-run' would do
+#[derive(Serialize)]
+struct ResetPlan {
+    disable: Vec<String>,
+    keep: Vec<String>,
+    state: StateFile,
+}
+
+fn cmd_reset_dry_run(keep: &ResetKeep, output: &OutputOptions) -> Result<()> {
+    let manager = ModuleManager::new().context("failed to initialize module manager for reset")?;
+    let (disable, kept) = manager.reset_plan(keep);
+    let state = manager.preview_disable(&disable);
+
+    if output.json {
+        let plan = ResetPlan {
+            disable,
+            keep: kept,
+            state,
+        };
+        let json = output
+            .render(&plan)
+            .context("failed to serialize reset plan to JSON")?;
+        output.emit(&json)?;
+    } else {
+        if disable.is_empty() {
+            println!("no modules would be disabled");
+        }
+        for module in &disable {
+            println!("would disable {module}");
+        }
+        for module in &kept {
+            if manager.is_protected(module) && !keep.force_protected {
+                println!("would keep protected module {module}");
+            } else {
+                println!("would keep module {module}");
+            }
+        }
+        println!("resulting state:");
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&state).context("failed to serialize state")?
+        );
+    }
+
+    Ok(())
+}
+
+fn cmd_enable(
+    mut manager: ModuleManager,
+    replace: Option<&str>,
+    modules: &[String],
+    settings: &[(String, String)],
+    confirm_within: Option<u64>,
+    force: bool,
+    output: &OutputOptions,
+) -> Result<()> {
+    let previous = manager.snapshot();
+    let mut toggled = ToggleOutcome::default();
+    let result = match replace {
+        Some(old) => manager
+            .replace_modules(old, modules, settings, force)
+            .with_context(|| format!("failed to replace {old} with {modules:?}")),
+        None => manager
+            .enable_modules(modules, settings, force)
+            .with_context(|| format!("failed to enable modules: {modules:?}"))
+            .map(|outcome| {
+                let changed = outcome.changed;
+                toggled = outcome;
+                changed
+            }),
+    }
+    .and_then(|changes| {
+        // Arm the rollback only once the changes are actually applied
+        match confirm_within {
+            Some(window) if changes || force => manager.require_confirmation(previous, window),
+            _ => Ok(()),
+        }
+    });
+    if !output.json {
+        for module in &toggled.unchanged {
+            println!("module {module} is already enabled");
+        }
+        for check in &toggled.health_checks {
+            let verdict = if check.ok { "passed" } else { "failed" };
+            println!("health check for {} {verdict}", check.check);
+            for line in check.detail.lines() {
+                println!("  {line}");
+            }
+        }
+    }
+    report_toggle(output, "enable", &mut manager, result, &toggled)?;
+
+    // The modules stay enabled, but a failed check still shows in the exit code
+    let failed: Vec<String> = toggled
+        .health_checks
         .iter()
-        .any(|status| status.state != ModuleState::Enabled);
+        .filter(|check| !check.ok)
+        .map(|check| check.check.clone())
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(HealthCheckFailed(failed).into())
+    }
+}
+
+// A module 'enable' was asked for, or one of their dependencies
+#[derive(Serialize)]
+struct ResolvedModule {
+    name: String,
+    // Pulled in by another module's `deps` rather than requested
+    dependency: bool,
+    // Enabled in the resulting state
+    enabled: bool,
+}
+
+// What 'enable --dry-run' would do
+#[derive(Serialize)]
+struct EnablePlan {
+    requested: Vec<String>,
+    resolved: Vec<ResolvedModule>,
+    #[serde(flatten)]
+    diff: StateDiff,
+    state: StateFile,
+}
+
+fn cmd_enable_dry_run(
+    replace: Option<&str>,
+    modules: &[String],
+    settings: &[(String, String)],
+    output: &OutputOptions,
+) -> Result<()> {
+    let manager =
+        ModuleManager::new().context("failed to initialize module manager for enabling modules")?;
+    let state = manager.preview_enable(replace, modules, settings);
+    let diff = manager.diff(&state);
+    let resolved: Vec<ResolvedModule> = manager
+        .resolve_dependencies(modules)
+        .into_iter()
+        .map(|name| ResolvedModule {
+            dependency: !modules.contains(&name),
+            enabled: state.enabled.contains(&name),
+            name,
+        })
+        .collect();
+
+    if output.json {
+        let plan = EnablePlan {
+            requested: modules.to_vec(),
+            resolved,
+            diff,
+            state,
+        };
+        let json = output
+            .render(&plan)
+            .context("failed to serialize enable plan to JSON")?;
+        output.emit(&json)?;
+    } else {
+        println!("requested: {}", modules.join(", "));
+        println!("with dependencies:");
+        for module in &resolved {
+            let note = match (module.dependency, module.enabled) {
+                (false, _) => "",
+                (true, true) => " (dependency, enabled)",
+                (true, false) => " (dependency, not enabled)",
+            };
+            println!("  {}{note}", module.name);
+        }
+        let missing: Vec<&str> = resolved
+            .iter()
+            .filter(|module| !module.enabled)
+            .map(|module| module.name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            println!(
+                "note: dependencies aren't enabled automatically, enable them as well: {}",
+                missing.join(", ")
+            );
+        }
+        if diff.is_empty() {
+            println!("no changes needed");
+        }
+        for module in &diff.enable {
+            println!("+ {module}");
+        }
+        for module in &diff.disable {
+            println!("- {module}");
+        }
+        for module in &diff.settings_changed {
+            println!("~ {module} (settings)");
+        }
+        println!("resulting state:");
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&state).context("failed to serialize state")?
+        );
+    }
+
+    Ok(())
+}
+
+fn cmd_apply_state(
+    desired: StateFile,
+    force: bool,
+    options: ApplyOptions,
+    output: &OutputOptions,
+) -> Result<()> {
+    let mut manager = ModuleManager::new_writable()
+        .context("failed to initialize module manager for applying state")?;
+    manager.set_apply_options(options);
+    let changes = manager.state_changes(&desired);
+    let result = manager
+        .apply_state(desired, force)
+        .context("failed to apply desired state")
+        .map(|_| ());
+    if result.is_ok() && !output.json {
+        print_state_changes(&changes);
+    }
+    let outcome = ToggleOutcome {
+        changes: Some(changes),
+        ..ToggleOutcome::default()
+    };
+    report_toggle(output, "apply-state", &mut manager, result, &outcome)
+}
+
+// Summarize the modules 'apply-state' left alone; the ones it changed were listed
+// as it applied them
+fn print_state_changes(changes: &StateChanges) {
+    let groups = [
+        ("unchanged", &changes.unchanged),
+        ("skipped (settings only, not enabled)", &changes.skipped),
+    ];
+    for (label, modules) in groups {
+        if !modules.is_empty() {
+            println!("{label}: {}", modules.join(", "));
+        }
+    }
+}
+
+fn cmd_confirm() -> Result<()> {
+    if ModuleManager::confirm()? {
+        println!("changes confirmed");
+    } else {
+        println!("no changes pending confirmation");
+    }
+    Ok(())
+}
+
+fn cmd_metrics(path: Option<&Path>, per_module: bool) -> Result<()> {
+    let manager =
+        ModuleManager::new().context("failed to initialize module manager for metrics")?;
+    let diff = ModuleManager::pending_changes()?;
+    let metrics = render_metrics(
+        &manager.get_all_status(),
+        diff.enable.len() + diff.disable.len(),
+        per_module,
+    );
+
+    match path {
+        // Replace the file in one step, so collectors never read it half-written
+        Some(path) => write_atomic(path, &metrics)
+            .with_context(|| format!("failed to write metrics to {}", path.display())),
+        None => {
+            print!("{metrics}");
+            Ok(())
+        }
+    }
+}
+
+fn cmd_history(
+    since: Option<Duration>,
+    limit: Option<usize>,
+    output: &OutputOptions,
+) -> Result<()> {
+    let entries = ModuleManager::history(since, limit)?;
+
+    if output.json {
+        let json = output
+            .render(&entries)
+            .context("failed to serialize history to JSON")?;
+        output.emit(&json)?;
+    } else if entries.is_empty() {
+        println!("no recorded operations");
+    } else {
+        for entry in &entries {
+            let outcome = match (entry.staged, entry.ok) {
+                (true, _) => "staged",
+                (false, true) => "ok",
+                (false, false) => "failed",
+            };
+            let enabled = if entry.enabled.is_empty() {
+                "no modules enabled".to_string()
+            } else {
+                entry.enabled.join(", ")
+            };
+            println!(
+                "{}  {outcome:<6}  {}: {enabled}",
+                format_timestamp(entry.time),
+                entry.action
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_pending(output: &OutputOptions) -> Result<()> {
+    let diff = ModuleManager::pending_changes()?;
+    let pending = !diff.is_empty();
+
+    if output.json {
+        let json = output
+            .render(&PendingOutput {
+                pending,
+                enable: &diff.enable,
+                disable: &diff.disable,
+            })
+            .context("failed to serialize pending changes to JSON")?;
+        output.emit(&json)?;
+    } else if pending {
+        for module in &diff.enable {
+            println!("+ {module}");
+        }
+        for module in &diff.disable {
+            println!("- {module}");
+        }
+    } else {
+        println!("no pending changes");
+    }
+
+    if pending {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+fn cmd_reconcile(assume_applied: bool) -> Result<()> {
+    // Uncertain modules then run as the boot configuration left them, not as the
+    // failed change did
+    if ModuleManager::rebooted_since_apply() {
+        println!("the system rebooted since the configuration was last applied");
+    }
+    let mismatched = ModuleManager::reconcile(assume_applied)?;
+    if mismatched.is_empty() {
+        println!("recorded states match the state file");
+    } else if assume_applied {
+        println!("force-confirmed modules: {}", mismatched.join(", "));
+    } else {
+        println!(
+            "run 'runtime-modules --force rebuild' to settle them, or 'reconcile \
+             --assume-applied' if the system is known to match"
+        );
+    }
+    Ok(())
+}
+
+// Forget modules enabled with --ephemeral before the last reboot, which took them away
+fn cmd_drop_stale_ephemeral() -> Result<()> {
+    if ModuleManager::stale_ephemeral()?.is_empty() {
+        return Ok(());
+    }
 
-    if json_output {
+    require_sudo()?;
+    let _lock = StateLock::exclusive()?;
+    let mut manager = ModuleManager::new_writable()
+        .context("failed to initialize module manager for reconciling")?;
+    let dropped = manager.drop_stale_ephemeral()?;
+    if !dropped.is_empty() {
+        println!(
+            "dropped modules enabled until the last reboot: {}",
+            dropped.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn cmd_confirm_watch() -> Result<()> {
+    // The deadline may move if changes are re-applied, so re-check after each wait
+    while let Some(remaining) = ModuleManager::confirmation_remaining()? {
+        if remaining > 0 {
+            thread::sleep(Duration::from_secs(remaining));
+            continue;
+        }
+
+        let _lock = StateLock::exclusive()?;
+        let mut manager = ModuleManager::new_writable()
+            .context("failed to initialize module manager for rollback")?;
+        manager.rollback_unconfirmed()?;
+    }
+    Ok(())
+}
+
+fn cmd_disable(
+    modules: &[String],
+    force: bool,
+    force_protected: bool,
+    options: ApplyOptions,
+    output: &OutputOptions,
+) -> Result<()> {
+    let mut manager = ModuleManager::new_writable()
+        .context("failed to initialize module manager for disabling modules")?;
+    manager.set_apply_options(options);
+    let mut toggled = ToggleOutcome::default();
+    let result = manager
+        .disable_modules(modules, force, force_protected)
+        .with_context(|| format!("failed to disable modules: {modules:?}"))
+        .map(|outcome| toggled = outcome);
+    if !output.json {
+        for module in &toggled.unchanged {
+            println!("module {module} is already disabled");
+        }
+    }
+    report_toggle(output, "disable", &mut manager, result, &toggled)
+}
+
+// Pager that receives stdout while alive, like git's
+struct Pager {
+    child: Child,
+    saved_stdout: libc::c_int,
+}
+
+impl Pager {
+    // Redirect stdout into the configured pager, if enabled and stdout is a terminal
+    fn start(enabled: bool) -> Option<Self> {
+        if !enabled || unsafe { libc::isatty(libc::STDOUT_FILENO) } != 1 {
+            return None;
+        }
+
+        // -F quits if the output fits on one screen, -R keeps colors, -X keeps it on screen
+        let command = env::var(env_vars::PAGER)
+            .or_else(|_| env::var("PAGER"))
+            .unwrap_or_else(|_| "less -FRX".to_string());
+        if command.trim().is_empty() || command.trim() == "cat" {
+            return None;
+        }
+
+        let mut child = Command::new("sh")
+            .args(["-c", &command])
+            .stdin(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let stdin = child.stdin.take()?;
+        let _ = io::stdout().flush();
+        let saved_stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        unsafe { libc::dup2(stdin.as_raw_fd(), libc::STDOUT_FILENO) };
+
+        Some(Self {
+            child,
+            saved_stdout,
+        })
+    }
+}
+
+impl Drop for Pager {
+    // Restore stdout, closing the pipe so the pager sees the end, then wait for it
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::dup2(self.saved_stdout, libc::STDOUT_FILENO);
+            libc::close(self.saved_stdout);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+// Whether to color output, decided once at startup
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+// Decide whether to colorize output: as chosen with --color, never when NO_COLOR is
+// set, and by default only on a terminal. This runs before a pager takes over stdout,
+// so output paged through `less -R` keeps the colors it would have on the terminal.
+fn init_color(choice: ColorChoice) {
+    let _ = COLOR.set(choice.enabled(
+        env::var_os("NO_COLOR").is_some(),
+        unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1,
+    ));
+}
+
+// Whether to colorize output
+fn use_color() -> bool {
+    if COLOR.get().is_none() {
+        init_color(ColorChoice::default());
+    }
+    COLOR.get().copied().unwrap_or_default()
+}
+
+// Underline a section heading when coloring output
+fn print_heading(heading: &str) {
+    if use_color() {
+        println!("\u{001b}[4m{heading}\u{001b}[0m");
+    } else {
+        println!("{heading}");
+    }
+}
+
+fn cmd_status(
+    modules: &[String],
+    exit_zero: bool,
+    fail_under: Option<usize>,
+    name_filter: Option<&Regex>,
+    output: &OutputOptions,
+) -> Result<()> {
+    let manager =
+        ModuleManager::new().context("failed to initialize module manager for checking status")?;
+    let mut status_list = if modules.is_empty() {
+        manager.get_all_status()
+    } else {
+        manager.get_status(modules)
+    };
+    if let Some(filter) = name_filter {
+        status_list.retain(|status| filter.is_match(&status.name));
+    }
+    let passes = status_check_passes(&status_list, fail_under);
+
+    if output.json {
         // Output as JSON
-        let json = serde_json::to_string_pretty(&status_list)
+        let json = output
+            .render_statuses(&status_list)
             .context("failed to serialize module status to JSON")?;
-        println!("{json}");
+        output.emit(&json)?;
+    } else if status_list.len() == 1 {
+        // Keep the bare state word for a single module, for script compatibility
+        println!("{}", status_list[0].state);
+        if status_list[0].pending {
+            eprintln!("note: change pending, run 'runtime-modules rebuild' to apply");
+        } else if status_list[0].ephemeral {
+            eprintln!("note: enabled only until the next reboot");
+        }
     } else {
+        let max_name_length = status_list
+            .iter()
+            .map(|status| status.name.len())
+            .max()
+            .unwrap_or(0);
+        let color = use_color();
+
         for status in &status_list {
-            match status.state {
-                ModuleState::Enabled => println!("enabled"),
-                ModuleState::Disabled => println!("disabled"),
-                ModuleState::Uncertain => println!("uncertain"),
-            }
+            println!("{}", status.format_aligned(max_name_length, color));
+        }
+    }
+
+    // Exit with non-zero status if the check fails, unless asked not to
+    if !passes && !exit_zero {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+fn cmd_doctor(options: &ApplyOptions, output: &OutputOptions) -> Result<()> {
+    let mut checks = ModuleManager::doctor_checks();
+    checks.push(check_runtime_flake(options));
+    checks.push(check_nix_available());
+    let ok = checks.iter().all(|check| check.ok);
+    let config = ResolvedConfig::resolve(options);
+
+    if output.json {
+        let json = output
+            .render(&DoctorOutput {
+                ok,
+                checks: &checks,
+                config: &config,
+            })
+            .context("failed to serialize doctor checks to JSON")?;
+        output.emit(&json)?;
+    } else {
+        for check in &checks {
+            let mark = if check.ok { "ok" } else { "FAIL" };
+            println!("[{mark}] {}: {}", check.check, check.detail);
+        }
+        println!("resolved configuration:");
+        config.print();
+    }
+
+    if !ok {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+fn cmd_check_registry(path: &Path, output: &OutputOptions) -> Result<()> {
+    let registry = ModuleRegistry::from_file(path)?;
+    let problems = registry.validate();
+    let ok = problems.is_empty();
+
+    if output.json {
+        let json = output
+            .render(&CheckRegistryOutput {
+                ok,
+                problems: &problems,
+            })
+            .context("failed to serialize registry problems to JSON")?;
+        output.emit(&json)?;
+    } else if ok {
+        println!("{}: no problems found", path.display());
+    } else {
+        for problem in &problems {
+            println!("{}: {problem}", path.display());
         }
     }
 
-    // Exit with non-zero status if any module is not fully enabled
-    if not_fully_enabled {
+    if !ok {
         exit(1);
     }
 
     Ok(())
 }
 
-fn cmd_rebuild(force: bool) -> Result<()> {
+fn cmd_activate(toplevel: PathBuf, options: ApplyOptions, output: &OutputOptions) -> Result<()> {
+    let mut manager = ModuleManager::new_writable()
+        .context("failed to initialize module manager for activation")?;
+    manager.set_apply_options(ApplyOptions {
+        toplevel: Some(toplevel),
+        ..options
+    });
+    let result = manager.activate().context("failed to activate system");
+    report_operation(output, "activate", &mut manager, result)
+}
+
+fn cmd_rebuild(force: bool, options: ApplyOptions, output: &OutputOptions) -> Result<()> {
     let mut manager =
-        ModuleManager::new().context("failed to initialize module manager for rebuild")?;
-    manager.rebuild(force).context("failed to rebuild system")
+        ModuleManager::new_writable().context("failed to initialize module manager for rebuild")?;
+    manager.set_apply_options(options);
+    let result = manager.rebuild(force).context("failed to rebuild system");
+    report_operation(output, "rebuild", &mut manager, result)
+}
+
+fn cmd_show(generated: bool) -> Result<()> {
+    if generated {
+        let manager =
+            ModuleManager::new().context("failed to initialize module manager for show")?;
+        println!("{}", manager.generated_content());
+    } else {
+        match ModuleManager::saved_content()? {
+            Some(content) => println!("{}", content.trim_end()),
+            None => eprintln!("no state file saved yet"),
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_info(module: &str, output: &OutputOptions) -> Result<()> {
+    let manager = ModuleManager::new().context("failed to initialize module manager for info")?;
+    let status = manager.get_info(module)?;
+
+    if output.json {
+        let json = output
+            .render(&status)
+            .context("failed to serialize module info to JSON")?;
+        output.emit(&json)?;
+    } else {
+        println!("name:         {}", status.name);
+        println!("state:        {}", status.state);
+        if status.pending {
+            println!("pending:      yes, run 'runtime-modules rebuild' to apply");
+        }
+        if status.ephemeral {
+            println!("ephemeral:    yes, enabled until the next reboot");
+        }
+        if !status.desc.is_empty() {
+            println!("description:  {}", status.desc);
+        }
+        if !status.path.is_empty() {
+            println!("path:         {}", status.path);
+        }
+        println!(
+            "last enabled: {}",
+            status.enabled_at.as_deref().unwrap_or("never")
+        );
+    }
+
+    Ok(())
+}
+
+fn cmd_dependents(module: &str, enabled_only: bool, output: &OutputOptions) -> Result<()> {
+    let manager =
+        ModuleManager::new().context("failed to initialize module manager for dependents")?;
+    let dependents = manager.get_dependents(module, enabled_only)?;
+
+    if output.json {
+        let json = output
+            .render_statuses(&dependents)
+            .context("failed to serialize dependents to JSON")?;
+        output.emit(&json)?;
+    } else if dependents.is_empty() {
+        println!("no modules depend on {module}");
+    } else {
+        let max_name_length = dependents
+            .iter()
+            .map(|status| status.name.len())
+            .max()
+            .unwrap_or(0);
+        let color = use_color();
+
+        for status in &dependents {
+            println!("{}", status.format_aligned(max_name_length, color));
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_sync(from_flake: &str, attr: &str, output: &OutputOptions) -> Result<()> {
+    let flake = env::current_dir().map_or_else(
+        |_| from_flake.to_string(),
+        |cwd| resolve_flake_ref(from_flake, &cwd),
+    );
+    let installable = if flake.contains('#') {
+        flake
+    } else {
+        format!("{flake}#{attr}")
+    };
+
+    let json_content = eval_flake_json(&installable)?;
+    let registry = ModuleRegistry::from_introspection(&json_content)
+        .with_context(|| format!("unexpected module definitions from {installable}"))?;
+    let count = registry.modules.len();
+    let changes = ModuleManager::replace_registry(registry)?;
+
+    if output.json {
+        let json = output
+            .render(&changes)
+            .context("failed to serialize sync result to JSON")?;
+        output.emit(&json)?;
+    } else {
+        println!(
+            "synced {count} modules: {} added, {} removed, {} changed",
+            changes.added.len(),
+            changes.removed.len(),
+            changes.changed.len()
+        );
+        for (label, names) in [
+            ("added", &changes.added),
+            ("removed", &changes.removed),
+            ("changed", &changes.changed),
+        ] {
+            for name in names {
+                println!("  {label}: {name}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_graph(format: GraphFormat, output: &OutputOptions) -> Result<()> {
+    let registry = ModuleManager::load_registry()?;
+
+    match format {
+        GraphFormat::Dot => print!("{}", registry.to_dot()),
+        GraphFormat::Json => {
+            let json = output
+                .render(&registry.dependency_graph())
+                .context("failed to serialize module graph to JSON")?;
+            output.emit(&json)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Registry as loaded, including the lookup map
+#[derive(Serialize)]
+struct RegistryDump<'a> {
+    modules: &'a [Module],
+    lookup_map: Option<BTreeMap<&'a str, usize>>,
+}
+
+fn cmd_schema_registry(output: &OutputOptions) -> Result<()> {
+    let json = to_json(&registry_schema(), !output.compact)
+        .context("failed to serialize registry schema")?;
+    output.emit(&json)
+}
+
+fn cmd_debug_registry(output: &OutputOptions) -> Result<()> {
+    let registry = ModuleManager::load_registry()?;
+
+    if output.json {
+        let lookup_map = registry.get_lookup_map().map(|map| {
+            map.iter()
+                .map(|(name, index)| (name.as_str(), *index))
+                .collect()
+        });
+        let json = output
+            .render(&RegistryDump {
+                modules: &registry.modules,
+                lookup_map,
+            })
+            .context("failed to serialize registry to JSON")?;
+        output.emit(&json)?;
+    } else {
+        println!("{registry:#?}");
+    }
+
+    Ok(())
 }
@@ -0,0 +1,68 @@
+use crate::{paths, system};
+use runtime_modules::{DEFAULT_FILE_MODE, RebuildAction};
+use std::env;
+use std::ffi::OsString;
+
+// Environment variables read by the program
+pub const STATE_DIR: &str = "RUNTIME_MODULES_DIR";
+pub const HOST: &str = "RUNTIME_MODULES_HOST";
+pub const PAGER: &str = "RUNTIME_MODULES_PAGER";
+pub const SUDO: &str = "RUNTIME_MODULES_SUDO";
+pub const ACTION: &str = "RUNTIME_MODULES_ACTION";
+pub const FILE_MODE: &str = "RUNTIME_MODULES_FILE_MODE";
+pub const FILE_GROUP: &str = "RUNTIME_MODULES_FILE_GROUP";
+
+// A flag that falls back to environment variables, and then to a default
+pub struct FlagEnv {
+    // Argument id of the flag, i.e. its field name in `Cli`
+    pub arg: &'static str,
+    // Variables in lookup order
+    pub vars: &'static [&'static str],
+    pub default: Option<String>,
+}
+
+// Flags backed by the environment, used to write their help text
+pub fn flag_env() -> Vec<FlagEnv> {
+    vec![
+        FlagEnv {
+            arg: "state_dir",
+            vars: &[STATE_DIR],
+            default: Some(paths::DEFAULT_STATE_DIR.to_string()),
+        },
+        FlagEnv {
+            arg: "host",
+            vars: &[HOST],
+            default: None,
+        },
+        FlagEnv {
+            arg: "action",
+            vars: &[ACTION],
+            default: Some(RebuildAction::default().to_string()),
+        },
+        FlagEnv {
+            arg: "file_mode",
+            vars: &[FILE_MODE],
+            default: Some(format!("{DEFAULT_FILE_MODE:o}")),
+        },
+        FlagEnv {
+            arg: "file_group",
+            vars: &[FILE_GROUP],
+            default: None,
+        },
+        FlagEnv {
+            arg: "reexec_with",
+            vars: &[SUDO],
+            default: Some(system::DEFAULT_ESCALATION.to_string()),
+        },
+    ]
+}
+
+// Value of an environment variable, treating an empty one as unset
+pub fn value(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|value| !value.is_empty())
+}
+
+// Like `value`, without requiring the value to be UTF-8
+pub fn value_os(var: &str) -> Option<OsString> {
+    env::var_os(var).filter(|value| !value.is_empty())
+}
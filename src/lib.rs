@@ -1,8 +1,14 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 // Module state enum
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
@@ -13,15 +19,194 @@ pub enum ModuleState {
     Uncertain,
 }
 
+impl fmt::Display for ModuleState {
+    // Lowercase state name, as accepted by `from_str`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Enabled => "enabled",
+            Self::Disabled => "disabled",
+            Self::Uncertain => "uncertain",
+        })
+    }
+}
+
+impl FromStr for ModuleState {
+    type Err = anyhow::Error;
+
+    // Parse a state name, ignoring case
+    fn from_str(input: &str) -> Result<Self> {
+        match input.to_ascii_lowercase().as_str() {
+            "enabled" => Ok(Self::Enabled),
+            "disabled" => Ok(Self::Disabled),
+            "uncertain" => Ok(Self::Uncertain),
+            _ => Err(anyhow!(
+                "invalid module state '{input}', expected enabled, disabled or uncertain"
+            )),
+        }
+    }
+}
+
+// How a built configuration is activated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RebuildAction {
+    // Activate without making it the boot default
+    #[default]
+    Test,
+    // Activate and make it the boot default
+    Switch,
+    // Only make it the boot default, activating on the next reboot
+    Boot,
+}
+
+impl RebuildAction {
+    // Whether the configuration is activated right away, rather than on the next boot
+    #[must_use]
+    pub fn activates_now(self) -> bool {
+        self != Self::Boot
+    }
+}
+
+impl fmt::Display for RebuildAction {
+    // Action name, as passed to nixos-rebuild and switch-to-configuration
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Test => "test",
+            Self::Switch => "switch",
+            Self::Boot => "boot",
+        })
+    }
+}
+
+impl FromStr for RebuildAction {
+    type Err = anyhow::Error;
+
+    // Parse an action name, ignoring case
+    fn from_str(input: &str) -> Result<Self> {
+        match input.to_ascii_lowercase().as_str() {
+            "test" => Ok(Self::Test),
+            "switch" => Ok(Self::Switch),
+            "boot" => Ok(Self::Boot),
+            _ => Err(anyhow!(
+                "invalid rebuild action '{input}', expected test, switch or boot"
+            )),
+        }
+    }
+}
+
+// Exit code of switch-to-configuration when the configuration was activated
+// but some units failed to start
+const EXIT_ACTIVATED_WITH_ERRORS: i32 = 4;
+
+// What nixos-rebuild prints before exiting with 1 when switch-to-configuration
+// exited with `EXIT_ACTIVATED_WITH_ERRORS`
+const SWITCH_ERRORS_MESSAGE: &str = "error(s) occurred while switching to the new configuration";
+
+// Outcome of a configuration that was actually activated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyOutcome {
+    Applied,
+    AppliedWithWarnings,
+}
+
+/// Decide how activating went from the exit code of `program` and its output: only
+/// a failed activation is an error, while units that failed to start leave the
+/// configuration applied with warnings
+///
+/// switch-to-configuration reports those with exit code 4, which nixos-rebuild turns
+/// into exit code 1 along with a message saying so.
+///
+/// # Errors
+///
+/// Returns an error naming the exit code, or the signal, if activating failed.
+pub fn activation_outcome(program: &str, code: Option<i32>, log: &str) -> Result<ApplyOutcome> {
+    match code {
+        Some(0) => Ok(ApplyOutcome::Applied),
+        Some(EXIT_ACTIVATED_WITH_ERRORS) if program == "switch-to-configuration" => {
+            Ok(ApplyOutcome::AppliedWithWarnings)
+        }
+        Some(1) if program == "nixos-rebuild" && log.contains(SWITCH_ERRORS_MESSAGE) => {
+            Ok(ApplyOutcome::AppliedWithWarnings)
+        }
+        Some(code) => Err(anyhow!("{program} failed with exit code {code}")),
+        None => Err(anyhow!("{program} was terminated by a signal")),
+    }
+}
+
+// When to color terminal output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    // Only when writing to a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    // Whether to color output, given whether NO_COLOR is set and stdout is a terminal;
+    // NO_COLOR turns color off even with `always`
+    #[must_use]
+    pub fn enabled(self, no_color: bool, is_terminal: bool) -> bool {
+        match self {
+            _ if no_color => false,
+            Self::Auto => is_terminal,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    // Lowercase choice name, as accepted by `from_str`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        })
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    // Parse a color choice, ignoring case
+    fn from_str(input: &str) -> Result<Self> {
+        match input.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(anyhow!(
+                "invalid color choice '{input}', expected auto, always or never"
+            )),
+        }
+    }
+}
+
+// Where a module comes from: the user's configuration or the built-in library
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleOrigin {
+    User,
+    Upstream,
+}
+
 // Module data structures
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModuleRegistry {
     pub modules: Vec<Module>,
+    // Named sets of modules, enabled or disabled together as `@name`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sets: BTreeMap<String, Vec<String>>,
     #[serde(skip)]
     module_map: Option<HashMap<String, usize>>, // name -> index in modules vector
+    #[serde(skip)]
+    merged_descriptions: HashSet<String>, // modules whose desc came from a descriptions file
+    #[serde(skip)]
+    transient: HashSet<String>, // modules added for one run with 'enable --path', never saved
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Module {
     pub name: String,
     #[serde(default)]
@@ -30,6 +215,127 @@ pub struct Module {
     pub desc: String,
     #[serde(default)]
     pub state: ModuleState,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deps: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    // Protected modules are kept by disable/reset unless explicitly forced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+    // Kept by reset unless --keep-none is given, but disabled like any other module
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keep_on_reset: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<ModuleOrigin>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Shell command run after a rebuild enables the module, to check it works
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<String>,
+}
+
+impl Module {
+    // Whether the module is upstream, by its origin or else by the `rt.` name prefix
+    #[must_use]
+    pub fn is_upstream(&self) -> bool {
+        match self.origin {
+            Some(origin) => origin == ModuleOrigin::Upstream,
+            None => self.name.starts_with("rt."),
+        }
+    }
+}
+
+// Module relationships as adjacency lists
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DependencyGraph {
+    pub nodes: Vec<String>,
+    pub deps: BTreeMap<String, Vec<String>>,
+    pub conflicts: BTreeMap<String, Vec<String>>,
+}
+
+// Differences between two registries, by module name
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct RegistryChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+// Inputs deciding whether an operation rebuilds the system
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RebuildDecision {
+    // The requested change alters the state file
+    pub changed: bool,
+    // Affected modules left in an uncertain state, which a rebuild settles
+    pub uncertain: Vec<String>,
+    pub force: bool,
+}
+
+// Why an operation rebuilds the system or skips the rebuild
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebuildReason {
+    Changed,
+    Uncertain,
+    Forced,
+    NoChanges,
+}
+
+impl RebuildDecision {
+    // The reason for the decision, preferring changes over uncertainty over force
+    #[must_use]
+    pub fn reason(&self) -> RebuildReason {
+        if self.changed {
+            RebuildReason::Changed
+        } else if !self.uncertain.is_empty() {
+            RebuildReason::Uncertain
+        } else if self.force {
+            RebuildReason::Forced
+        } else {
+            RebuildReason::NoChanges
+        }
+    }
+}
+
+impl RebuildReason {
+    #[must_use]
+    pub fn rebuilds(self) -> bool {
+        self != Self::NoChanges
+    }
+}
+
+impl fmt::Display for RebuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Changed => "rebuild, the state changes",
+            Self::Uncertain => "rebuild, modules are in an uncertain state",
+            Self::Forced => "rebuild, forced with --force",
+            Self::NoChanges => "skip rebuild, nothing changes",
+        })
+    }
+}
+
+// Result of a single 'doctor' check
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub check: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl CheckResult {
+    // Build a check result from an outcome, using the error chain as the detail on failure
+    #[must_use]
+    pub fn from_result<E: fmt::Display>(check: &str, result: Result<String, E>) -> Self {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, format!("{e:#}")),
+        };
+        Self {
+            check: check.to_string(),
+            ok,
+            detail,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -40,6 +346,113 @@ pub struct ModuleStatus {
     pub state: ModuleState,
     #[serde(default)]
     pub desc: String,
+    // When the module was last enabled, as an ISO 8601 UTC timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled_at: Option<String>,
+    // Changed in the state file, but not yet applied by a rebuild
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+    // Enabled only until the next reboot
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ephemeral: bool,
+}
+
+// Whether statuses pass a status check: at least `fail_under` enabled modules
+// if a threshold is given, otherwise every module enabled
+#[must_use]
+pub fn status_check_passes(statuses: &[ModuleStatus], fail_under: Option<usize>) -> bool {
+    let enabled = statuses
+        .iter()
+        .filter(|status| status.state == ModuleState::Enabled)
+        .count();
+    match fail_under {
+        Some(threshold) => enabled >= threshold,
+        None => enabled == statuses.len(),
+    }
+}
+
+// Escape a Prometheus label value
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render module counts in the Prometheus text exposition format
+///
+/// `pending` is the number of modules with changes not yet applied by a rebuild.
+/// With `per_module`, a `runtime_modules_module_enabled` gauge labelled with the
+/// module name is added for each module.
+#[must_use]
+pub fn render_metrics(statuses: &[ModuleStatus], pending: usize, per_module: bool) -> String {
+    let count = |state: ModuleState| statuses.iter().filter(|s| s.state == state).count();
+    let gauges = [
+        (
+            "runtime_modules_total",
+            "Modules in the registry",
+            statuses.len(),
+        ),
+        (
+            "runtime_modules_enabled_total",
+            "Enabled modules",
+            count(ModuleState::Enabled),
+        ),
+        (
+            "runtime_modules_uncertain_total",
+            "Modules in an uncertain state after a failed rebuild",
+            count(ModuleState::Uncertain),
+        ),
+        (
+            "runtime_modules_pending",
+            "Modules with changes not yet applied by a rebuild",
+            pending,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        out.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+        ));
+    }
+    if per_module {
+        out.push_str("# HELP runtime_modules_module_enabled Whether the module is enabled\n");
+        out.push_str("# TYPE runtime_modules_module_enabled gauge\n");
+        for status in statuses {
+            out.push_str(&format!(
+                "runtime_modules_module_enabled{{module=\"{}\"}} {}\n",
+                escape_label_value(&status.name),
+                u8::from(status.state == ModuleState::Enabled)
+            ));
+        }
+    }
+    out
+}
+
+impl ModuleStatus {
+    // Format as an aligned `name: state` line, optionally colorized by state
+    #[must_use]
+    pub fn format_aligned(&self, name_width: usize, color: bool) -> String {
+        let (state, code) = match self.state {
+            ModuleState::Enabled => ("enabled", "32"),
+            ModuleState::Disabled => ("disabled", "31"),
+            ModuleState::Uncertain => ("uncertain", "33"),
+        };
+        let label = format!("{}:", self.name);
+        let width = name_width + 1;
+        let pending = match (self.pending, self.ephemeral) {
+            (true, _) => " (pending rebuild)",
+            (false, true) => " (until reboot)",
+            (false, false) => "",
+        };
+
+        if color {
+            format!("{label:<width$} \u{001b}[{code}m{state}\u{001b}[0m{pending}")
+        } else {
+            format!("{label:<width$} {state}{pending}")
+        }
+    }
 }
 
 impl ModuleRegistry {
@@ -48,7 +461,10 @@ impl ModuleRegistry {
     pub fn new(modules: Vec<Module>) -> Self {
         Self {
             modules,
+            sets: BTreeMap::new(),
             module_map: None,
+            merged_descriptions: HashSet::new(),
+            transient: HashSet::new(),
         }
     }
 
@@ -59,17 +475,142 @@ impl ModuleRegistry {
     /// Returns an error if the file cannot be read or if it contains invalid JSON.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path_str = path.as_ref().to_string_lossy();
-        let json_content = fs::read_to_string(&path)
+        let file = fs::File::open(&path)
             .with_context(|| format!("failed to read registry from {path_str}"))?;
 
-        let mut registry: ModuleRegistry = serde_json::from_str(&json_content)
-            .with_context(|| format!("failed to parse JSON from {path_str}"))?;
+        // Parse straight from the file, so large registries aren't held in memory twice
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("failed to parse JSON from {path_str}"))
+    }
+
+    /// Load registry from a reader, such as stdin
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input cannot be read or is not a valid registry.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut registry: ModuleRegistry =
+            serde_json::from_reader(reader).context("invalid registry JSON")?;
 
         // Initialize lookup map for efficiency
         registry.init_lookup();
         Ok(registry)
     }
 
+    /// Build a registry from module definitions evaluated out of a flake
+    ///
+    /// Accepts either a list of modules or an object with a `modules` list. Each
+    /// module needs a non-empty, unique `name`; states always start out disabled.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON is invalid or does not have the expected shape.
+    pub fn from_introspection(json_content: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(json_content).context("failed to parse evaluated JSON")?;
+        let (entries, sets) = match value {
+            serde_json::Value::Array(entries) => (entries, BTreeMap::new()),
+            serde_json::Value::Object(mut object) => {
+                let Some(serde_json::Value::Array(entries)) = object.remove("modules") else {
+                    return Err(anyhow!("expected an object with a 'modules' list"));
+                };
+                let sets = match object.remove("sets") {
+                    Some(sets) => serde_json::from_value(sets).context("invalid module sets")?,
+                    None => BTreeMap::new(),
+                };
+                (entries, sets)
+            }
+            _ => return Err(anyhow!("expected a list of modules")),
+        };
+
+        let mut modules = Vec::new();
+        let mut seen = HashSet::new();
+        for (index, entry) in entries.into_iter().enumerate() {
+            let mut module: Module = serde_json::from_value(entry)
+                .with_context(|| format!("invalid module definition at index {index}"))?;
+            if module.name.is_empty() {
+                return Err(anyhow!("module at index {index} has an empty name"));
+            }
+            if !seen.insert(module.name.clone()) {
+                return Err(anyhow!("duplicate module name: {}", module.name));
+            }
+            module.state = ModuleState::Disabled;
+            modules.push(module);
+        }
+
+        let mut registry = Self::new(modules);
+        registry.sets = sets;
+        registry.init_lookup();
+        Ok(registry)
+    }
+
+    /// Expand a named set into its member modules
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the set is not defined or lists modules missing from the registry.
+    pub fn expand_set(&self, name: &str) -> Result<Vec<String>> {
+        let members = self
+            .sets
+            .get(name)
+            .ok_or_else(|| anyhow!("module set not found: @{name}"))?;
+
+        let unknown: Vec<_> = members
+            .iter()
+            .filter(|member| !self.verify_modules_exist(std::slice::from_ref(*member)))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow!(
+                "module set @{name} references unknown modules: {}",
+                unknown.join(", ")
+            ));
+        }
+
+        Ok(members.clone())
+    }
+
+    // Carry over module states from a previous registry for modules present in both
+    pub fn inherit_states(&mut self, previous: &ModuleRegistry) {
+        for module in &mut self.modules {
+            if let Some(old) = previous.modules.iter().find(|old| old.name == module.name) {
+                module.state = old.state.clone();
+            }
+        }
+    }
+
+    // Modules added, removed, or with changed definitions relative to `previous`
+    #[must_use]
+    pub fn changes_since(&self, previous: &ModuleRegistry) -> RegistryChanges {
+        let mut changes = RegistryChanges::default();
+
+        for module in &self.modules {
+            match previous.modules.iter().find(|old| old.name == module.name) {
+                None => changes.added.push(module.name.clone()),
+                Some(old) => {
+                    if old.path != module.path
+                        || old.desc != module.desc
+                        || old.deps != module.deps
+                        || old.conflicts != module.conflicts
+                        || old.protected != module.protected
+                        || old.keep_on_reset != module.keep_on_reset
+                        || old.origin != module.origin
+                        || old.tags != module.tags
+                    {
+                        changes.changed.push(module.name.clone());
+                    }
+                }
+            }
+        }
+        for old in &previous.modules {
+            if !self.modules.iter().any(|module| module.name == old.name) {
+                changes.removed.push(old.name.clone());
+            }
+        }
+
+        changes
+    }
+
     /// Save registry to file
     ///
     /// # Errors
@@ -77,7 +618,19 @@ impl ModuleRegistry {
     /// Returns an error if the file cannot be written or if the JSON serialization fails.
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let path_str = path.as_ref().to_string_lossy();
-        let content = serde_json::to_string_pretty(&self)
+
+        // Keep merged descriptions and transient modules out of the registry file
+        let mut registry = self.clone();
+        registry
+            .modules
+            .retain(|module| !self.transient.contains(&module.name));
+        for module in &mut registry.modules {
+            if self.merged_descriptions.contains(&module.name) {
+                module.desc.clear();
+            }
+        }
+
+        let content = serde_json::to_string_pretty(&registry)
             .with_context(|| "failed to serialize registry to JSON")?;
 
         fs::write(&path, content)
@@ -98,6 +651,221 @@ impl ModuleRegistry {
         Ok(())
     }
 
+    /// Fill empty descriptions from a `name -> desc` JSON file, returning how many were merged
+    ///
+    /// Descriptions already present in the registry take precedence. A missing file merges nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but cannot be read or contains invalid JSON.
+    pub fn merge_descriptions_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let path_ref = path.as_ref();
+
+        if !path_ref.exists() {
+            return Ok(0);
+        }
+
+        let path_str = path_ref.to_string_lossy();
+        let content = fs::read_to_string(path_ref)
+            .with_context(|| format!("failed to read descriptions from {path_str}"))?;
+        let descriptions: HashMap<String, String> = serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse JSON from {path_str}"))?;
+
+        Ok(self.merge_descriptions(&descriptions))
+    }
+
+    // Fill empty descriptions from a name -> desc map, returning how many were merged
+    pub fn merge_descriptions(&mut self, descriptions: &HashMap<String, String>) -> usize {
+        let mut merged = 0;
+
+        for module in &mut self.modules {
+            if !module.desc.is_empty() {
+                continue;
+            }
+            if let Some(desc) = descriptions.get(&module.name) {
+                module.desc.clone_from(desc);
+                self.merged_descriptions.insert(module.name.clone());
+                merged += 1;
+            }
+        }
+
+        merged
+    }
+
+    // Build the dependency/conflict graph of all modules
+    #[must_use]
+    pub fn dependency_graph(&self) -> DependencyGraph {
+        let mut graph = DependencyGraph::default();
+
+        for module in &self.modules {
+            graph.nodes.push(module.name.clone());
+            if !module.deps.is_empty() {
+                graph.deps.insert(module.name.clone(), module.deps.clone());
+            }
+            if !module.conflicts.is_empty() {
+                graph
+                    .conflicts
+                    .insert(module.name.clone(), module.conflicts.clone());
+            }
+        }
+
+        graph
+    }
+
+    // Every problem in the registry: empty or duplicate names, paths that aren't
+    // absolute, references to unknown modules and dependency cycles
+    #[must_use]
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut seen = HashSet::new();
+        for (index, module) in self.modules.iter().enumerate() {
+            if module.name.is_empty() {
+                problems.push(format!("module at index {index} has an empty name"));
+            } else if !seen.insert(module.name.as_str()) {
+                problems.push(format!("duplicate module name: {}", module.name));
+            }
+        }
+
+        for module in &self.modules {
+            let path = Path::new(&module.path);
+            if !module.path.is_empty()
+                && (!path.is_absolute() || path.components().any(|c| c == Component::ParentDir))
+            {
+                problems.push(format!(
+                    "module {} has a path that isn't absolute: {}",
+                    module.name, module.path
+                ));
+            }
+            for (kind, names) in [
+                ("dependency", &module.deps),
+                ("conflict", &module.conflicts),
+            ] {
+                for name in names.iter().filter(|name| !seen.contains(name.as_str())) {
+                    problems.push(format!("module {} has unknown {kind} {name}", module.name));
+                }
+            }
+        }
+
+        for (set, members) in &self.sets {
+            for name in members.iter().filter(|name| !seen.contains(name.as_str())) {
+                problems.push(format!("set {set} has unknown member {name}"));
+            }
+        }
+
+        problems.extend(
+            self.dependency_cycles()
+                .into_iter()
+                .map(|cycle| format!("dependency cycle: {}", cycle.join(" -> "))),
+        );
+        problems
+    }
+
+    // Dependency cycles, each listed from its first module back to that module
+    fn dependency_cycles(&self) -> Vec<Vec<String>> {
+        // Depth-first search, reporting a cycle whenever a module still on the stack is reached
+        fn visit<'a>(
+            name: &'a str,
+            deps: &HashMap<&'a str, &'a [String]>,
+            stack: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+            cycles: &mut Vec<Vec<String>>,
+        ) {
+            if done.contains(name) {
+                return;
+            }
+            if let Some(start) = stack.iter().position(|&entry| entry == name) {
+                let mut cycle: Vec<String> =
+                    stack[start..].iter().map(ToString::to_string).collect();
+                cycle.push(name.to_string());
+                cycles.push(cycle);
+                return;
+            }
+
+            stack.push(name);
+            for dep in deps.get(name).copied().unwrap_or_default() {
+                if deps.contains_key(dep.as_str()) {
+                    visit(dep, deps, stack, done, cycles);
+                }
+            }
+            stack.pop();
+            done.insert(name);
+        }
+
+        // Duplicate names are reported separately, so keep the first definition
+        let mut deps: HashMap<&str, &[String]> = HashMap::new();
+        for module in &self.modules {
+            deps.entry(&module.name).or_insert(&module.deps);
+        }
+        let mut done = HashSet::new();
+        let mut cycles = Vec::new();
+        for module in &self.modules {
+            visit(&module.name, &deps, &mut Vec::new(), &mut done, &mut cycles);
+        }
+        cycles
+    }
+
+    // The modules with everything they transitively depend on: the given modules in
+    // order, then their dependencies as they are found, each once even with cycles
+    #[must_use]
+    pub fn resolve_dependencies(&self, modules: &[String]) -> Vec<String> {
+        let mut resolved: Vec<String> = Vec::new();
+        for module in modules {
+            if !resolved.contains(module) {
+                resolved.push(module.clone());
+            }
+        }
+
+        let mut next = 0;
+        while next < resolved.len() {
+            let name = resolved[next].clone();
+            next += 1;
+            let Some(module) = self.modules.iter().find(|module| module.name == name) else {
+                continue;
+            };
+            for dep in &module.deps {
+                if !resolved.contains(dep) {
+                    resolved.push(dep.clone());
+                }
+            }
+        }
+        resolved
+    }
+
+    // Names of modules that declare `module_name` as a dependency
+    #[must_use]
+    pub fn dependents(&self, module_name: &str) -> Vec<String> {
+        self.modules
+            .iter()
+            .filter(|module| module.deps.iter().any(|dep| dep == module_name))
+            .map(|module| module.name.clone())
+            .collect()
+    }
+
+    // Render the dependency/conflict graph in Graphviz DOT format
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph runtime_modules {\n");
+
+        for module in &self.modules {
+            dot.push_str(&format!("  {};\n", dot_id(&module.name)));
+        }
+        for module in &self.modules {
+            let name = dot_id(&module.name);
+            for dep in &module.deps {
+                dot.push_str(&format!("  {name} -> {};\n", dot_id(dep)));
+            }
+            for conflict in &module.conflicts {
+                dot.push_str(&format!(
+                    "  {name} -> {} [style=dashed, color=red, label=\"conflicts\"];\n",
+                    dot_id(conflict)
+                ));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
     // Initialize the lookup map for efficient module retrieval
     pub fn init_lookup(&mut self) {
         let mut map = HashMap::new();
@@ -125,6 +893,37 @@ impl ModuleRegistry {
         ModuleState::Disabled // Default if not found
     }
 
+    // State of a module as shown to users: uncertain as recorded here, otherwise
+    // enabled if the state file lists it
+    #[must_use]
+    pub fn effective_state(&self, module_name: &str, in_state_file: bool) -> ModuleState {
+        if self.get_state(module_name) == ModuleState::Uncertain {
+            ModuleState::Uncertain
+        } else if in_state_file {
+            ModuleState::Enabled
+        } else {
+            ModuleState::Disabled
+        }
+    }
+
+    // Effective states of many modules at once, looking up the enabled modules in a
+    // single pass rather than once per module
+    #[must_use]
+    pub fn effective_states(
+        &self,
+        active_modules: &[String],
+        modules: &[&str],
+    ) -> BTreeMap<String, ModuleState> {
+        let active: HashSet<&str> = active_modules.iter().map(String::as_str).collect();
+        modules
+            .iter()
+            .map(|module| {
+                let state = self.effective_state(module, active.contains(module));
+                ((*module).to_string(), state)
+            })
+            .collect()
+    }
+
     // Set module state
     pub fn set_state(&mut self, module_name: &str, state: ModuleState) -> bool {
         if let Some(map) = &self.module_map {
@@ -151,10 +950,90 @@ impl ModuleRegistry {
         }
     }
 
-    // Confirm states based on active modules
-    pub fn confirm_states(&mut self, active_modules: &[String]) {
-        // Create set of active modules
-        let active_set: HashSet<_> = active_modules.iter().cloned().collect();
+    // Modules whose recorded state differs from what the active set implies
+    #[must_use]
+    pub fn mismatched_modules(&self, active_modules: &[String]) -> Vec<String> {
+        self.modules
+            .iter()
+            .filter(|module| {
+                let expected = if active_modules.contains(&module.name) {
+                    ModuleState::Enabled
+                } else {
+                    ModuleState::Disabled
+                };
+                module.state != expected
+            })
+            .map(|module| module.name.clone())
+            .collect()
+    }
+
+    // Changes between `applied`, the modules enabled by the last activated rebuild,
+    // and the active set; without a record of it, the states confirmed in the registry
+    // stand in, which activation resets. Uncertain modules count as pending either way
+    #[must_use]
+    pub fn pending_changes(
+        &self,
+        active_modules: &[String],
+        applied: Option<&[String]>,
+    ) -> StateDiff {
+        let confirmed = |module: &str| match self.get_state(module) {
+            ModuleState::Uncertain => None,
+            state => Some(match applied {
+                Some(applied) => applied.iter().any(|name| name == module),
+                None => state == ModuleState::Enabled,
+            }),
+        };
+
+        let mut diff = StateDiff::default();
+        for module in active_modules {
+            if confirmed(module) != Some(true) {
+                diff.enable.push(module.clone());
+            }
+        }
+        let unknown = applied
+            .unwrap_or_default()
+            .iter()
+            .filter(|module| !self.verify_modules_exist(std::slice::from_ref(module)));
+        for module in self
+            .modules
+            .iter()
+            .map(|module| &module.name)
+            .chain(unknown)
+        {
+            if confirmed(module) != Some(false) && !active_modules.contains(module) {
+                diff.disable.push(module.clone());
+            }
+        }
+        diff
+    }
+
+    // Changes staged in the state file without a rebuild: like `pending_changes`,
+    // but leaving out uncertain modules, whose rebuild failed rather than never ran
+    #[must_use]
+    pub fn staged_changes(
+        &self,
+        active_modules: &[String],
+        applied: Option<&[String]>,
+    ) -> StateDiff {
+        let mut diff = self.pending_changes(active_modules, applied);
+        diff.enable
+            .retain(|module| self.get_state(module) != ModuleState::Uncertain);
+        diff.disable
+            .retain(|module| self.get_state(module) != ModuleState::Uncertain);
+        diff
+    }
+
+    // Mark every module active before or after a failed rebuild as uncertain, as
+    // the running system may be anywhere between the two
+    pub fn mark_failed_transition(&mut self, previous: &[String], current: &[String]) {
+        self.mark_uncertain(previous);
+        self.mark_uncertain(current);
+    }
+
+    // Confirm states based on active modules, returning active names missing from the registry
+    pub fn confirm_states(&mut self, active_modules: &[String]) -> Vec<String> {
+        // Create set of active modules
+        let active_set: HashSet<_> = active_modules.iter().cloned().collect();
 
         // Update all module states
         for module in &mut self.modules {
@@ -165,6 +1044,212 @@ impl ModuleRegistry {
             };
             module.state = new_state;
         }
+
+        // Report orphans so callers can warn about them
+        let known: HashSet<_> = self.modules.iter().map(|module| &module.name).collect();
+        let mut unknown: Vec<String> = active_set
+            .into_iter()
+            .filter(|name| !known.contains(name))
+            .collect();
+        unknown.sort();
+        unknown
+    }
+
+    // Whether the named module is upstream, using the name prefix for unknown modules
+    #[must_use]
+    pub fn is_upstream(&self, module_name: &str) -> bool {
+        self.modules
+            .iter()
+            .find(|module| module.name == module_name)
+            .map_or_else(|| module_name.starts_with("rt."), Module::is_upstream)
+    }
+
+    // Names of modules carrying `tag`, in registry order
+    #[must_use]
+    pub fn modules_with_tag(&self, tag: &str) -> Vec<String> {
+        self.modules
+            .iter()
+            .filter(|module| module.tags.iter().any(|t| t == tag))
+            .map(|module| module.name.clone())
+            .collect()
+    }
+
+    // Module names grouped by tag, in registry order, with a module listed under each
+    // of its tags; modules without tags are returned separately
+    #[must_use]
+    pub fn tag_groups(&self) -> (BTreeMap<String, Vec<String>>, Vec<String>) {
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut untagged = Vec::new();
+        for module in &self.modules {
+            if module.tags.is_empty() {
+                untagged.push(module.name.clone());
+            }
+            for tag in &module.tags {
+                let members = groups.entry(tag.clone()).or_default();
+                if !members.contains(&module.name) {
+                    members.push(module.name.clone());
+                }
+            }
+        }
+        (groups, untagged)
+    }
+
+    // Name of the module at a path, ignoring a trailing slash
+    #[must_use]
+    pub fn name_for_path(&self, path: &str) -> Option<&str> {
+        let path = path.trim_end_matches('/');
+        self.modules
+            .iter()
+            .find(|module| !module.path.is_empty() && module.path.trim_end_matches('/') == path)
+            .map(|module| module.name.as_str())
+    }
+
+    /// Replace arguments starting with `/` by the name of the module at that path,
+    /// also looking in `state_paths`, the files recorded for modules added with
+    /// 'enable --path'
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the path if no module has it.
+    pub fn resolve_paths(
+        &self,
+        args: &[String],
+        state_paths: &BTreeMap<String, String>,
+    ) -> Result<Vec<String>> {
+        args.iter()
+            .map(|arg| {
+                if !arg.starts_with('/') {
+                    return Ok(arg.clone());
+                }
+                let path = arg.trim_end_matches('/');
+                self.name_for_path(arg)
+                    .or_else(|| {
+                        state_paths
+                            .iter()
+                            .find(|(_, module_path)| module_path.trim_end_matches('/') == path)
+                            .map(|(name, _)| name.as_str())
+                    })
+                    .map(ToString::to_string)
+                    .ok_or_else(|| anyhow!("no module in the registry has the path {arg}"))
+            })
+            .collect()
+    }
+
+    /// Add a module to the registry
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a module with the same name already exists.
+    pub fn add_module(&mut self, module: Module) -> Result<()> {
+        if self
+            .modules
+            .iter()
+            .any(|existing| existing.name == module.name)
+        {
+            return Err(anyhow!("module already exists: {}", module.name));
+        }
+        self.modules.push(module);
+        if self.module_map.is_some() {
+            self.init_lookup();
+        }
+        Ok(())
+    }
+
+    /// Add a module for this run only, leaving it out of the saved registry
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a module with the same name already exists.
+    pub fn add_transient_module(&mut self, module: Module) -> Result<()> {
+        let name = module.name.clone();
+        self.add_module(module)?;
+        self.transient.insert(name);
+        Ok(())
+    }
+
+    // Whether the module was added for this run only
+    #[must_use]
+    pub fn is_transient(&self, module_name: &str) -> bool {
+        self.transient.contains(module_name)
+    }
+
+    // Health check command of the module, if it has one
+    #[must_use]
+    pub fn health_check(&self, module_name: &str) -> Option<&str> {
+        self.modules
+            .iter()
+            .find(|module| module.name == module_name)
+            .and_then(|module| module.health_check.as_deref())
+    }
+
+    // Whether the module is marked as protected
+    #[must_use]
+    pub fn is_protected(&self, module_name: &str) -> bool {
+        self.modules
+            .iter()
+            .any(|module| module.name == module_name && module.protected)
+    }
+
+    // Split modules into those that may be removed and the protected ones to keep
+    #[must_use]
+    pub fn split_protected(
+        &self,
+        modules: &[String],
+        force_protected: bool,
+    ) -> (Vec<String>, Vec<String>) {
+        if force_protected {
+            return (modules.to_vec(), Vec::new());
+        }
+        modules
+            .iter()
+            .cloned()
+            .partition(|module| !self.is_protected(module))
+    }
+
+    // Fail if `old` is protected and would be swapped out without forcing
+    pub fn check_replaceable(&self, old: &str, force_protected: bool) -> Result<()> {
+        if self.is_protected(old) && !force_protected {
+            Err(anyhow!(
+                "module {old} is protected, use --force-protected to replace it"
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Whether the module is marked to be kept on reset
+    #[must_use]
+    pub fn is_kept_on_reset(&self, module_name: &str) -> bool {
+        self.modules
+            .iter()
+            .any(|module| module.name == module_name && module.keep_on_reset)
+    }
+
+    // Split modules into those a reset removes and those it keeps: protected modules
+    // unless forced, and modules marked `keep_on_reset` or listed in `keep` unless
+    // `keep_none` is set
+    #[must_use]
+    pub fn split_reset(&self, modules: &[String], keep: &ResetKeep) -> (Vec<String>, Vec<String>) {
+        let (removable, mut kept) = self.split_protected(modules, keep.force_protected);
+        let (removable, also_kept) = if keep.keep_none {
+            (removable, Vec::new())
+        } else {
+            removable
+                .into_iter()
+                .partition(|module| !self.is_kept_on_reset(module) && !keep.keep.contains(module))
+        };
+        kept.extend(also_kept);
+        (removable, kept)
+    }
+
+    // Names of modules the registry records as enabled
+    #[must_use]
+    pub fn enabled_modules(&self) -> Vec<String> {
+        self.modules
+            .iter()
+            .filter(|module| module.state == ModuleState::Enabled)
+            .map(|module| module.name.clone())
+            .collect()
     }
 
     // Check if all modules exist in the registry
@@ -193,20 +1278,1043 @@ impl ModuleRegistry {
     }
 }
 
+/// Quote a name as a DOT ID, escaping quotes, backslashes and newlines
+#[must_use]
+pub fn dot_id(name: &str) -> String {
+    let mut id = String::with_capacity(name.len() + 2);
+    id.push('"');
+    for c in name.chars() {
+        match c {
+            '"' => id.push_str("\\\""),
+            '\\' => id.push_str("\\\\"),
+            '\n' => id.push_str("\\n"),
+            c => id.push(c),
+        }
+    }
+    id.push('"');
+    id
+}
+
+/// JSON Schema describing the registry file (`modules.json`)
+///
+/// Kept in sync with the serialized fields of [`ModuleRegistry`] and [`Module`].
+#[must_use]
+pub fn registry_schema() -> serde_json::Value {
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "runtime-modules registry",
+        "type": "object",
+        "required": ["modules"],
+        "properties": {
+            "modules": {
+                "type": "array",
+                "items": { "$ref": "#/$defs/module" }
+            },
+            "sets": {
+                "description": "Named sets of modules, enabled or disabled together as @name",
+                "type": "object",
+                "additionalProperties": {
+                    "type": "array",
+                    "items": { "type": "string" }
+                }
+            }
+        },
+        "$defs": {
+            "module": {
+                "type": "object",
+                "required": ["name"],
+                "properties": {
+                    "name": {
+                        "type": "string",
+                        "minLength": 1,
+                        "description": "Unique identifier used in CLI commands"
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "Path of the module in the Nix store"
+                    },
+                    "desc": {
+                        "type": "string",
+                        "description": "Short description of what the module provides"
+                    },
+                    "state": {
+                        "enum": ["Enabled", "Disabled", "Uncertain"],
+                        "default": "Disabled"
+                    },
+                    "deps": {
+                        "description": "Names of modules this module depends on",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "conflicts": {
+                        "description": "Names of modules this module conflicts with",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "protected": {
+                        "type": "boolean",
+                        "default": false,
+                        "description": "Kept by disable/reset unless --force-protected is given"
+                    },
+                    "keep_on_reset": {
+                        "type": "boolean",
+                        "default": false,
+                        "description": "Kept by reset unless --keep-none is given"
+                    },
+                    "tags": {
+                        "description": "Labels for selecting modules with --tag",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "health_check": {
+                        "type": ["string", "null"],
+                        "description": "Shell command run after enabling; a non-zero exit fails it"
+                    },
+                    "origin": {
+                        "enum": ["user", "upstream"],
+                        "description": "Whether the module is a user or an upstream module"
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Anchor a relative flake path to `cwd`, leaving absolute paths and URLs unchanged
+///
+/// Handles plain paths and the `path:` scheme, keeping any `?query` or `#fragment` suffix.
+#[must_use]
+pub fn resolve_flake_ref(flake_ref: &str, cwd: &Path) -> String {
+    let (scheme, rest) = match flake_ref.strip_prefix("path:") {
+        Some(rest) => ("path:", rest),
+        None if flake_ref.contains(':') => return flake_ref.to_string(),
+        None => ("", flake_ref),
+    };
+
+    let split = rest.find(['?', '#']).unwrap_or(rest.len());
+    let (path, suffix) = rest.split_at(split);
+    if path.starts_with('/') {
+        return flake_ref.to_string();
+    }
+
+    let absolute = cwd.join(if path.is_empty() { "." } else { path });
+    let normalized: PathBuf =
+        absolute
+            .components()
+            .fold(PathBuf::new(), |mut normalized, component| {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => {
+                        normalized.pop();
+                    }
+                    other => normalized.push(other),
+                }
+                normalized
+            });
+    format!("{scheme}{}{suffix}", normalized.display())
+}
+
+/// Render `path` as an import relative to `flake_dir`, like `./modules/foo.nix`, if
+/// it lies under `base`; other paths stay absolute and give `None`
+///
+/// # Errors
+///
+/// Returns an error if `base` is not inside `flake_dir`, as the relative imports
+/// wouldn't resolve from the flake then.
+pub fn relative_import(path: &Path, base: &Path, flake_dir: &Path) -> Result<Option<String>> {
+    if !base.starts_with(flake_dir) {
+        return Err(anyhow!(
+            "import base {} is not inside the flake directory {}, so imports relative to it \
+             wouldn't resolve",
+            base.display(),
+            flake_dir.display()
+        ));
+    }
+    if !path.starts_with(base) {
+        return Ok(None);
+    }
+    Ok(path
+        .strip_prefix(flake_dir)
+        .ok()
+        .map(|relative| format!("./{}", relative.display())))
+}
+
+/// Resolve a flake reference to a local directory, if it points at one
+///
+/// Supports plain paths and the `path:` and `git+file:` schemes; query parameters are ignored.
+#[must_use]
+pub fn local_flake_dir(flake_ref: &str) -> Option<PathBuf> {
+    let without_query = flake_ref.split(['?', '#']).next().unwrap_or_default();
+    let path = if let Some(rest) = without_query.strip_prefix("git+file:") {
+        rest.trim_start_matches("//")
+    } else if let Some(rest) = without_query.strip_prefix("path:") {
+        rest
+    } else if without_query.starts_with('/') {
+        without_query
+    } else {
+        return None;
+    };
+
+    (!path.is_empty()).then(|| PathBuf::from(path))
+}
+
+// Format a Unix timestamp as an ISO 8601 UTC date and time
+#[must_use]
+pub fn format_timestamp(secs: u64) -> String {
+    let days = secs / 86_400;
+    let time = secs % 86_400;
+
+    // Civil date from days since the epoch, counted in 400-year eras from March 1st
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Parse a human duration such as `90s`, `30m`, `24h`, `7d` or `1h30m`
+///
+/// A bare number is taken as seconds.
+///
+/// # Errors
+///
+/// Returns an error if the input is empty, has an unknown unit, or overflows.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let invalid = || anyhow!("invalid duration '{input}', expected e.g. 90s, 30m, 24h or 7d");
+
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(secs) = input.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(digits);
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let scale = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total = number
+            .checked_mul(scale)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+        rest = tail;
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Score how well `query` fuzzy-matches `candidate`, lower being better
+///
+/// The characters of the query must appear in order, ignoring case. Tighter and
+/// earlier matches score better; an empty query matches everything equally.
+#[must_use]
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<usize> {
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut position = 0;
+    let mut first = None;
+
+    for wanted in query.to_lowercase().chars() {
+        let found = candidate[position..].iter().position(|&c| c == wanted)? + position;
+        first.get_or_insert(found);
+        position = found + 1;
+    }
+
+    Some(first.map_or(0, |first| (position - first) * 2 + first))
+}
+
+/// Replace numeric arguments with the module shown at that 1-based index by the last listing
+///
+/// Arguments that exactly match a module name are kept as names, even if all digits.
+///
+/// # Errors
+///
+/// Returns an error if indices are used but the last listing is missing or no longer matches
+/// the current module order, or if an index is out of range.
+pub fn resolve_module_indices(
+    args: &[String],
+    listed: Option<&[String]>,
+    current: &[String],
+) -> Result<Vec<String>> {
+    args.iter()
+        .map(|arg| {
+            let is_index = !arg.is_empty() && arg.bytes().all(|b| b.is_ascii_digit());
+            if !is_index || current.contains(arg) {
+                return Ok(arg.clone());
+            }
+
+            let listed = listed.filter(|listed| *listed == current).ok_or_else(|| {
+                anyhow!("module index {arg} is unknown or stale, re-run 'runtime-modules list'")
+            })?;
+            arg.parse::<usize>()
+                .ok()
+                .and_then(|index| index.checked_sub(1))
+                .and_then(|index| listed.get(index))
+                .cloned()
+                .ok_or_else(|| anyhow!("no module at index {arg}"))
+        })
+        .collect()
+}
+
+/// Replace arguments that are a unique prefix of a module name with that name
+///
+/// Exact names are kept even if they prefix other modules, and arguments matching
+/// nothing are kept for the caller to report as unknown.
+///
+/// # Errors
+///
+/// Returns an error listing the candidates if a prefix matches more than one module.
+pub fn resolve_module_prefixes(args: &[String], names: &[String]) -> Result<Vec<String>> {
+    args.iter()
+        .map(|arg| {
+            if arg.is_empty() || names.contains(arg) {
+                return Ok(arg.clone());
+            }
+
+            let candidates: Vec<&String> = names
+                .iter()
+                .filter(|name| name.starts_with(arg.as_str()))
+                .collect();
+            match candidates.as_slice() {
+                [] => Ok(arg.clone()),
+                [name] => Ok((*name).clone()),
+                _ => Err(anyhow!(
+                    "ambiguous module name '{arg}', could be: {}",
+                    candidates
+                        .iter()
+                        .map(|name| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Locate a program the way the shell would
+///
+/// A name containing `/` is taken as a path, anything else is looked up in the
+/// directories of `search_path`, a PATH-style list.
+#[must_use]
+pub fn find_in_path(program: &str, search_path: Option<&std::ffi::OsStr>) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+    std::env::split_paths(search_path?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+/// Check that a path is a built NixOS system that can be activated
+///
+/// # Errors
+///
+/// Returns an error if the path doesn't exist, lacks a `nixos-version` file, or
+/// has no executable `bin/switch-to-configuration`.
+pub fn check_system_toplevel<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path_ref = path.as_ref();
+    let toplevel = path_ref
+        .canonicalize()
+        .with_context(|| format!("system {} does not exist", path_ref.display()))?;
+
+    if !toplevel.join("nixos-version").is_file() {
+        return Err(anyhow!(
+            "{} does not look like a NixOS system: no nixos-version file",
+            toplevel.display()
+        ));
+    }
+    let switch = toplevel.join("bin/switch-to-configuration");
+    if find_in_path(&switch.to_string_lossy(), None).is_none() {
+        return Err(anyhow!(
+            "{} does not look like a NixOS system: no executable bin/switch-to-configuration",
+            toplevel.display()
+        ));
+    }
+    Ok(toplevel)
+}
+
+/// Serialize a value as JSON, either pretty-printed or on a single line
+///
+/// # Errors
+///
+/// Returns an error if the value cannot be serialized.
+pub fn to_json<T: Serialize + ?Sized>(value: &T, pretty: bool) -> serde_json::Result<String> {
+    if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+/// Write a file atomically by writing a temporary sibling and renaming it into place
+///
+/// # Errors
+///
+/// Returns an error if the temporary file cannot be written or renamed.
+pub fn write_atomic<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+    let path_ref = path.as_ref();
+    let file_name = path_ref
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid file path {}", path_ref.display()))?;
+
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = path_ref.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, content)
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path_ref).with_context(|| {
+        let _ = fs::remove_file(&tmp_path);
+        format!("failed to move {} into place", tmp_path.display())
+    })
+}
+
+/// Resolve a directory to an absolute path with symlinks followed, so files in it
+/// are written and renamed on the filesystem it actually lives on
+///
+/// A directory that doesn't exist yet is only made absolute.
+#[must_use]
+pub fn canonical_dir(dir: &Path) -> PathBuf {
+    let dir = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+    fs::canonicalize(&dir).unwrap_or(dir)
+}
+
+/// Ensure the state directory exists and can be written to
+///
+/// Fails if the directory has no write permission bits or if creating a file in it
+/// fails, e.g. because it is on a read-only mount.
+///
+/// # Errors
+///
+/// Returns an error explaining how the directory is provisioned if it is not writable.
+pub fn ensure_writable_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    let not_writable = || {
+        anyhow!(
+            "state directory {} is not writable; it is created by the runtime-modules \
+             NixOS module on activation, or use --state-dir to point at a writable directory",
+            dir.display()
+        )
+    };
+
+    let metadata = fs::metadata(dir).map_err(|_| not_writable())?;
+    if !metadata.is_dir() || metadata.permissions().readonly() {
+        return Err(not_writable());
+    }
+
+    let probe = dir.join(format!(".write-test.{}", std::process::id()));
+    fs::write(&probe, "").map_err(|_| not_writable())?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Ensure a directory holds a flake before running nix commands in it
+///
+/// # Errors
+///
+/// Returns an error if there is no `flake.nix` in the directory.
+pub fn ensure_flake_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    if dir.join("flake.nix").is_file() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "no flake.nix found in {}; the runtime-modules NixOS module may not be active",
+            dir.display()
+        ))
+    }
+}
+
+/// Render rows as a bordered table, truncating the last column to fit `max_width`
+///
+/// Uses box-drawing characters, or plain ASCII when `ascii` is set.
+#[must_use]
+pub fn render_table(
+    headers: &[&str],
+    rows: &[Vec<String>],
+    max_width: usize,
+    ascii: bool,
+) -> String {
+    let [h, v, tl, tm, tr, ml, mm, mr, bl, bm, br] = if ascii {
+        ['-', '|', '+', '+', '+', '+', '+', '+', '+', '+', '+']
+    } else {
+        ['─', '│', '┌', '┬', '┐', '├', '┼', '┤', '└', '┴', '┘']
+    };
+    let ellipsis = if ascii { "..." } else { "…" };
+    let ellipsis_width = ellipsis.chars().count();
+
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    // Shrink the last column so the table fits, keeping room for the ellipsis
+    if let Some((last, rest)) = widths.split_last_mut() {
+        let fixed = rest.iter().sum::<usize>() + 3 * headers.len() + 1;
+        let available = max_width.saturating_sub(fixed);
+        *last = (*last).min(available.max(ellipsis_width + 1));
+    }
+
+    let truncate = |cell: &str, width: usize| {
+        if cell.chars().count() <= width {
+            cell.to_string()
+        } else {
+            let kept: String = cell.chars().take(width - ellipsis_width).collect();
+            kept + ellipsis
+        }
+    };
+    let border = |left: char, mid: char, right: char| {
+        let segments: Vec<String> = widths
+            .iter()
+            .map(|width| h.to_string().repeat(width + 2))
+            .collect();
+        format!("{left}{}{right}\n", segments.join(&mid.to_string()))
+    };
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!(" {:<width$} ", truncate(cell, *width)))
+            .collect();
+        format!("{v}{}{v}\n", cells.join(&v.to_string()))
+    };
+
+    let mut table = border(tl, tm, tr);
+    table.push_str(&line(headers.to_vec()));
+    table.push_str(&border(ml, mm, mr));
+    for row in rows {
+        table.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    table.push_str(&border(bl, bm, br));
+    table
+}
+
+// Per-module settings: module name -> (key -> value)
+pub type ModuleSettings = BTreeMap<String, BTreeMap<String, String>>;
+
 // State file format for enabled modules
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct StateFile {
     #[serde(default)]
     pub enabled: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub settings: ModuleSettings,
+    // Paths of modules added with 'enable --path', imported by the flake directly
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub paths: BTreeMap<String, String>,
+    // Modules of `paths` added with 'enable --path --persist', which keep their path
+    // when disabled and are brought back after activation regenerates the registry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub persisted: Vec<String>,
+    // Modules enabled with 'enable --ephemeral', with the boot ID they were enabled in
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ephemeral: BTreeMap<String, String>,
+    // Git revision of the built flake when the file was saved; informational only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+}
+
+impl StateFile {
+    /// Parse state file content, also accepting JSON5 (comments, trailing commas)
+    /// when built with the `json5` feature
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the content cannot be parsed.
+    pub fn parse(content: &str) -> Result<Self> {
+        #[cfg(feature = "json5")]
+        {
+            // Keep the location, which json5 leaves out of the message unlike serde_json
+            json5::from_str(content).map_err(|json5::Error::Message { msg, location }| {
+                match location {
+                    Some(at) => anyhow!("{msg} at line {} column {}", at.line, at.column),
+                    None => anyhow!("{msg}"),
+                }
+            })
+        }
+        #[cfg(not(feature = "json5"))]
+        {
+            serde_json::from_str(content).map_err(Into::into)
+        }
+    }
+
+    /// Parse the enabled modules from the Nix file older versions generated, with one
+    /// `/nix/store/...-source/ # name` import per module
+    ///
+    /// Returns `None` if the content doesn't look like such a file.
+    #[must_use]
+    pub fn parse_legacy(content: &str) -> Option<Self> {
+        if !content.contains("imports") {
+            return None;
+        }
+
+        let mut enabled: Vec<String> = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if !line.starts_with('/') && !line.starts_with('"') {
+                continue;
+            }
+            let Some((_, name)) = line.split_once('#') else {
+                continue;
+            };
+            let name = name.trim();
+            if !name.is_empty() && !enabled.iter().any(|module| module == name) {
+                enabled.push(name.to_string());
+            }
+        }
+
+        Some(Self {
+            enabled,
+            ..Self::default()
+        })
+    }
+
+    /// Parse state file content, falling back to the Nix format of older versions so
+    /// the enabled modules survive an upgrade
+    ///
+    /// # Errors
+    ///
+    /// Returns the JSON parse error if the content is in neither format.
+    pub fn parse_any(content: &str) -> Result<Self> {
+        Self::parse(content).or_else(|err| Self::parse_legacy(content).ok_or(err))
+    }
+
+    /// Load a state file, such as a desired state committed to git
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, contains invalid JSON, or has invalid setting keys.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path_str = path.as_ref().to_string_lossy();
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read state from {path_str}"))?;
+
+        let state = StateFile::parse(&content)
+            .with_context(|| format!("failed to parse JSON from {path_str}"))?;
+
+        for key in state.settings.values().flat_map(BTreeMap::keys) {
+            validate_setting_key(key).with_context(|| format!("invalid state in {path_str}"))?;
+        }
+
+        Ok(state)
+    }
+
+    // All module names referenced by this state, enabled or with settings
+    #[must_use]
+    pub fn referenced_modules(&self) -> Vec<String> {
+        let mut modules = self.enabled.clone();
+        for module in self.settings.keys() {
+            if !modules.contains(module) {
+                modules.push(module.clone());
+            }
+        }
+        modules
+    }
+}
+
+// What normalizing a state file changed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NormalizeReport {
+    // Enabled modules listed more than once
+    pub duplicates: Vec<String>,
+    // Whether the enabled modules were out of order
+    pub reordered: bool,
+    // Modules with settings that aren't enabled
+    pub dropped_settings: Vec<String>,
+    // Modules with a recorded path that are neither enabled nor persisted
+    pub dropped_paths: Vec<String>,
+    // Modules marked ephemeral that aren't enabled
+    pub dropped_ephemeral: Vec<String>,
+}
+
+impl NormalizeReport {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.duplicates.is_empty()
+            && !self.reordered
+            && self.dropped_settings.is_empty()
+            && self.dropped_paths.is_empty()
+            && self.dropped_ephemeral.is_empty()
+    }
+}
+
+// Changes needed to move from one state to another
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct StateDiff {
+    pub enable: Vec<String>,
+    pub disable: Vec<String>,
+    pub settings_changed: Vec<String>,
+}
+
+impl StateDiff {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.enable.is_empty() && self.disable.is_empty() && self.settings_changed.is_empty()
+    }
+}
+
+// Which enabled modules a reset keeps
+#[derive(Debug, Clone, Default)]
+pub struct ResetKeep {
+    // Also disable protected modules
+    pub force_protected: bool,
+    // Modules to keep for this reset, on top of those marked `keep_on_reset`
+    pub keep: Vec<String>,
+    // Ignore `keep_on_reset` and `keep`, so only protected modules are kept
+    pub keep_none: bool,
+}
+
+// What moving to a desired state does to each module it mentions, in one rebuild
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct StateChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub settings_changed: Vec<String>,
+    // Enabled in both states with the same settings
+    pub unchanged: Vec<String>,
+    // Given settings but not enabled, so the settings have no effect
+    pub skipped: Vec<String>,
+}
+
+impl StateChanges {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.settings_changed.is_empty()
+    }
+}
+
+/// Parse a `KEY=VALUE` setting, requiring the key to be a simple identifier
+///
+/// # Errors
+///
+/// Returns an error if the `=` separator is missing or the key is not a valid identifier.
+pub fn parse_setting(input: &str) -> Result<(String, String)> {
+    let (key, value) = input
+        .split_once('=')
+        .ok_or_else(|| anyhow!("invalid setting '{input}', expected KEY=VALUE"))?;
+
+    validate_setting_key(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Check that a setting key is a simple identifier
+///
+/// # Errors
+///
+/// Returns an error if the key is empty or contains characters other than
+/// ASCII letters, digits, `_` and `-`, or starts with a digit or `-`.
+pub fn validate_setting_key(key: &str) -> Result<()> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "invalid setting key '{key}', expected a simple identifier"
+        ))
+    }
+}
+
+/// Check that a module name is safe to pass through shell arguments and Nix
+///
+/// # Errors
+///
+/// Returns an error if the name is empty, starts with `-`, or contains characters
+/// other than ASCII letters, digits, `.`, `_`, `-` and `+`.
+pub fn validate_module_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "unsafe module name '{name}', expected only letters, digits, '.', '_', '-' and \
+             '+' (pass --allow-unsafe-names to accept it)"
+        ))
+    }
+}
+
+// One applied operation, as recorded in the history file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    // Unix time the operation finished
+    pub time: u64,
+    pub action: String,
+    // Modules enabled by the operation's state file
+    pub enabled: Vec<String>,
+    pub ok: bool,
+    // Saved with --no-rebuild, for a later rebuild to apply
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub staged: bool,
+}
+
+impl HistoryEntry {
+    /// Read all records of a history file, oldest first; a missing file has none
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or a line is not a valid record.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<Self>> {
+        let path_ref = path.as_ref();
+        if !path_ref.exists() {
+            return Ok(Vec::new());
+        }
+
+        let path_str = path_ref.to_string_lossy();
+        let content = fs::read_to_string(path_ref)
+            .with_context(|| format!("failed to read history from {path_str}"))?;
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line).with_context(|| {
+                    format!("invalid history record on line {} of {path_str}", index + 1)
+                })
+            })
+            .collect()
+    }
+}
+
+// Records at or after `since` (a Unix time), keeping only the last `limit` of them
+#[must_use]
+pub fn filter_history(
+    entries: Vec<HistoryEntry>,
+    since: Option<u64>,
+    limit: Option<usize>,
+) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = entries
+        .into_iter()
+        .filter(|entry| since.is_none_or(|since| entry.time >= since))
+        .collect();
+    if let Some(limit) = limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+    entries
+}
+
+/// Append a line to a file, creating it if needed
+///
+/// The line is written with a single call under an exclusive advisory lock, so
+/// records from concurrent appenders never interleave.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened, locked or written.
+pub fn append_line<P: AsRef<Path>>(path: P, line: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    let path_ref = path.as_ref();
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path_ref)
+        .with_context(|| format!("failed to open {}", path_ref.display()))?;
+
+    // The lock is released when the file is closed
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            break;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).with_context(|| format!("failed to lock {}", path_ref.display()));
+        }
+    }
+
+    file.write_all(format!("{line}\n").as_bytes())
+        .with_context(|| format!("failed to append to {}", path_ref.display()))
+}
+
+// How a captured command ended, and everything it printed up to then
+#[derive(Debug)]
+pub struct CapturedRun {
+    pub status: std::process::ExitStatus,
+    // Killed because `should_kill` asked for it, rather than exiting by itself
+    pub killed: bool,
+    pub log: String,
+}
+
+// Copy a child's output to our own while keeping it, so it survives the child being killed
+fn tee_output<R, W>(mut from: R, mut to: W, log: Arc<Mutex<Vec<u8>>>) -> thread::JoinHandle<()>
+where
+    R: Read + Send + 'static,
+    W: std::io::Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        while let Ok(read) = from.read(&mut buf) {
+            if read == 0 {
+                break;
+            }
+            let _ = to.write_all(&buf[..read]);
+            let _ = to.flush();
+            if let Ok(mut log) = log.lock() {
+                log.extend_from_slice(&buf[..read]);
+            }
+        }
+    })
+}
+
+/// Run a command to completion in its own process group, showing and capturing its
+/// output
+///
+/// The child's pid is kept in `child_pid` while it runs, so a signal handler can
+/// forward signals to its group. `should_kill` is polled with the time since the
+/// start, and once it returns true the whole group is killed. Descendants of a killed
+/// child may still hold the pipes open, so the output is then taken as captured so
+/// far instead of waiting for the pipes to close.
+///
+/// # Errors
+///
+/// Returns an error if the command cannot be started or waited for.
+pub fn run_captured<F>(
+    command: &mut std::process::Command,
+    child_pid: &std::sync::atomic::AtomicI32,
+    mut should_kill: F,
+) -> Result<CapturedRun>
+where
+    F: FnMut(Duration) -> bool,
+{
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+    use std::sync::atomic::Ordering;
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    let pid = child.id().cast_signed();
+    child_pid.store(pid, Ordering::SeqCst);
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut readers = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(tee_output(stdout, std::io::stdout(), Arc::clone(&log)));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(tee_output(stderr, std::io::stderr(), Arc::clone(&log)));
+    }
+
+    let started = std::time::Instant::now();
+    let mut killed = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if should_kill(started.elapsed()) {
+            killed = true;
+            // The child leads its group, so this reaches its descendants too
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    child_pid.store(0, Ordering::SeqCst);
+
+    if !killed {
+        for reader in readers {
+            let _ = reader.join();
+        }
+    }
+    let log = log.lock().map(|log| log.clone()).unwrap_or_default();
+    Ok(CapturedRun {
+        status,
+        killed,
+        log: String::from_utf8_lossy(&log).into_owned(),
+    })
+}
+
+// Permissions the state file is saved with unless configured otherwise
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Parse file permissions given in octal, such as `640`, `0640` or `0o640`
+///
+/// # Errors
+///
+/// Returns an error if the input isn't one to four octal digits.
+pub fn parse_file_mode(input: &str) -> Result<u32> {
+    let digits = input.strip_prefix("0o").unwrap_or(input);
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| ('0'..='7').contains(&c)) {
+        return Err(anyhow!(
+            "invalid file mode '{input}', expected octal permissions like 644 or 0640"
+        ));
+    }
+    u32::from_str_radix(digits, 8).map_err(Into::into)
 }
 
 // ModuleFile manages the state of enabled modules
 pub struct ModuleFile {
+    // Still public for compatibility; read it through `active_modules()` instead
     pub active_modules: Vec<String>,
+    pub settings: ModuleSettings,
+    paths: BTreeMap<String, String>,
+    persisted: Vec<String>,
+    ephemeral: BTreeMap<String, String>,
+    revision: Option<String>,
+    mode: u32,
+    group: Option<u32>,
 }
 
 impl ModuleFile {
-    /// Create a new `ModuleFile` by reading from JSON state file
+    /// Create a new `ModuleFile` by reading from JSON state file, or from the Nix file
+    /// older versions generated; saving always writes JSON
     ///
     /// # Errors
     ///
@@ -215,21 +2323,45 @@ impl ModuleFile {
         let path_ref = path.as_ref();
 
         if !path_ref.exists() {
-            return Ok(Self {
-                active_modules: Vec::new(),
-            });
+            return Ok(Self::empty());
         }
 
         let path_str = path_ref.to_string_lossy();
         let content = fs::read_to_string(path_ref)
             .with_context(|| format!("failed to read state file from {path_str}"))?;
 
-        let state: StateFile = serde_json::from_str(&content)
+        let state = StateFile::parse_any(&content)
             .with_context(|| format!("failed to parse JSON from {path_str}"))?;
 
-        Ok(Self {
+        Ok(Self::from_state(state))
+    }
+
+    // Create a ModuleFile from a parsed state
+    #[must_use]
+    pub fn from_state(state: StateFile) -> Self {
+        Self {
             active_modules: state.enabled,
-        })
+            settings: state.settings,
+            paths: state.paths,
+            persisted: state.persisted,
+            ephemeral: state.ephemeral,
+            revision: state.revision,
+            mode: DEFAULT_FILE_MODE,
+            group: None,
+        }
+    }
+
+    // Snapshot the enabled modules and their settings
+    #[must_use]
+    pub fn state(&self) -> StateFile {
+        StateFile {
+            enabled: self.active_modules.clone(),
+            settings: self.settings.clone(),
+            paths: self.paths.clone(),
+            persisted: self.persisted.clone(),
+            ephemeral: self.ephemeral.clone(),
+            revision: self.revision.clone(),
+        }
     }
 
     // Create an empty ModuleFile
@@ -237,17 +2369,234 @@ impl ModuleFile {
     pub fn empty() -> Self {
         Self {
             active_modules: Vec::new(),
+            settings: ModuleSettings::new(),
+            paths: BTreeMap::new(),
+            persisted: Vec::new(),
+            ephemeral: BTreeMap::new(),
+            revision: None,
+            mode: DEFAULT_FILE_MODE,
+            group: None,
+        }
+    }
+
+    // Record the git revision to include in the generated content, or none to omit it
+    pub fn set_revision(&mut self, revision: Option<String>) {
+        self.revision = revision;
+    }
+
+    // Permissions and group ID to save the file with; the group is left alone if none
+    pub fn set_file_mode(&mut self, mode: u32, group: Option<u32>) {
+        self.mode = mode;
+        self.group = group;
+    }
+
+    // Modules added with 'enable --path' and the files they import
+    #[must_use]
+    pub fn paths(&self) -> &BTreeMap<String, String> {
+        &self.paths
+    }
+
+    // Record the file imported for a module added with 'enable --path'
+    pub fn set_path(&mut self, module: &str, path: &str) {
+        self.paths.insert(module.to_string(), path.to_string());
+    }
+
+    // Forget the file of a module added with 'enable --path'
+    pub fn remove_path(&mut self, module: &str) -> bool {
+        self.persisted.retain(|name| name != module);
+        self.paths.remove(module).is_some()
+    }
+
+    // Keep the path of a module added with 'enable --path --persist' when it is disabled
+    pub fn set_persisted(&mut self, module: &str) {
+        if !self.is_persisted(module) {
+            self.persisted.push(module.to_string());
+            self.persisted.sort();
         }
     }
 
-    // Parse module names from JSON content
+    // Whether a module was added with 'enable --path --persist'
+    #[must_use]
+    pub fn is_persisted(&self, module_name: &str) -> bool {
+        self.persisted.iter().any(|name| name == module_name)
+    }
+
+    // Mark modules as enabled only until the boot with this ID ends, returning whether
+    // any mark changed
+    pub fn set_ephemeral(&mut self, modules: &[String], boot_id: &str) -> bool {
+        let mut changes = false;
+        for module in modules {
+            if self
+                .ephemeral
+                .insert(module.clone(), boot_id.to_string())
+                .as_deref()
+                != Some(boot_id)
+            {
+                changes = true;
+            }
+        }
+        changes
+    }
+
+    // Keep modules enabled across reboots again, returning whether any was ephemeral
+    pub fn clear_ephemeral(&mut self, modules: &[String]) -> bool {
+        let before = self.ephemeral.len();
+        self.ephemeral.retain(|module, _| !modules.contains(module));
+        self.ephemeral.len() != before
+    }
+
+    // Whether a module was enabled only until reboot
+    #[must_use]
+    pub fn is_ephemeral(&self, module_name: &str) -> bool {
+        self.ephemeral.contains_key(module_name)
+    }
+
+    // Modules enabled only until reboot
+    #[must_use]
+    pub fn ephemeral_modules(&self) -> Vec<String> {
+        self.ephemeral.keys().cloned().collect()
+    }
+
+    // Ephemeral modules enabled in an earlier boot than the one with this ID
+    #[must_use]
+    pub fn stale_ephemeral(&self, boot_id: &str) -> Vec<String> {
+        self.ephemeral
+            .iter()
+            .filter(|(_, enabled_in)| enabled_in.as_str() != boot_id)
+            .map(|(module, _)| module.clone())
+            .collect()
+    }
+
+    // Parse module names from JSON content, or the Nix content of older versions
     #[must_use]
     pub fn parse_active_modules(content: &str) -> Vec<String> {
-        serde_json::from_str::<StateFile>(content)
+        StateFile::parse_any(content)
             .map(|s| s.enabled)
             .unwrap_or_default()
     }
 
+    // Deduplicate and sort the enabled modules and drop settings, ephemeral marks and
+    // unpersisted paths of modules that aren't enabled, reporting what changed
+    pub fn normalize(&mut self) -> NormalizeReport {
+        let mut report = NormalizeReport::default();
+        let mut seen = HashSet::new();
+        self.active_modules.retain(|module| {
+            if seen.insert(module.clone()) {
+                true
+            } else {
+                if !report.duplicates.contains(module) {
+                    report.duplicates.push(module.clone());
+                }
+                false
+            }
+        });
+
+        report.reordered = !self.active_modules.is_sorted();
+        self.active_modules.sort();
+
+        let active = &self.active_modules;
+        self.settings.retain(|module, _| {
+            let keep = active.contains(module);
+            if !keep {
+                report.dropped_settings.push(module.clone());
+            }
+            keep
+        });
+
+        let persisted = &self.persisted;
+        self.paths.retain(|module, _| {
+            let keep = active.contains(module) || persisted.contains(module);
+            if !keep {
+                report.dropped_paths.push(module.clone());
+            }
+            keep
+        });
+        self.persisted
+            .retain(|module| self.paths.contains_key(module));
+
+        self.ephemeral.retain(|module, _| {
+            let keep = active.contains(module);
+            if !keep {
+                report.dropped_ephemeral.push(module.clone());
+            }
+            keep
+        });
+        report
+    }
+
+    // Compute the changes needed to reach the desired state
+    #[must_use]
+    pub fn diff(&self, desired: &StateFile) -> StateDiff {
+        let empty = BTreeMap::new();
+
+        StateDiff {
+            enable: desired
+                .enabled
+                .iter()
+                .filter(|module| !self.is_module_enabled(module))
+                .cloned()
+                .collect(),
+            disable: self
+                .active_modules
+                .iter()
+                .filter(|module| !desired.enabled.contains(module))
+                .cloned()
+                .collect(),
+            settings_changed: desired
+                .enabled
+                .iter()
+                .filter(|module| {
+                    self.is_module_enabled(module)
+                        && self.settings.get(*module).unwrap_or(&empty)
+                            != desired.settings.get(*module).unwrap_or(&empty)
+                })
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Changes needed to move to `desired`, along with the modules it leaves as they are
+    #[must_use]
+    pub fn changes(&self, desired: &StateFile) -> StateChanges {
+        let diff = self.diff(desired);
+        let mut unchanged = Vec::new();
+        for module in &desired.enabled {
+            if !diff.enable.contains(module)
+                && !diff.settings_changed.contains(module)
+                && !unchanged.contains(module)
+            {
+                unchanged.push(module.clone());
+            }
+        }
+
+        StateChanges {
+            added: diff.enable,
+            removed: diff.disable,
+            settings_changed: diff.settings_changed,
+            unchanged,
+            skipped: desired
+                .settings
+                .keys()
+                .filter(|module| !desired.enabled.contains(module))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Active modules, in the order they were enabled
+    #[must_use]
+    pub fn active_modules(&self) -> &[String] {
+        &self.active_modules
+    }
+
+    // Active modules, sorted by name
+    #[must_use]
+    pub fn enabled_modules(&self) -> Vec<String> {
+        let mut modules = self.active_modules.clone();
+        modules.sort();
+        modules
+    }
+
     // Check if a module is enabled
     #[must_use]
     pub fn is_module_enabled(&self, module_name: &str) -> bool {
@@ -268,51 +2617,262 @@ impl ModuleFile {
         changes
     }
 
-    // Disable modules and return if changes were made
-    pub fn disable_modules(&mut self, modules: &[String]) -> bool {
+    // Disable modules and return the ones that were actually removed
+    pub fn disable_modules(&mut self, modules: &[String]) -> Vec<String> {
         let disable_set: HashSet<_> = modules.iter().collect();
-        let original_len = self.active_modules.len();
+        let mut removed = Vec::new();
 
-        self.active_modules
-            .retain(|module| !disable_set.contains(module));
+        self.active_modules.retain(|module| {
+            if disable_set.contains(module) {
+                removed.push(module.clone());
+                false
+            } else {
+                true
+            }
+        });
+        self.settings
+            .retain(|module, _| !disable_set.contains(module));
+        self.ephemeral
+            .retain(|module, _| !disable_set.contains(module));
 
-        original_len != self.active_modules.len()
+        removed
     }
 
-    // Generate JSON content with enabled modules
+    // Merge settings into the given modules and return if changes were made
+    pub fn set_settings(&mut self, modules: &[String], settings: &[(String, String)]) -> bool {
+        let mut changes = false;
+
+        for module in modules {
+            let entry = self.settings.entry(module.clone()).or_default();
+            for (key, value) in settings {
+                if entry.insert(key.clone(), value.clone()).as_ref() != Some(value) {
+                    changes = true;
+                }
+            }
+            if entry.is_empty() {
+                self.settings.remove(module);
+            }
+        }
+
+        changes
+    }
+
+    // Generate JSON content with enabled modules and their settings
     #[must_use]
     pub fn generate_content(&self) -> String {
-        let state = StateFile {
-            enabled: self.active_modules.clone(),
-        };
-        serde_json::to_string_pretty(&state).unwrap_or_else(|_| r#"{"enabled":[]}"#.to_string())
+        serde_json::to_string_pretty(&self.state())
+            .unwrap_or_else(|_| r#"{"enabled":[]}"#.to_string())
+    }
+
+    // Whether the file at `path` was edited by hand: it parses, but saving what it holds
+    // would write different content, losing its formatting, comments or unknown fields.
+    // A missing or unparsable file doesn't count.
+    #[must_use]
+    pub fn is_hand_edited<P: AsRef<Path>>(path: P) -> bool {
+        fs::read_to_string(path).is_ok_and(|content| {
+            StateFile::parse(&content)
+                .is_ok_and(|state| Self::from_state(state).generate_content() != content)
+        })
+    }
+
+    // Check whether the file at `path` holds exactly the content this state would save;
+    // a missing file counts as saved only for the empty base state
+    #[must_use]
+    pub fn is_saved_at<P: AsRef<Path>>(&self, path: P) -> bool {
+        match fs::read_to_string(path) {
+            Ok(content) => content == self.generate_content(),
+            Err(_) => {
+                self.active_modules.is_empty() && self.settings.is_empty() && self.paths.is_empty()
+            }
+        }
     }
 
-    /// Save the state file as JSON
+    /// Save the state file as JSON, returning whether it was written
+    ///
+    /// A file that already holds the same content is left untouched, keeping its mtime,
+    /// apart from its permissions. The file gets the permissions set with
+    /// `set_file_mode`, 644 by default. New content is written to a temporary sibling
+    /// that has these permissions before anything is written to it, and then renamed
+    /// into place, so it is never readable more widely than configured.
     ///
     /// # Errors
     ///
-    /// Returns an error if the file cannot be written or permissions cannot be set.
-    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    /// Returns an error if the file cannot be written or its permissions or group cannot be set.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
         let path_ref = path.as_ref();
         let path_str = path_ref.to_string_lossy();
 
         let content = self.generate_content();
-        fs::write(path_ref, &content)
-            .with_context(|| format!("failed to write state file to {path_str}"))?;
+        if fs::read_to_string(path_ref).is_ok_and(|existing| existing == content) {
+            // The configured permissions may have changed even if the content didn't
+            self.apply_permissions(path_ref)?;
+            return Ok(false);
+        }
 
-        // Fix permissions - set to 644 (rw-r--r--)
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(path_ref)
-                .with_context(|| format!("failed to get metadata for {path_str}"))?
-                .permissions();
-            perms.set_mode(0o644);
-            fs::set_permissions(path_ref, perms)
-                .with_context(|| format!("failed to set permissions for {path_str}"))?;
+        let file_name = path_ref
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid file path {path_str}"))?;
+        let mut tmp_name = file_name.to_os_string();
+        tmp_name.push(format!(".tmp.{}", std::process::id()));
+        let tmp_path = path_ref.with_file_name(tmp_name);
+
+        let written = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(self.mode)
+            .open(&tmp_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut file| {
+                // A leftover temporary file keeps its old permissions, so set them first
+                self.apply_permissions(&tmp_path)?;
+                file.write_all(content.as_bytes())?;
+                Ok(())
+            })
+            .and_then(|()| fs::rename(&tmp_path, path_ref).map_err(anyhow::Error::from));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e).with_context(|| format!("failed to write state file to {path_str}"));
         }
 
+        Ok(true)
+    }
+
+    // Give the file the configured group and permissions where they differ, changing
+    // the group first as that may clear mode bits
+    fn apply_permissions(&self, path: &Path) -> Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let path_str = path.to_string_lossy();
+        let metadata =
+            fs::metadata(path).with_context(|| format!("failed to get metadata for {path_str}"))?;
+        if let Some(group) = self.group.filter(|group| *group != metadata.gid()) {
+            std::os::unix::fs::chown(path, None, Some(group))
+                .with_context(|| format!("failed to set group {group} for {path_str}"))?;
+        }
+        let metadata =
+            fs::metadata(path).with_context(|| format!("failed to get metadata for {path_str}"))?;
+        if metadata.mode() & 0o7777 != self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))
+                .with_context(|| format!("failed to set permissions for {path_str}"))?;
+        }
         Ok(())
     }
 }
+
+// Pending confirmation for changes applied with 'enable --safe'
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingConfirmation {
+    // Unix timestamp after which unconfirmed changes are rolled back
+    pub deadline: u64,
+    // State to restore on rollback
+    pub previous: StateFile,
+    // How the changes were applied, so the rollback builds and activates the same way
+    #[serde(default)]
+    pub options: RollbackOptions,
+}
+
+// Options of the rebuild awaiting confirmation that its rollback reuses
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RollbackOptions {
+    pub flake: Option<String>,
+    pub build_host: Option<String>,
+    pub target_host: Option<String>,
+    pub cores: Option<u32>,
+    pub max_jobs: Option<u32>,
+    pub no_revision: bool,
+    pub no_update: bool,
+    pub timeout_secs: Option<u64>,
+}
+
+impl PendingConfirmation {
+    /// Load a pending confirmation, if one exists
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but cannot be read or parsed.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path_ref = path.as_ref();
+
+        if !path_ref.exists() {
+            return Ok(None);
+        }
+
+        let path_str = path_ref.to_string_lossy();
+        let content = fs::read_to_string(path_ref)
+            .with_context(|| format!("failed to read pending confirmation from {path_str}"))?;
+
+        serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("failed to parse JSON from {path_str}"))
+    }
+
+    /// Save the pending confirmation as JSON
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON serialization fails or the file cannot be written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path_str = path.as_ref().to_string_lossy();
+        let content = serde_json::to_string_pretty(&self)
+            .with_context(|| "failed to serialize pending confirmation to JSON")?;
+
+        fs::write(&path, content)
+            .with_context(|| format!("failed to write pending confirmation to {path_str}"))
+    }
+}
+
+// Modules that may be enabled, by name or by tag
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EnablePolicy {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub allow_tags: Vec<String>,
+}
+
+impl EnablePolicy {
+    /// Load the policy, if one exists
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but cannot be read or parsed.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path_ref = path.as_ref();
+
+        if !path_ref.exists() {
+            return Ok(None);
+        }
+
+        let path_str = path_ref.to_string_lossy();
+        let content = fs::read_to_string(path_ref)
+            .with_context(|| format!("failed to read policy from {path_str}"))?;
+
+        serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("failed to parse JSON from {path_str}"))
+    }
+
+    // Requested modules the policy doesn't allow, neither by name nor by any tag
+    #[must_use]
+    pub fn denied(&self, registry: &ModuleRegistry, modules: &[String]) -> Vec<String> {
+        modules
+            .iter()
+            .filter(|name| {
+                !self.allow.contains(name)
+                    && !registry
+                        .modules
+                        .iter()
+                        .find(|module| &module.name == *name)
+                        .is_some_and(|module| {
+                            module.tags.iter().any(|tag| self.allow_tags.contains(tag))
+                        })
+            })
+            .cloned()
+            .collect()
+    }
+}
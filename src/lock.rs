@@ -0,0 +1,90 @@
+use crate::paths;
+use anyhow::{Context, Result};
+use runtime_modules::ensure_writable_dir;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Constants
+const SHARED_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const SHARED_LOCK_RETRY: Duration = Duration::from_millis(100);
+
+// Advisory lock over the runtime state, released when dropped
+pub struct StateLock {
+    _file: File,
+}
+
+// Try to take a lock without blocking, returning false if it is held elsewhere
+fn try_flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+// Take a lock, blocking until it becomes available
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+impl StateLock {
+    // Take an exclusive lock for commands that modify state, waiting for other holders
+    pub fn exclusive() -> Result<Self> {
+        // Explain a read-only state directory instead of failing to create the lock file
+        ensure_writable_dir(paths::state_dir())?;
+
+        let lock_file = paths::lock_file();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_file)
+            .with_context(|| format!("failed to open lock file {}", lock_file.display()))?;
+
+        if !try_flock(&file, libc::LOCK_EX).context("failed to lock runtime state")? {
+            println!("info: waiting for another runtime-modules operation to finish...");
+            flock(&file, libc::LOCK_EX).context("failed to lock runtime state")?;
+        }
+
+        Ok(Self { _file: file })
+    }
+
+    // Take a shared lock for read-only commands, proceeding without it on timeout
+    pub fn shared() -> Option<Self> {
+        // Without a lock file no writer has ever run, so there is nothing to wait for
+        let file = File::open(paths::lock_file()).ok()?;
+        let deadline = Instant::now() + SHARED_LOCK_TIMEOUT;
+
+        loop {
+            match try_flock(&file, libc::LOCK_SH) {
+                Ok(true) => return Some(Self { _file: file }),
+                Ok(false) if Instant::now() < deadline => thread::sleep(SHARED_LOCK_RETRY),
+                _ => {
+                    eprintln!(
+                        "warning: could not lock runtime state, output may reflect an operation \
+                         in progress"
+                    );
+                    return None;
+                }
+            }
+        }
+    }
+}
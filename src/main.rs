@@ -1,11 +1,15 @@
 use anyhow::{Context, Result};
-use clap::Parser;
 
 mod cli;
+mod env_vars;
+mod lock;
 mod module_manager;
+mod paths;
+mod picker;
 mod system;
 
-use cli::{Cli, execute_command};
+use cli::{execute_command, parse_cli};
+use system::{EXIT_HEALTH_CHECK_FAILED, EXIT_INTERRUPTED, HealthCheckFailed, Interrupted};
 
 fn main() -> Result<()> {
     // Check for deprecated invocation name
@@ -16,8 +20,23 @@ fn main() -> Result<()> {
     }
 
     // Parse command line arguments
-    let cli = Cli::parse();
+    let cli = parse_cli();
 
     // Execute the appropriate command
-    execute_command(&cli).with_context(|| "command execution failed")
+    let result = execute_command(&cli).with_context(|| "command execution failed");
+
+    // Interrupted operations have already recorded their state; exit with a distinct code
+    if let Err(err) = &result {
+        if err.chain().any(|cause| cause.is::<Interrupted>()) {
+            eprintln!("Error: {err:?}");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        // The modules were enabled, only their health checks failed
+        if err.chain().any(|cause| cause.is::<HealthCheckFailed>()) {
+            eprintln!("Error: {err:?}");
+            std::process::exit(EXIT_HEALTH_CHECK_FAILED);
+        }
+    }
+
+    result
 }
@@ -1,29 +1,122 @@
-use crate::system::apply_configuration;
-use anyhow::{Context, Result};
-use runtime_modules::{ModuleFile, ModuleRegistry, ModuleState, ModuleStatus};
+use crate::paths;
+use crate::system::{
+    ApplyOptions, NothingApplied, apply_configuration, boot_id, flake_revision, run_health_check,
+    spawn_confirmation_watcher,
+};
+use anyhow::{Context, Result, anyhow};
+use runtime_modules::{
+    ApplyOutcome, CheckResult, DEFAULT_FILE_MODE, EnablePolicy, HistoryEntry, Module, ModuleFile,
+    ModuleRegistry, ModuleState, ModuleStatus, PendingConfirmation, RebuildAction, RebuildDecision,
+    RegistryChanges, ResetKeep, StateChanges, StateDiff, StateFile, append_line,
+    ensure_writable_dir, filter_history, format_timestamp, relative_import, write_atomic,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-// Constants
-const MODULES_JSON: &str = "/run/runtime-modules/modules.json";
-const STATE_FILE: &str = "/run/runtime-modules/state.json";
+// Registry piped in with --registry-from-stdin, used in place of the registry file
+static STDIN_REGISTRY: OnceLock<ModuleRegistry> = OnceLock::new();
+
+// Permissions and group ID the state file is saved with
+static FILE_PERMISSIONS: OnceLock<(u32, Option<u32>)> = OnceLock::new();
+
+// Set the state file permissions for this process; only the first call has any effect
+pub fn init_file_permissions(mode: u32, group: Option<u32>) {
+    let _ = FILE_PERMISSIONS.set((mode, group));
+}
+
+// Apply the configured permissions before saving a state file
+fn with_file_permissions(module_file: &mut ModuleFile) {
+    let (mode, group) = *FILE_PERMISSIONS.get_or_init(|| (DEFAULT_FILE_MODE, None));
+    module_file.set_file_mode(mode, group);
+}
+
+// Current time as a Unix timestamp
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Outcome of enabling or disabling modules
+#[derive(Debug, Default)]
+pub struct ToggleOutcome {
+    // Whether anything was applied, including settling uncertain modules
+    pub changed: bool,
+    // Requested modules that were already in the requested state
+    pub unchanged: Vec<String>,
+    // Health checks run for newly enabled modules
+    pub health_checks: Vec<CheckResult>,
+    // Per-module changes when moving to a whole desired state
+    pub changes: Option<StateChanges>,
+    // Enabled modules a reset left enabled
+    pub kept: Vec<String>,
+}
+
+// Registry entry for a module file enabled with 'enable --path'
+fn path_module(name: &str, path: &str) -> Module {
+    Module {
+        name: name.to_string(),
+        path: path.to_string(),
+        desc: format!("added from {path}"),
+        ..Module::default()
+    }
+}
 
 // ModuleManager handles the business logic
 pub struct ModuleManager {
     registry: ModuleRegistry,
     module_file: ModuleFile,
+    apply_options: ApplyOptions,
+    warnings: Vec<String>,
+    enabled_at: BTreeMap<String, u64>, // module -> Unix time it was last enabled
+    policy: Option<EnablePolicy>,      // allowlist for enabling, if one is set
+    staged: StateDiff,                 // saved changes not yet applied by a rebuild
+    hand_edited: bool,                 // state file was edited by hand since it was saved
 }
 
 impl ModuleManager {
     // Initialize the manager by loading registry and module file
     pub fn new() -> Result<Self> {
-        let registry =
-            ModuleRegistry::from_file(MODULES_JSON).context("failed to load module registry")?;
+        let mut registry = Self::load_registry()?;
+        registry
+            .merge_descriptions_file(paths::descriptions_file())
+            .context("failed to load module descriptions")?;
         let module_file =
-            ModuleFile::from_file(STATE_FILE).context("failed to load module file")?;
+            ModuleFile::from_file(paths::state_file()).context("failed to load module file")?;
+        let policy = EnablePolicy::from_file(paths::policy_file())
+            .context("failed to load enable policy")?;
+
+        // Bring back modules added with 'enable --path' that the registry doesn't keep,
+        // including persisted ones dropped when activation regenerated the registry
+        for (name, path) in module_file.paths() {
+            if (module_file.is_module_enabled(name) || module_file.is_persisted(name))
+                && !registry.verify_modules_exist(std::slice::from_ref(name))
+            {
+                registry.add_transient_module(path_module(name, path))?;
+            }
+        }
+
+        // Note staged changes before the sync below counts them as enabled
+        let staged = registry.staged_changes(
+            module_file.active_modules(),
+            Self::load_applied().as_deref(),
+        );
 
         // Update the registry states based on active modules
         let mut manager = Self {
             registry,
             module_file,
+            apply_options: ApplyOptions::default(),
+            warnings: Vec::new(),
+            enabled_at: Self::load_enabled_at(),
+            policy,
+            staged,
+            hand_edited: ModuleFile::is_hand_edited(paths::state_file()),
         };
 
         // Sync state with module file at initialization
@@ -32,10 +125,308 @@ impl ModuleManager {
         Ok(manager)
     }
 
+    // Overwrite the state recorded in the registry for one module, for debugging
+    pub fn set_recorded_state(module: &str, state: ModuleState) -> Result<()> {
+        Self::require_registry_file()?;
+        let mut registry = Self::load_registry()?;
+        if !registry.set_state(module, state.clone()) {
+            return Err(anyhow!("module not found: {module}"));
+        }
+        registry
+            .save(paths::modules_json())
+            .context("failed to save module registry")?;
+        println!("recorded {module} as {state}");
+        Ok(())
+    }
+
+    // Modules enabled by the last activated rebuild, if recorded; activation regenerates
+    // the registry with every module disabled, so its states can't tell this
+    fn load_applied() -> Option<Vec<String>> {
+        fs::read_to_string(paths::applied_file())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+
+    // Record the modules the running system now has
+    fn record_applied(active: &[String]) -> Result<()> {
+        let content = serde_json::to_string(active)?;
+        write_atomic(paths::applied_file(), &content)
+            .with_context(|| format!("failed to write {}", paths::applied_file().display()))
+    }
+
+    // Times modules were last enabled; the file is optional and best-effort, so any
+    // problem reading it just leaves the times unknown
+    fn load_enabled_at() -> BTreeMap<String, u64> {
+        fs::read_to_string(paths::enabled_at_file())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    // Record the current time for modules that just became enabled, warning
+    // rather than failing if the times can't be saved
+    fn record_enabled_at(&mut self, previous_active: &[String]) {
+        let now = unix_now();
+        let newly_enabled: Vec<String> = self
+            .module_file
+            .active_modules()
+            .iter()
+            .filter(|module| !previous_active.contains(module))
+            .cloned()
+            .collect();
+        if newly_enabled.is_empty() {
+            return;
+        }
+        for module in newly_enabled {
+            self.enabled_at.insert(module, now);
+        }
+
+        let saved = serde_json::to_string_pretty(&self.enabled_at)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| write_atomic(paths::enabled_at_file(), &content));
+        if let Err(e) = saved {
+            self.warnings
+                .push(format!("failed to record module enable times: {e:#}"));
+        }
+    }
+
+    // Record the boot the configuration was applied in, warning rather than failing
+    // if it can't be saved; without a boot ID there is nothing to compare against
+    fn record_boot_id(&mut self) {
+        let Some(boot_id) = boot_id() else {
+            return;
+        };
+        if let Err(e) = write_atomic(paths::applied_boot_file(), &format!("{boot_id}\n")) {
+            self.warnings
+                .push(format!("failed to record the boot ID: {e:#}"));
+        }
+    }
+
+    // Whether the system rebooted since the configuration was last applied; unknown
+    // when either boot ID is missing, which counts as no reboot
+    pub fn rebooted_since_apply() -> bool {
+        let applied = fs::read_to_string(paths::applied_boot_file()).ok();
+        match (applied, boot_id()) {
+            (Some(applied), Some(current)) => applied.trim() != current,
+            _ => false,
+        }
+    }
+
+    // Recorded operations within `since` of now, keeping only the last `limit`
+    pub fn history(since: Option<Duration>, limit: Option<usize>) -> Result<Vec<HistoryEntry>> {
+        let entries = HistoryEntry::read_file(paths::history_file())?;
+        let since = since.map(|since| unix_now().saturating_sub(since.as_secs()));
+        Ok(filter_history(entries, since, limit))
+    }
+
+    // Append the outcome of an operation to the history file, warning rather than
+    // failing if it can't be written
+    fn record_history(&mut self, action: &str, ok: bool) {
+        let entry = HistoryEntry {
+            time: unix_now(),
+            action: action.to_string(),
+            enabled: self.module_file.active_modules().to_vec(),
+            ok,
+            staged: self.apply_options.no_rebuild,
+        };
+        let recorded = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| append_line(paths::history_file(), &line));
+        if let Err(e) = recorded {
+            self.warnings
+                .push(format!("failed to record operation history: {e:#}"));
+        }
+    }
+
+    // With --explain, print why the requested change does or doesn't rebuild
+    fn explain(&self, requested: &str, decision: &RebuildDecision) {
+        if !self.apply_options.explain {
+            return;
+        }
+
+        let list = |modules: &[String]| {
+            if modules.is_empty() {
+                "none".to_string()
+            } else {
+                modules.join(", ")
+            }
+        };
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        let saved_active = fs::read_to_string(paths::state_file())
+            .map(|content| ModuleFile::parse_active_modules(&content))
+            .unwrap_or_default();
+
+        println!("explain: active modules: {}", list(&saved_active));
+        println!("explain: requested: {requested}");
+        println!("explain: changes detected: {}", yes_no(decision.changed));
+        println!("explain: uncertain modules: {}", list(&decision.uncertain));
+        println!("explain: forced: {}", yes_no(decision.force));
+        println!("explain: decision: {}", decision.reason());
+    }
+
+    // Last enabled time of a module, formatted for output
+    fn enabled_at(&self, module: &str) -> Option<String> {
+        self.enabled_at.get(module).copied().map(format_timestamp)
+    }
+
+    // Initialize the manager for a command that changes state, failing early if
+    // the state directory is read-only
+    pub fn new_writable() -> Result<Self> {
+        Self::require_registry_file()?;
+        ensure_writable_dir(paths::state_dir())?;
+        Self::new()
+    }
+
+    // Add the module file at `path` under `name`, recording the path for the flake to
+    // import, relative to the flake if it lies under `import_base`; the module is kept
+    // in the registry file only with `persist`
+    pub fn add_path_module(
+        &mut self,
+        name: &str,
+        path: &Path,
+        persist: bool,
+        import_base: Option<&Path>,
+    ) -> Result<()> {
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("module path {} does not exist", path.display()))?;
+        let import = match import_base {
+            Some(base) => {
+                let base = fs::canonicalize(base)
+                    .with_context(|| format!("import base {} does not exist", base.display()))?;
+                relative_import(&path, &base, paths::state_dir())?
+            }
+            None => None,
+        };
+        let path = path.to_string_lossy();
+
+        if self.registry.verify_modules_exist(&[name.to_string()]) {
+            if self.registry.name_for_path(&path) != Some(name) {
+                return Err(anyhow!("module already exists: {name}"));
+            }
+        } else if persist {
+            Self::require_registry_file()?;
+            self.registry.add_module(path_module(name, &path))?;
+            self.registry
+                .save(paths::modules_json())
+                .context("failed to save module registry")?;
+            println!("added module {name} to the registry");
+        } else {
+            self.registry
+                .add_transient_module(path_module(name, &path))?;
+        }
+
+        self.module_file
+            .set_path(name, import.as_deref().unwrap_or(&path));
+        if persist {
+            self.module_file.set_persisted(name);
+        }
+        Ok(())
+    }
+
+    // Forget the files of transient modules once they are disabled, so they don't
+    // come back in later runs
+    fn forget_transient_paths(&mut self, removed: &[String]) {
+        for module in removed {
+            if self.registry.is_transient(module) && !self.module_file.is_persisted(module) {
+                self.module_file.remove_path(module);
+            }
+        }
+    }
+
+    // Read the registry from stdin once, to use in place of the registry file
+    pub fn use_registry_from_stdin() -> Result<()> {
+        let registry = ModuleRegistry::from_reader(io::stdin().lock())
+            .context("failed to parse registry from stdin")?;
+        let _ = STDIN_REGISTRY.set(registry);
+        Ok(())
+    }
+
+    // Refuse changes that would save a registry piped in on stdin over the registry file
+    fn require_registry_file() -> Result<()> {
+        if STDIN_REGISTRY.get().is_some() {
+            return Err(anyhow!(
+                "--registry-from-stdin is only for commands that don't change state"
+            ));
+        }
+        Ok(())
+    }
+
+    // Re-parse the state file and write it back in canonical form, without rebuilding
+    pub fn normalize(dry_run: bool) -> Result<()> {
+        let state_file = paths::state_file();
+        let mut module_file =
+            ModuleFile::from_file(&state_file).context("failed to load module file")?;
+        let report = module_file.normalize();
+        with_file_permissions(&mut module_file);
+
+        let (remove, sort, drop) = if dry_run {
+            ("would remove", "would sort", "would drop")
+        } else {
+            ("removed", "sorted", "dropped")
+        };
+        for module in &report.duplicates {
+            println!("{remove} duplicate entries of {module}");
+        }
+        if report.reordered {
+            println!("{sort} the enabled modules");
+        }
+        for module in &report.dropped_settings {
+            println!("{drop} settings of {module}, which is not enabled");
+        }
+        for module in &report.dropped_paths {
+            println!("{drop} the path of {module}, which is not enabled");
+        }
+        for module in &report.dropped_ephemeral {
+            println!("{drop} the ephemeral mark of {module}, which is not enabled");
+        }
+
+        if dry_run {
+            println!("{}", module_file.generate_content());
+            return Ok(());
+        }
+
+        let written = module_file
+            .save(&state_file)
+            .context("failed to save normalized state file")?;
+        if written {
+            println!("normalized '{}'", state_file.display());
+        } else {
+            println!("'{}' is already normalized", state_file.display());
+        }
+        Ok(())
+    }
+
+    // Load only the module registry, without any enabled-module state
+    pub fn load_registry() -> Result<ModuleRegistry> {
+        if let Some(registry) = STDIN_REGISTRY.get() {
+            return Ok(registry.clone());
+        }
+        ModuleRegistry::from_file(paths::modules_json()).context("failed to load module registry")
+    }
+
+    // Set the options used when applying configuration
+    pub fn set_apply_options(&mut self, options: ApplyOptions) {
+        self.apply_options = options;
+    }
+
+    // Git revision to record in the state file, unless suppressed
+    fn revision(options: &ApplyOptions) -> Option<String> {
+        if options.no_revision {
+            None
+        } else {
+            flake_revision(options)
+        }
+    }
+
+    // Take the warnings collected so far
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
     // Sync registry state with active modules in module file
     fn sync_registry_with_module_file(&mut self) {
         // Make sure modules in the module file are marked as Enabled in the registry
-        for module in &self.module_file.active_modules {
+        for module in self.module_file.active_modules() {
             if self.registry.get_state(module) != ModuleState::Uncertain {
                 self.registry.set_state(module, ModuleState::Enabled);
             }
@@ -44,199 +435,913 @@ impl ModuleManager {
 
     // Helper method to get the effective state of a module
     fn get_effective_state(&self, module: &str) -> ModuleState {
-        let is_in_config = self.module_file.is_module_enabled(module);
-        let state = self.registry.get_state(module);
+        self.registry
+            .effective_state(module, self.module_file.is_module_enabled(module))
+    }
 
-        if state == ModuleState::Uncertain {
-            ModuleState::Uncertain
-        } else if is_in_config {
-            ModuleState::Enabled
-        } else {
-            ModuleState::Disabled
+    // Status of a module in a known effective state
+    fn status_of(&self, module: &str, state: ModuleState) -> ModuleStatus {
+        // Find module in registry for details, falling back to a bare status if not found
+        let registry_module = self
+            .registry
+            .get_lookup_map()
+            .and_then(|map| map.get(module))
+            .map(|index| &self.registry.modules[*index]);
+
+        ModuleStatus {
+            name: module.to_string(),
+            path: registry_module.map(|m| m.path.clone()).unwrap_or_default(),
+            state,
+            desc: registry_module.map(|m| m.desc.clone()).unwrap_or_default(),
+            enabled_at: self.enabled_at(module),
+            pending: self.is_staged(module),
+            ephemeral: self.module_file.is_ephemeral(module),
         }
     }
 
-    // Get status for specific modules
+    // Status of many modules by name, answered in one pass over the loaded state so a
+    // manager can be queried repeatedly without reading any files again
+    pub fn statuses(&self, modules: &[&str]) -> BTreeMap<String, ModuleStatus> {
+        self.registry
+            .effective_states(self.module_file.active_modules(), modules)
+            .into_iter()
+            .map(|(name, state)| {
+                let status = self.status_of(&name, state);
+                (name, status)
+            })
+            .collect()
+    }
+
+    // Get status for specific modules, in the order given
     pub fn get_status(&self, modules: &[String]) -> Vec<ModuleStatus> {
+        let names: Vec<&str> = modules.iter().map(String::as_str).collect();
+        let statuses = self.statuses(&names);
         modules
             .iter()
-            .map(|module| {
-                let state = self.get_effective_state(module);
-
-                // Find module in registry for details
-                let module_index = self
-                    .registry
-                    .get_lookup_map()
-                    .and_then(|map| map.get(module))
-                    .copied();
-
-                if let Some(index) = module_index {
-                    let registry_module = &self.registry.modules[index];
-                    ModuleStatus {
-                        name: module.clone(),
-                        path: registry_module.path.clone(),
-                        state,
-                        desc: registry_module.desc.clone(),
-                    }
-                } else {
-                    // Fallback if module not found
-                    ModuleStatus {
-                        name: module.clone(),
-                        path: String::new(),
-                        state,
-                        desc: String::new(),
-                    }
-                }
-            })
+            .filter_map(|module| statuses.get(module).cloned())
             .collect()
     }
 
     // Get status for all modules
     pub fn get_all_status(&self) -> Vec<ModuleStatus> {
+        let names: Vec<&str> = self
+            .registry
+            .modules
+            .iter()
+            .map(|module| module.name.as_str())
+            .collect();
+        let states = self
+            .registry
+            .effective_states(self.module_file.active_modules(), &names);
         self.registry
             .modules
             .iter()
             .map(|module| {
                 let name = &module.name;
-                let state = self.get_effective_state(name);
+                let state = states.get(name).cloned().unwrap_or_default();
 
                 ModuleStatus {
                     name: name.clone(),
                     path: module.path.clone(),
                     state,
                     desc: module.desc.clone(),
+                    enabled_at: self.enabled_at(name),
+                    pending: self.is_staged(name),
+                    ephemeral: self.module_file.is_ephemeral(name),
                 }
             })
             .collect()
     }
 
+    // Whether a module has a change saved but not yet applied by a rebuild
+    fn is_staged(&self, module: &str) -> bool {
+        let module = module.to_string();
+        self.staged.enable.contains(&module) || self.staged.disable.contains(&module)
+    }
+
+    // Whether a module is upstream rather than user-defined
+    pub fn is_upstream(&self, module: &str) -> bool {
+        self.registry.is_upstream(module)
+    }
+
+    // Currently active modules, in the order they were enabled
+    pub fn active_modules(&self) -> &[String] {
+        self.module_file.active_modules()
+    }
+
+    // Modules carrying `tag`, optionally only those currently enabled
+    pub fn tagged_modules(&self, tag: &str, enabled_only: bool) -> Vec<String> {
+        self.registry
+            .modules_with_tag(tag)
+            .into_iter()
+            .filter(|module| !enabled_only || self.module_file.is_module_enabled(module))
+            .collect()
+    }
+
+    // Module names grouped by tag, and the untagged ones
+    pub fn tag_groups(&self) -> (BTreeMap<String, Vec<String>>, Vec<String>) {
+        self.registry.tag_groups()
+    }
+
+    // Fail if the enable policy doesn't allow all of `modules`; without a policy
+    // every module is allowed
+    pub fn check_enable_policy(&self, modules: &[String]) -> Result<()> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        let denied = policy.denied(&self.registry, modules);
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "not allowed to enable by {}: {}",
+                paths::policy_file().display(),
+                denied.join(", ")
+            ))
+        }
+    }
+
+    // Get status for a single known module
+    pub fn get_info(&self, module: &str) -> Result<ModuleStatus> {
+        if !self.registry.verify_modules_exist(&[module.to_string()]) {
+            return Err(anyhow!("module not found: {module}"));
+        }
+        Ok(self.get_status(&[module.to_string()]).remove(0))
+    }
+
+    // Get status for modules that depend on `module`, optionally only enabled ones
+    pub fn get_dependents(&self, module: &str, enabled_only: bool) -> Result<Vec<ModuleStatus>> {
+        if !self.registry.verify_modules_exist(&[module.to_string()]) {
+            return Err(anyhow!("module not found: {module}"));
+        }
+
+        let mut dependents = self.get_status(&self.registry.dependents(module));
+        if enabled_only {
+            dependents.retain(|status| status.state == ModuleState::Enabled);
+        }
+        Ok(dependents)
+    }
+
     // Apply changes and persist state
-    fn apply_changes(&mut self, _force: bool, action_msg: &str) -> Result<()> {
-        // Save the state file
+    fn apply_changes(&mut self, force: bool, action_msg: &str) -> Result<()> {
+        // Saving rewrites the file in canonical form, so don't drop hand edits silently
+        if self.hand_edited && !force && !self.apply_options.overwrite {
+            return Err(anyhow!(
+                "state file '{}' was edited by hand, and saving it would lose the formatting, \
+                 comments or unknown fields; pass --overwrite (or --force) to rewrite it",
+                paths::state_file().display()
+            ));
+        }
+        // Save the state file, remembering the previous one in case nothing gets applied
+        let previous = fs::read_to_string(paths::state_file()).ok();
+        let previous_active = previous
+            .as_deref()
+            .map(ModuleFile::parse_active_modules)
+            .unwrap_or_default();
+        // What the running system has, as staged changes were never applied
+        let previous_active: Vec<String> = previous_active
+            .into_iter()
+            .filter(|module| !self.staged.enable.contains(module))
+            .chain(self.staged.disable.iter().cloned())
+            .collect();
         self.module_file
-            .save(STATE_FILE)
+            .set_revision(Self::revision(&self.apply_options));
+        with_file_permissions(&mut self.module_file);
+
+        // Switching or booting puts ephemeral modules into the boot configuration too
+        if !self.apply_options.no_rebuild && self.apply_options.action != RebuildAction::Test {
+            let ephemeral = self.module_file.ephemeral_modules();
+            if self.module_file.clear_ephemeral(&ephemeral) {
+                self.warnings.push(format!(
+                    "modules enabled until reboot are now part of the boot configuration and \
+                     stay enabled: {}",
+                    ephemeral.join(", ")
+                ));
+            }
+        }
+        let written = self
+            .module_file
+            .save(paths::state_file())
             .with_context(|| format!("failed to save state file after {action_msg}"))?;
-        println!("saved state to '{STATE_FILE}'");
+        if written {
+            println!("saved state to '{}'", paths::state_file().display());
+        } else {
+            println!("state file '{}' unchanged", paths::state_file().display());
+        }
+
+        // Leave the registry untouched, so the change shows as pending until rebuilt
+        if self.apply_options.no_rebuild {
+            println!("{action_msg} without rebuilding, run 'runtime-modules rebuild' to apply");
+            self.record_history(action_msg, true);
+            return Ok(());
+        }
 
         // Apply configuration
-        match apply_configuration() {
-            Ok(()) => {
-                println!("{action_msg} successfully");
-                // Confirm states after successful rebuild
-                self.registry
-                    .confirm_states(&self.module_file.active_modules);
+        match apply_configuration(&self.apply_options, &mut self.warnings) {
+            Ok(outcome) => {
+                self.record_boot_id();
+                if outcome == ApplyOutcome::Applied {
+                    println!("{action_msg} successfully");
+                } else {
+                    println!("{action_msg} with warnings");
+                }
+                // Nothing changed in the running system, so states stay as they were
+                if !self.apply_options.action.activates_now() {
+                    println!("the configuration becomes active after a reboot");
+                    self.record_history(action_msg, true);
+                    return Ok(());
+                }
+                // Units of the changed modules may have failed to start, so only a clean
+                // activation confirms states
+                if outcome == ApplyOutcome::AppliedWithWarnings {
+                    self.warnings.push(
+                        "modules in uncertain state as some units failed to start".to_string(),
+                    );
+                    self.registry.mark_failed_transition(
+                        &previous_active,
+                        self.module_file.active_modules(),
+                    );
+                    self.registry
+                        .save(paths::modules_json())
+                        .context("failed to save registry after rebuild with warnings")?;
+                    self.record_history(action_msg, true);
+                    return Ok(());
+                }
+                self.record_enabled_at(&previous_active);
+                // Confirm states once the configuration is activated
+                let orphans = self
+                    .registry
+                    .confirm_states(self.module_file.active_modules());
+                for module in orphans {
+                    self.warnings
+                        .push(format!("enabled module {module} is not in the registry"));
+                }
                 self.registry
-                    .save(MODULES_JSON)
+                    .save(paths::modules_json())
                     .context("failed to save registry after successful rebuild")?;
+                if let Err(e) = Self::record_applied(self.module_file.active_modules()) {
+                    self.warnings.push(format!("{e:#}"));
+                }
+                self.record_history(action_msg, true);
                 Ok(())
             }
+            // The check runs against the saved file, so put the previous one back
+            Err(e) if e.chain().any(|cause| cause.is::<NothingApplied>()) => {
+                let restored = match &previous {
+                    Some(content) => fs::write(paths::state_file(), content),
+                    None => fs::remove_file(paths::state_file()),
+                };
+                if let Err(err) = restored {
+                    println!("warning: failed to restore the previous state file: {err}");
+                }
+                Err(e)
+            }
             Err(e) => {
-                println!("warning: modules in uncertain state due to rebuild failure");
-                // Mark relevant modules as uncertain
+                self.warnings
+                    .push("modules in uncertain state due to rebuild failure".to_string());
+                // Mark modules active before or after the change as uncertain
                 self.registry
-                    .mark_uncertain(&self.module_file.active_modules);
+                    .mark_failed_transition(&previous_active, self.module_file.active_modules());
                 self.registry
-                    .save(MODULES_JSON)
+                    .save(paths::modules_json())
                     .context("failed to save registry after rebuild failure")?;
+                self.record_history(action_msg, false);
                 Err(e)
             }
         }
     }
 
     // Enable modules with state tracking
-    pub fn enable_modules(&mut self, modules: &[String], force: bool) -> Result<bool> {
-        let mut changes = false;
+    pub fn enable_modules(
+        &mut self,
+        modules: &[String],
+        settings: &[(String, String)],
+        force: bool,
+    ) -> Result<ToggleOutcome> {
+        let mut decision = RebuildDecision {
+            force,
+            ..RebuildDecision::default()
+        };
+        let mut unchanged = Vec::new();
+        let mut newly_enabled = Vec::new();
 
         // Display status and mark modules for change
         for module in modules {
             let current_state = self.get_effective_state(module);
 
             match current_state {
-                ModuleState::Enabled => {
-                    println!("module {module} is already enabled");
-                }
+                ModuleState::Enabled => unchanged.push(module.clone()),
                 ModuleState::Uncertain => {
-                    println!("warning: module {module} is in an uncertain state");
-                    changes = true;
+                    self.warnings
+                        .push(format!("module {module} is in an uncertain state"));
+                    decision.uncertain.push(module.clone());
                 }
                 ModuleState::Disabled => {
                     self.registry.set_state(module, ModuleState::Uncertain);
-                    changes = true;
+                    decision.changed = true;
+                    newly_enabled.push(module.clone());
                 }
             }
         }
 
         // Update the module file
-        let file_changes = self.module_file.enable_modules(modules);
-        changes = changes || file_changes;
+        if self.module_file.enable_modules(modules) {
+            decision.changed = true;
+        }
+
+        // Mark the modules as enabled until reboot, or for good if they were ephemeral
+        if self.apply_options.ephemeral {
+            let boot_id =
+                boot_id().ok_or_else(|| anyhow!("failed to read the boot ID for --ephemeral"))?;
+            if self.module_file.set_ephemeral(&newly_enabled, &boot_id) {
+                decision.changed = true;
+            }
+            for module in modules {
+                if !newly_enabled.contains(module) && !self.module_file.is_ephemeral(module) {
+                    self.warnings.push(format!(
+                        "module {module} was already enabled, it stays enabled after a reboot"
+                    ));
+                }
+            }
+        } else if self.module_file.clear_ephemeral(modules) {
+            println!(
+                "keeping modules enabled after a reboot: {}",
+                modules.join(", ")
+            );
+            decision.changed = true;
+        }
+
+        // Record any settings passed along with the modules
+        if self.module_file.set_settings(modules, settings) {
+            println!("updated settings for modules: {}", modules.join(", "));
+            decision.changed = true;
+        }
 
         // If changes were made or force is set, apply them
-        if changes || force {
+        self.explain(&format!("enable {}", modules.join(", ")), &decision);
+        let mut health_checks = Vec::new();
+        if decision.reason().rebuilds() {
             self.apply_changes(force, "modules enabled")?;
+            let activated =
+                !self.apply_options.no_rebuild && self.apply_options.action.activates_now();
+            if activated && !self.apply_options.skip_health_checks {
+                health_checks = self.run_health_checks(&newly_enabled);
+            }
         } else {
             println!("no changes needed, skipping rebuild");
         }
 
-        Ok(changes)
+        Ok(ToggleOutcome {
+            changed: decision.changed || !decision.uncertain.is_empty(),
+            unchanged,
+            health_checks,
+            ..ToggleOutcome::default()
+        })
     }
 
-    // Disable modules with state tracking
-    pub fn disable_modules(&mut self, modules: &[String], force: bool) -> Result<bool> {
-        let mut changes = false;
-
-        // Display status and mark modules for change
+    // Run the health checks of modules that define one; failures only warn, as the
+    // modules are built and activated either way
+    fn run_health_checks(&mut self, modules: &[String]) -> Vec<CheckResult> {
+        let mut results = Vec::new();
         for module in modules {
-            let current_state = self.get_effective_state(module);
-
-            match current_state {
-                ModuleState::Enabled => {
-                    println!("disabling module {module}...");
-                    self.registry.set_state(module, ModuleState::Uncertain);
-                    changes = true;
-                }
-                ModuleState::Uncertain => {
-                    println!("warning: module {module} is in an uncertain state");
-                    changes = true;
-                }
-                ModuleState::Disabled => {
-                    println!("module {module} is already disabled");
-                }
+            let Some(command) = self.registry.health_check(module) else {
+                continue;
+            };
+            println!("running health check for {module}...");
+            let result = run_health_check(module, command);
+            if !result.ok {
+                self.warnings
+                    .push(format!("health check failed for module {module}"));
             }
+            results.push(result);
+        }
+        results
+    }
+
+    // Disable modules with state tracking
+    pub fn disable_modules(
+        &mut self,
+        modules: &[String],
+        force: bool,
+        force_protected: bool,
+    ) -> Result<ToggleOutcome> {
+        // Refuse to remove protected modules unless forced
+        let (modules, skipped) = self.registry.split_protected(modules, force_protected);
+        for module in &skipped {
+            self.warnings.push(format!(
+                "skipped protected module {module} (use --force-protected to disable it)"
+            ));
         }
 
         // Update the module file
-        let file_changes = self.module_file.disable_modules(modules);
-        changes = changes || file_changes;
+        let removed = self.module_file.disable_modules(&modules);
+        self.forget_transient_paths(&removed);
+        let mut decision = RebuildDecision {
+            changed: !removed.is_empty(),
+            force,
+            ..RebuildDecision::default()
+        };
+        let mut unchanged = Vec::new();
+
+        // Display status and mark modules for change
+        for module in &modules {
+            if removed.contains(module) {
+                println!("disabling module {module}...");
+                self.registry.set_state(module, ModuleState::Uncertain);
+            } else if self.registry.get_state(module) == ModuleState::Uncertain {
+                self.warnings
+                    .push(format!("module {module} is in an uncertain state"));
+                decision.uncertain.push(module.clone());
+            } else {
+                unchanged.push(module.clone());
+            }
+        }
 
         // If changes were made or force is set, apply them
-        if changes || force {
+        self.explain(&format!("disable {}", modules.join(", ")), &decision);
+        if decision.reason().rebuilds() {
             self.apply_changes(force, "modules disabled")?;
         } else {
             println!("no changes needed, skipping rebuild");
         }
 
-        Ok(changes)
+        Ok(ToggleOutcome {
+            changed: decision.changed || !decision.uncertain.is_empty(),
+            unchanged,
+            ..ToggleOutcome::default()
+        })
+    }
+
+    // Move to a complete desired state with a single rebuild
+    pub fn apply_state(&mut self, desired: StateFile, force: bool) -> Result<bool> {
+        let changes = self.module_file.changes(&desired);
+        let decision = RebuildDecision {
+            changed: !changes.is_empty(),
+            force,
+            ..RebuildDecision::default()
+        };
+
+        self.explain("apply a desired state", &decision);
+        if !decision.reason().rebuilds() {
+            println!("already in desired state, skipping rebuild");
+            return Ok(false);
+        }
+
+        // Display the changes and mark modules for change
+        for module in &changes.added {
+            println!("+ {module}");
+        }
+        for module in &changes.removed {
+            println!("- {module}");
+        }
+        for module in &changes.settings_changed {
+            println!("~ {module} (settings)");
+        }
+        self.registry.mark_uncertain(&changes.added);
+        self.registry.mark_uncertain(&changes.removed);
+
+        self.module_file = ModuleFile::from_state(desired);
+        self.apply_changes(force, "desired state applied")?;
+
+        Ok(!changes.is_empty())
+    }
+
+    // Active modules a reset would disable, and the ones it would keep
+    pub fn reset_plan(&self, keep: &ResetKeep) -> (Vec<String>, Vec<String>) {
+        self.registry.split_reset(self.active_modules(), keep)
+    }
+
+    // Whether the module is marked as protected
+    pub fn is_protected(&self, module: &str) -> bool {
+        self.registry.is_protected(module)
+    }
+
+    // Fail if `old` is protected and `force_protected` isn't set
+    pub fn check_replaceable(&self, old: &str, force_protected: bool) -> Result<()> {
+        self.registry.check_replaceable(old, force_protected)
+    }
+
+    // State that would result from disabling `modules`, without changing anything
+    pub fn preview_disable(&self, modules: &[String]) -> StateFile {
+        let mut module_file = ModuleFile::from_state(self.module_file.state());
+        module_file.disable_modules(modules);
+        module_file.state()
+    }
+
+    // The modules with everything they transitively depend on
+    pub fn resolve_dependencies(&self, modules: &[String]) -> Vec<String> {
+        self.registry.resolve_dependencies(modules)
+    }
+
+    // Changes needed to move from the current state to `desired`
+    pub fn diff(&self, desired: &StateFile) -> StateDiff {
+        self.module_file.diff(desired)
+    }
+
+    // Per-module outcome of moving from the current state to `desired`
+    pub fn state_changes(&self, desired: &StateFile) -> StateChanges {
+        self.module_file.changes(desired)
+    }
+
+    // State that would result from enabling `modules`, optionally in place of `replace`
+    pub fn preview_enable(
+        &self,
+        replace: Option<&str>,
+        modules: &[String],
+        settings: &[(String, String)],
+    ) -> StateFile {
+        let mut module_file = ModuleFile::from_state(self.module_file.state());
+        if let Some(old) = replace {
+            module_file.disable_modules(&[old.to_string()]);
+        }
+        module_file.enable_modules(modules);
+        module_file.set_settings(modules, settings);
+        module_file.state()
+    }
+
+    // Swap `old` for `modules` with a single rebuild
+    pub fn replace_modules(
+        &mut self,
+        old: &str,
+        modules: &[String],
+        settings: &[(String, String)],
+        force: bool,
+    ) -> Result<bool> {
+        if self.module_file.is_module_enabled(old) {
+            println!("replacing module {old} with {}...", modules.join(", "));
+        } else {
+            self.warnings
+                .push(format!("module {old} is not enabled, nothing to replace"));
+        }
+
+        let desired = self.preview_enable(Some(old), modules, settings);
+        self.apply_state(desired, force)
     }
 
     // Reset to base system with state tracking
-    pub fn reset(&mut self, force: bool) -> Result<()> {
-        println!("resetting to base system...");
+    pub fn reset(&mut self, force: bool, keep: &ResetKeep) -> Result<ToggleOutcome> {
+        // Keep protected modules enabled unless forced, and the ones asked to be kept
+        let (remove, kept) = self.reset_plan(keep);
+        for module in &kept {
+            if self.is_protected(module) && !keep.force_protected {
+                self.warnings.push(format!(
+                    "kept protected module {module} (use --force-protected to disable it)"
+                ));
+            } else {
+                println!("keeping module {module} (use --keep-none to disable it)");
+            }
+        }
 
-        // If we already have an empty state and force is false, skip
-        if self.module_file.active_modules.is_empty() && !force {
+        // Skip if already at base state, unless the saved file has drifted from it
+        let drifted = remove.is_empty() && !self.module_file.is_saved_at(paths::state_file());
+        let decision = RebuildDecision {
+            changed: !remove.is_empty() || drifted,
+            force,
+            ..RebuildDecision::default()
+        };
+        self.explain("reset to the base system", &decision);
+        let outcome = ToggleOutcome {
+            changed: decision.changed,
+            kept,
+            ..ToggleOutcome::default()
+        };
+        if !decision.reason().rebuilds() {
             println!("system already at base state, skipping rebuild");
-            return Ok(());
+            return Ok(outcome);
+        }
+        if drifted {
+            println!("state file does not match base state, regenerating...");
         }
 
-        // Mark all active modules as uncertain
-        self.registry
-            .mark_uncertain(&self.module_file.active_modules);
+        println!("resetting to base system...");
+
+        // Mark all removed modules as uncertain
+        self.registry.mark_uncertain(&remove);
 
-        // Create empty module file
-        self.module_file = ModuleFile::empty();
+        // Drop everything except the kept modules
+        self.module_file.disable_modules(&remove);
+        self.forget_transient_paths(&remove);
 
         // Apply changes - use the force parameter passed to the method
-        self.apply_changes(force, "system reset")
+        self.apply_changes(force, "system reset")?;
+        Ok(outcome)
+    }
+
+    // Snapshot the current state so it can be restored later
+    pub fn snapshot(&self) -> StateFile {
+        self.module_file.state()
+    }
+
+    // Require confirmation within `window` seconds, rolling back to `previous` otherwise
+    pub fn require_confirmation(&self, previous: StateFile, window: u64) -> Result<()> {
+        // Keep rolling back to the last confirmed state if changes are already pending
+        let previous = PendingConfirmation::from_file(paths::pending_file())?
+            .map_or(previous, |pending| pending.previous);
+        let pending = PendingConfirmation {
+            deadline: unix_now() + window,
+            previous,
+            options: self.apply_options.rollback_options(),
+        };
+        pending
+            .save(paths::pending_file())
+            .context("failed to save pending confirmation")?;
+        spawn_confirmation_watcher()?;
+
+        println!(
+            "run 'runtime-modules confirm' within {window}s to keep these changes, otherwise \
+             they will be rolled back"
+        );
+        Ok(())
+    }
+
+    // Confirm pending changes, returning whether anything was pending
+    pub fn confirm() -> Result<bool> {
+        if PendingConfirmation::from_file(paths::pending_file())?.is_none() {
+            return Ok(false);
+        }
+        fs::remove_file(paths::pending_file())
+            .with_context(|| format!("failed to remove {}", paths::pending_file().display()))?;
+        Ok(true)
+    }
+
+    // Seconds until the pending confirmation expires, if one is pending
+    pub fn confirmation_remaining() -> Result<Option<u64>> {
+        Ok(PendingConfirmation::from_file(paths::pending_file())?
+            .map(|pending| pending.deadline.saturating_sub(unix_now())))
+    }
+
+    // Roll back pending changes whose confirmation window has passed
+    pub fn rollback_unconfirmed(&mut self) -> Result<bool> {
+        let Some(pending) = PendingConfirmation::from_file(paths::pending_file())? else {
+            return Ok(false);
+        };
+        if pending.deadline > unix_now() {
+            return Ok(false);
+        }
+
+        fs::remove_file(paths::pending_file())
+            .with_context(|| format!("failed to remove {}", paths::pending_file().display()))?;
+
+        println!("changes were not confirmed in time, rolling back...");
+        self.registry
+            .mark_uncertain(self.module_file.active_modules());
+        self.module_file = ModuleFile::from_state(pending.previous);
+        self.apply_options = ApplyOptions::for_rollback(pending.options);
+        self.apply_changes(true, "unconfirmed changes rolled back")?;
+        Ok(true)
+    }
+
+    // Ephemeral modules enabled in an earlier boot, which the running system lacks
+    pub fn stale_ephemeral() -> Result<Vec<String>> {
+        let module_file =
+            ModuleFile::from_file(paths::state_file()).context("failed to load module file")?;
+        Ok(boot_id()
+            .map(|boot_id| module_file.stale_ephemeral(&boot_id))
+            .unwrap_or_default())
+    }
+
+    // Drop ephemeral modules enabled in an earlier boot from the tracked state, without
+    // rebuilding: the reboot already removed them from the running system
+    pub fn drop_stale_ephemeral(&mut self) -> Result<Vec<String>> {
+        let Some(boot_id) = boot_id() else {
+            return Ok(Vec::new());
+        };
+        let stale = self.module_file.stale_ephemeral(&boot_id);
+        if stale.is_empty() {
+            return Ok(stale);
+        }
+
+        self.module_file.disable_modules(&stale);
+        for module in &stale {
+            self.registry.set_state(module, ModuleState::Disabled);
+        }
+        with_file_permissions(&mut self.module_file);
+        self.module_file
+            .save(paths::state_file())
+            .context("failed to save state file after dropping ephemeral modules")?;
+        self.registry
+            .save(paths::modules_json())
+            .context("failed to save module registry")?;
+        if let Some(mut applied) = Self::load_applied() {
+            applied.retain(|module| !stale.contains(module));
+            Self::record_applied(&applied)?;
+        }
+        Ok(stale)
+    }
+
+    // Changes in the saved state file not yet applied by a successful rebuild
+    pub fn pending_changes() -> Result<StateDiff> {
+        let registry = Self::load_registry()?;
+        let module_file =
+            ModuleFile::from_file(paths::state_file()).context("failed to load module file")?;
+        Ok(registry.pending_changes(
+            module_file.active_modules(),
+            Self::load_applied().as_deref(),
+        ))
+    }
+
+    // Report modules whose recorded state disagrees with the saved state file; with
+    // `assume_applied`, trust the file and force-confirm all states without rebuilding
+    pub fn reconcile(assume_applied: bool) -> Result<Vec<String>> {
+        let mut registry = Self::load_registry()?;
+        let module_file =
+            ModuleFile::from_file(paths::state_file()).context("failed to load module file")?;
+        let mismatched = registry.mismatched_modules(module_file.active_modules());
+
+        if !assume_applied {
+            for module in &mismatched {
+                let expected = if module_file.is_module_enabled(module) {
+                    ModuleState::Enabled
+                } else {
+                    ModuleState::Disabled
+                };
+                println!(
+                    "{module}: recorded as {}, state file says {expected}",
+                    registry.get_state(module)
+                );
+            }
+            return Ok(mismatched);
+        }
+
+        Self::require_registry_file()?;
+        for module in registry.confirm_states(module_file.active_modules()) {
+            eprintln!("warning: enabled module {module} is not in the registry");
+        }
+        registry
+            .save(paths::modules_json())
+            .context("failed to save module registry")?;
+        Self::record_applied(module_file.active_modules())?;
+        if PendingConfirmation::from_file(paths::pending_file())?.is_some() {
+            fs::remove_file(paths::pending_file())
+                .with_context(|| format!("failed to remove {}", paths::pending_file().display()))?;
+            eprintln!("warning: discarded changes pending confirmation");
+        }
+
+        eprintln!(
+            "warning: force-confirmed all module states from '{}' by user assertion, without \
+             checking the running system",
+            paths::state_file().display()
+        );
+        Ok(mismatched)
+    }
+
+    // Replace the registry, keeping module states and a backup of the previous file
+    pub fn replace_registry(mut registry: ModuleRegistry) -> Result<RegistryChanges> {
+        Self::require_registry_file()?;
+        let modules_json = paths::modules_json();
+        let previous = if modules_json.exists() {
+            let previous = Self::load_registry()?;
+            fs::copy(&modules_json, paths::modules_json_backup())
+                .context("failed to back up module registry")?;
+            previous
+        } else {
+            ModuleRegistry::new(Vec::new())
+        };
+
+        registry.inherit_states(&previous);
+        let changes = registry.changes_since(&previous);
+        registry
+            .save(&modules_json)
+            .context("failed to save module registry")?;
+        Ok(changes)
+    }
+
+    // Rewrite the state file from tracked state without touching the running system,
+    // recovering the enabled modules from the registry if the file is missing or corrupt;
+    // with `to`, write the content there for review instead
+    pub fn regenerate(dry_run: bool, to: Option<&Path>, options: &ApplyOptions) -> Result<()> {
+        let state_file = paths::state_file();
+        let mut module_file = match ModuleFile::from_file(&state_file) {
+            Ok(module_file) if state_file.exists() => module_file,
+            result => {
+                if let Err(e) = result {
+                    eprintln!("warning: {e:#}");
+                }
+                println!("recovering enabled modules from the registry");
+                let mut module_file = ModuleFile::empty();
+                module_file.enable_modules(&Self::load_registry()?.enabled_modules());
+                module_file
+            }
+        };
+        module_file.set_revision(Self::revision(options));
+        with_file_permissions(&mut module_file);
+
+        // A review copy gets default permissions, as it isn't read by the system
+        if let Some(to) = to {
+            if let Some(parent) = to.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create {}", parent.display()))?;
+            }
+            fs::write(to, module_file.generate_content())
+                .with_context(|| format!("failed to write {}", to.display()))?;
+            println!("wrote '{}'", to.display());
+            return Ok(());
+        }
+
+        if dry_run {
+            println!("{}", module_file.generate_content());
+            return Ok(());
+        }
+
+        let written = module_file
+            .save(&state_file)
+            .context("failed to regenerate state file")?;
+        if written {
+            println!("regenerated '{}'", state_file.display());
+        } else {
+            println!("'{}' is already up to date", state_file.display());
+        }
+        Ok(())
+    }
+
+    // Check that the runtime state can be read and written and is consistent
+    pub fn doctor_checks() -> Vec<CheckResult> {
+        let state_dir = paths::state_dir();
+        let mut checks = vec![CheckResult::from_result(
+            "state_dir_writable",
+            ensure_writable_dir(state_dir).map(|()| state_dir.display().to_string()),
+        )];
+
+        let registry = Self::load_registry();
+        let module_file =
+            ModuleFile::from_file(paths::state_file()).context("failed to load module file");
+        checks.push(CheckResult::from_result(
+            "registry_readable",
+            registry
+                .as_ref()
+                .map(|registry| format!("{} modules", registry.modules.len())),
+        ));
+        checks.push(CheckResult::from_result(
+            "state_file_readable",
+            module_file
+                .as_ref()
+                .map(|module_file| format!("{} enabled", module_file.active_modules().len())),
+        ));
+
+        // The remaining checks need both files
+        let (Ok(registry), Ok(module_file)) = (registry, module_file) else {
+            return checks;
+        };
+
+        let unknown: Vec<_> = module_file
+            .active_modules()
+            .iter()
+            .filter(|module| !registry.verify_modules_exist(std::slice::from_ref(module)))
+            .cloned()
+            .collect();
+        checks.push(CheckResult::from_result(
+            "enabled_modules_known",
+            if unknown.is_empty() {
+                Ok("all enabled modules are in the registry".to_string())
+            } else {
+                Err(anyhow!("not in the registry: {}", unknown.join(", ")))
+            },
+        ));
+
+        let uncertain: Vec<_> = registry
+            .modules
+            .iter()
+            .filter(|module| module.state == ModuleState::Uncertain)
+            .map(|module| module.name.clone())
+            .collect();
+        checks.push(CheckResult::from_result(
+            "no_uncertain_modules",
+            if uncertain.is_empty() {
+                Ok("no modules in an uncertain state".to_string())
+            } else {
+                Err(anyhow!(
+                    "uncertain: {} (run 'rebuild' to settle them{})",
+                    uncertain.join(", "),
+                    if Self::rebooted_since_apply() {
+                        "; the system rebooted since, so they run as booted"
+                    } else {
+                        ""
+                    }
+                ))
+            },
+        ));
+
+        checks
+    }
+
+    // Read the state file as last saved, if it exists
+    pub fn saved_content() -> Result<Option<String>> {
+        if !paths::state_file().exists() {
+            return Ok(None);
+        }
+        fs::read_to_string(paths::state_file())
+            .map(Some)
+            .with_context(|| {
+                format!(
+                    "failed to read state file from {}",
+                    paths::state_file().display()
+                )
+            })
+    }
+
+    // Generate the state file content from the current state
+    pub fn generated_content(&self) -> String {
+        self.module_file.generate_content()
     }
 
     // Verify that modules exist in the registry
@@ -244,9 +1349,24 @@ impl ModuleManager {
         self.registry.verify_modules_exist(modules)
     }
 
+    // Activate a system built elsewhere, trusting it to match the state file;
+    // states are only confirmed once the activation succeeds
+    pub fn activate(&mut self) -> Result<()> {
+        println!("activating pre-built system with current modules:");
+        if self.active_modules().is_empty() {
+            println!("  (base system only)");
+        } else {
+            for module in self.active_modules() {
+                println!("  - {module}");
+            }
+        }
+
+        self.apply_changes(true, "pre-built system activated")
+    }
+
     // Rebuild the system with currently enabled modules
     pub fn rebuild(&mut self, force: bool) -> Result<()> {
-        if self.module_file.active_modules.is_empty() && !force {
+        if self.active_modules().is_empty() && self.staged.is_empty() && !force {
             println!("no active modules to rebuild");
             return Ok(());
         }
@@ -254,10 +1374,10 @@ impl ModuleManager {
         println!("rebuilding system with current modules:");
 
         // Display currently enabled modules
-        if self.module_file.active_modules.is_empty() {
+        if self.active_modules().is_empty() {
             println!("  (base system only)");
         } else {
-            for module in &self.module_file.active_modules {
+            for module in self.active_modules() {
                 println!("  - {module}");
             }
         }
@@ -0,0 +1,120 @@
+use crate::env_vars;
+use runtime_modules::canonical_dir;
+use std::env;
+use std::ffi::{CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Constants
+pub const DEFAULT_STATE_DIR: &str = "/run/runtime-modules";
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Resolve the state directory from the flag, then the environment, then the default
+fn resolve_state_dir(dir: Option<&Path>) -> PathBuf {
+    let dir = dir
+        .map(Path::to_path_buf)
+        .or_else(|| env_vars::value_os(env_vars::STATE_DIR).map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR));
+
+    // Anchor relative paths and resolve symlinks now, as applying the configuration
+    // changes directory and atomic saves rename within the directory
+    canonical_dir(&dir)
+}
+
+// Set the state directory for this process; only the first call has any effect
+pub fn init_state_dir(dir: Option<&Path>) {
+    let _ = STATE_DIR.set(resolve_state_dir(dir));
+}
+
+// Directory holding all runtime state
+pub fn state_dir() -> &'static Path {
+    STATE_DIR.get_or_init(|| resolve_state_dir(None))
+}
+
+// Module registry generated by the NixOS module
+pub fn modules_json() -> PathBuf {
+    state_dir().join("modules.json")
+}
+
+// Previous registry kept by 'sync'
+pub fn modules_json_backup() -> PathBuf {
+    state_dir().join("modules.json.bak")
+}
+
+// Enabled modules and their settings
+pub fn state_file() -> PathBuf {
+    state_dir().join("state.json")
+}
+
+// When each module was last enabled
+pub fn enabled_at_file() -> PathBuf {
+    state_dir().join("enabled-at.json")
+}
+
+// Modules enabled by the last activated rebuild, to tell staged changes apart
+pub fn applied_file() -> PathBuf {
+    state_dir().join("applied.json")
+}
+
+// Boot ID at the last successful apply, to notice reboots since
+pub fn applied_boot_file() -> PathBuf {
+    state_dir().join("applied-boot-id")
+}
+
+// Log of applied operations, one JSON record per line
+pub fn history_file() -> PathBuf {
+    state_dir().join("history.jsonl")
+}
+
+// Optional allowlist of modules that may be enabled
+pub fn policy_file() -> PathBuf {
+    state_dir().join("policy.json")
+}
+
+// Pending confirmation for 'enable --safe'
+pub fn pending_file() -> PathBuf {
+    state_dir().join("pending.json")
+}
+
+// Output of the last 'nix flake update'
+pub fn flake_update_log() -> PathBuf {
+    state_dir().join("flake-update.log")
+}
+
+// Output of the last nixos-rebuild or activation
+pub fn rebuild_log() -> PathBuf {
+    state_dir().join("rebuild.log")
+}
+
+// Optional side file with module descriptions
+pub fn descriptions_file() -> PathBuf {
+    state_dir().join("descriptions.json")
+}
+
+// Module order shown by the last 'list', kept in the invoking user's cache so it
+// is still found when re-executed through sudo
+pub fn list_cache() -> Option<PathBuf> {
+    let home = match env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()) {
+        Some(uid) => home_of(uid)?,
+        None => PathBuf::from(env::var_os("HOME")?),
+    };
+    Some(home.join(".cache/runtime-modules/last-list.json"))
+}
+
+// Look up a user's home directory
+fn home_of(uid: libc::uid_t) -> Option<PathBuf> {
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return None;
+    }
+
+    let dir = unsafe { CStr::from_ptr((*passwd).pw_dir) };
+    Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+}
+
+// Advisory lock over the runtime state
+pub fn lock_file() -> PathBuf {
+    state_dir().join("lock")
+}
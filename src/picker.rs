@@ -0,0 +1,179 @@
+use anyhow::{Context, Result, anyhow};
+use runtime_modules::{ModuleStatus, fuzzy_score};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+// Terminal switched to unbuffered input without echo, restored when dropped
+struct RawTerminal {
+    tty: File,
+    saved: libc::termios,
+}
+
+impl RawTerminal {
+    fn open() -> Result<Self> {
+        let tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .context("failed to open the terminal")?;
+
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), &raw mut saved) } != 0 {
+            return Err(anyhow!("failed to read terminal attributes"));
+        }
+
+        // Handle Ctrl-C ourselves so the terminal is always restored
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &raw const raw) } != 0 {
+            return Err(anyhow!("failed to set terminal attributes"));
+        }
+
+        let mut terminal = Self { tty, saved };
+        // Draw on the alternate screen with the cursor hidden
+        terminal.write("\x1b[?1049h\x1b[?25l")?;
+        Ok(terminal)
+    }
+
+    // Number of rows of the terminal, with a fallback if it can't be queried
+    fn rows(&self) -> usize {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        let queried =
+            unsafe { libc::ioctl(self.tty.as_raw_fd(), libc::TIOCGWINSZ, &raw mut size) } == 0;
+        if queried && size.ws_row > 0 {
+            usize::from(size.ws_row)
+        } else {
+            24
+        }
+    }
+
+    fn write(&mut self, content: &str) -> Result<()> {
+        self.tty
+            .write_all(content.as_bytes())
+            .and_then(|()| self.tty.flush())
+            .context("failed to write to the terminal")
+    }
+
+    // Read the bytes of the next key press
+    fn read_key(&mut self) -> Result<Vec<u8>> {
+        let mut buf = [0u8; 16];
+        let len = self
+            .tty
+            .read(&mut buf)
+            .context("failed to read from the terminal")?;
+        Ok(buf[..len].to_vec())
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = self.write("\x1b[?25h\x1b[?1049l");
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &raw const self.saved) };
+    }
+}
+
+// Fail unless modules can be picked interactively, i.e. stdin is a terminal
+pub fn ensure_interactive() -> Result<()> {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "no modules given; pass module names, or run in a terminal to pick them interactively"
+        ))
+    }
+}
+
+// Modules matching the query, best match first
+fn filter<'a>(items: &'a [ModuleStatus], query: &str) -> Vec<&'a ModuleStatus> {
+    let mut matches: Vec<_> = items
+        .iter()
+        .filter_map(|item| {
+            let text = format!("{} {}", item.name, item.desc);
+            fuzzy_score(query, &text).map(|score| (score, item))
+        })
+        .collect();
+    matches.sort_by_key(|(score, _)| *score);
+    matches.into_iter().map(|(_, item)| item).collect()
+}
+
+// Let the user choose any number of `items` on the terminal, returning their names;
+// nothing is returned if the selection is cancelled
+pub fn pick_modules(title: &str, items: &[ModuleStatus]) -> Result<Vec<String>> {
+    let mut terminal = RawTerminal::open()?;
+    let mut query = String::new();
+    let mut cursor = 0;
+    let mut selected: HashSet<String> = HashSet::new();
+
+    loop {
+        let matches = filter(items, &query);
+        cursor = cursor.min(matches.len().saturating_sub(1));
+
+        // Keep the cursor in view below the three header lines
+        let visible = terminal.rows().saturating_sub(3).max(1);
+        let offset = cursor.saturating_sub(visible - 1);
+        let name_width = items.iter().map(|item| item.name.len()).max().unwrap_or(0);
+
+        let mut screen = format!(
+            "\x1b[H\x1b[2J{title} ({}/{} selected)\n\
+             type to filter, up/down to move, space to toggle, enter to confirm, esc to cancel\n\
+             > {query}\n",
+            selected.len(),
+            items.len()
+        );
+        for (index, item) in matches.iter().enumerate().skip(offset).take(visible) {
+            let pointer = if index == cursor { '>' } else { ' ' };
+            let mark = if selected.contains(&item.name) {
+                'x'
+            } else {
+                ' '
+            };
+            screen.push_str(&format!(
+                "{pointer} [{mark}] {:<name_width$}  {}\n",
+                item.name, item.desc
+            ));
+        }
+        terminal.write(&screen)?;
+
+        match terminal.read_key()?.as_slice() {
+            // Enter takes the module under the cursor if nothing was toggled
+            b"\r" | b"\n" => {
+                if selected.is_empty() {
+                    if let Some(item) = matches.get(cursor) {
+                        selected.insert(item.name.clone());
+                    }
+                }
+                break;
+            }
+            // Esc alone or Ctrl-C
+            b"\x1b" | b"\x03" => return Ok(Vec::new()),
+            b" " => {
+                if let Some(item) = matches.get(cursor) {
+                    if !selected.remove(&item.name) {
+                        selected.insert(item.name.clone());
+                    }
+                }
+            }
+            b"\x1b[A" | b"\x1bOA" | b"\x10" => cursor = cursor.saturating_sub(1),
+            b"\x1b[B" | b"\x1bOB" | b"\x0e" => cursor += 1,
+            b"\x7f" | b"\x08" => {
+                query.pop();
+            }
+            key if key.first().is_some_and(|&b| b >= 0x20 && b != 0x7f) => {
+                query.push_str(&String::from_utf8_lossy(key));
+                cursor = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // Keep the registry order rather than the order of toggling
+    Ok(items
+        .iter()
+        .filter(|item| selected.contains(&item.name))
+        .map(|item| item.name.clone())
+        .collect())
+}
@@ -1,82 +1,917 @@
+use crate::{env_vars, paths};
 use anyhow::{Context, Result, anyhow};
+use runtime_modules::{
+    ApplyOutcome, CapturedRun, CheckResult, RebuildAction, RollbackOptions, activation_outcome,
+    ensure_flake_dir, find_in_path, local_flake_dir, run_captured,
+};
+use serde::Serialize;
 use std::env;
-use std::process::{Command, exit};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio, exit};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, fs};
 
 // Constants
-const SYSTEM_MODULES_DIR: &str = "/run/runtime-modules";
+pub const DEFAULT_ESCALATION: &str = "sudo";
+
+static ESCALATION: OnceLock<String> = OnceLock::new();
+
+// Options controlling how the configuration is applied
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    // Evaluate the runtime configuration before activating it
+    pub check: bool,
+    // Show the flake update output instead of only logging it
+    pub verbose: bool,
+    // Passed to nixos-rebuild as --cores
+    pub cores: Option<u32>,
+    // Passed to nixos-rebuild as --max-jobs
+    pub max_jobs: Option<u32>,
+    // Passed to nixos-rebuild as --build-host, to build on another machine
+    pub build_host: Option<String>,
+    // Passed to nixos-rebuild as --target-host, to activate on another machine
+    pub target_host: Option<String>,
+    // Flake providing the runtime configuration, instead of the state directory
+    pub flake: Option<String>,
+    // Leave the flake's git revision out of the state file
+    pub no_revision: bool,
+    // Skip `nix flake update` entirely
+    pub no_update: bool,
+    // Only run `nix flake update` if the lock file is older than this
+    pub update_if_older: Option<Duration>,
+    // Abort instead of only warning when `nix flake update` fails
+    pub strict_update: bool,
+    // Print why a rebuild does or doesn't happen
+    pub explain: bool,
+    // Kill the rebuild or activation if it runs longer than this
+    pub timeout: Option<Duration>,
+    // Activate this pre-built system instead of updating and building the flake
+    pub toplevel: Option<PathBuf>,
+    // How the configuration is activated
+    pub action: RebuildAction,
+    // Save the state file but leave applying it to a later rebuild
+    pub no_rebuild: bool,
+    // Don't run health checks of newly enabled modules
+    pub skip_health_checks: bool,
+    // Running in an SSH session, where activating may cut off the connection
+    pub remote_session: bool,
+    // Activate over SSH without refusing first
+    pub yes: bool,
+    // Rewrite a state file that was edited by hand
+    pub overwrite: bool,
+    // Enable modules only until the next reboot
+    pub ephemeral: bool,
+}
+
+impl ApplyOptions {
+    // The options a rollback of unconfirmed changes needs to build and activate the
+    // same way
+    pub fn rollback_options(&self) -> RollbackOptions {
+        RollbackOptions {
+            flake: self.flake.clone(),
+            build_host: self.build_host.clone(),
+            target_host: self.target_host.clone(),
+            cores: self.cores,
+            max_jobs: self.max_jobs,
+            no_revision: self.no_revision,
+            no_update: self.no_update,
+            timeout_secs: self.timeout.map(|timeout| timeout.as_secs()),
+        }
+    }
+
+    // Options for rolling back changes applied with `options`
+    pub fn for_rollback(options: RollbackOptions) -> Self {
+        Self {
+            flake: options.flake,
+            build_host: options.build_host,
+            target_host: options.target_host,
+            cores: options.cores,
+            max_jobs: options.max_jobs,
+            no_revision: options.no_revision,
+            no_update: options.no_update,
+            timeout: options.timeout_secs.map(Duration::from_secs),
+            ..Self::default()
+        }
+    }
+
+    // Flake reference holding the runtime configuration
+    fn flake(&self) -> &str {
+        self.flake.as_deref().unwrap_or(".")
+    }
+
+    // Why the flake update is skipped, if it is
+    fn skip_update_reason(&self) -> Option<String> {
+        if self.no_update {
+            return Some("--no-update".to_string());
+        }
+
+        // Update if the lock file's age can't be determined, e.g. for a remote flake
+        let max_age = self.update_if_older?;
+        let flake_dir = match &self.flake {
+            Some(flake) => local_flake_dir(flake)?,
+            None => paths::state_dir().to_path_buf(),
+        };
+        let age = fs::metadata(flake_dir.join("flake.lock"))
+            .and_then(|metadata| metadata.modified())
+            .ok()?
+            .elapsed()
+            .unwrap_or_default();
+
+        (age < max_age).then(|| "flake.lock is newer than --update-if-older".to_string())
+    }
+}
+
+// Error returned when applying stopped before anything was built or activated
+#[derive(Debug)]
+pub struct NothingApplied(pub String);
+
+impl fmt::Display for NothingApplied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NothingApplied {}
+
+// Exit code used when an operation was interrupted by SIGINT, as shells do
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+// Exit code used when modules were enabled but a health check failed
+pub const EXIT_HEALTH_CHECK_FAILED: i32 = 3;
+
+// Error for enabled modules whose health check failed, reported with a distinct exit code
+#[derive(Debug)]
+pub struct HealthCheckFailed(pub Vec<String>);
+
+impl fmt::Display for HealthCheckFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "health check failed for: {}", self.0.join(", "))
+    }
+}
+
+impl std::error::Error for HealthCheckFailed {}
+
+// How long an interrupted child may take to exit before it is killed
+const INTERRUPT_GRACE: Duration = Duration::from_secs(10);
+
+// Set by the SIGINT handler; the pid of the running child, whose process group it
+// forwards to, or 0
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+// Error returned when applying was interrupted by SIGINT
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// Only async-signal-safe operations here: record the interrupt and forward it
+extern "C" fn handle_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    let pid = CHILD_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(-pid, libc::SIGINT) };
+    }
+}
+
+// Catch SIGINT so an interrupted apply still records its state; only applying installs it
+fn install_interrupt_handler() {
+    let handler = handle_sigint as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+// Fail with `Interrupted` if SIGINT was received
+fn check_interrupted() -> Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Err(Interrupted.into())
+    } else {
+        Ok(())
+    }
+}
+
+// Lines of captured output shown in a rebuild error; the full log is in rebuild.log
+const REBUILD_ERROR_LINES: usize = 30;
+
+// Error for a rebuild or activation that failed or timed out, carrying its output
+#[derive(Debug)]
+pub struct RebuildFailed {
+    pub message: String,
+    pub log: String,
+}
+
+impl fmt::Display for RebuildFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        let lines: Vec<&str> = self.log.lines().collect();
+        if !lines.is_empty() {
+            let tail = &lines[lines.len().saturating_sub(REBUILD_ERROR_LINES)..];
+            write!(f, "\nlast output:\n{}", tail.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RebuildFailed {}
+
+// Run a command to completion while showing and capturing its output, forwarding
+// SIGINT and killing it if it outlives the grace period or the timeout; returns the
+// run and whether it timed out
+fn run_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> Result<(CapturedRun, bool)> {
+    let mut interrupted_at = None;
+    let mut timed_out = false;
+    let run = run_captured(command, &CHILD_PID, |elapsed| {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            let since = *interrupted_at.get_or_insert_with(Instant::now);
+            if since.elapsed() > INTERRUPT_GRACE {
+                return true;
+            }
+        }
+        timed_out = timeout.is_some_and(|timeout| elapsed > timeout);
+        timed_out
+    })?;
+    Ok((run, timed_out))
+}
+
+// Run the program activating the configuration, keeping its output in rebuild.log
+// and in the error if it fails or runs past the timeout
+fn run_activation(
+    program: &str,
+    command: &mut Command,
+    options: &ApplyOptions,
+    warnings: &mut Vec<String>,
+) -> Result<ApplyOutcome> {
+    let (run, timed_out) = run_with_timeout(command, options.timeout)
+        .with_context(|| format!("failed to run {program}"))?;
+    if let Err(err) = fs::write(paths::rebuild_log(), &run.log) {
+        warnings.push(format!("failed to write rebuild log: {err}"));
+    }
+    check_interrupted()?;
+
+    if timed_out {
+        let timeout = options.timeout.unwrap_or_default().as_secs();
+        return Err(RebuildFailed {
+            message: format!("{program} was killed after running longer than {timeout}s"),
+            log: run.log,
+        }
+        .into());
+    }
+    match activation_outcome(program, run.status.code(), &run.log) {
+        Ok(ApplyOutcome::Applied) => {
+            println!("configuration applied successfully");
+            Ok(ApplyOutcome::Applied)
+        }
+        Ok(ApplyOutcome::AppliedWithWarnings) => {
+            warnings.push(
+                "configuration applied with warnings (some units failed to start)".to_string(),
+            );
+            Ok(ApplyOutcome::AppliedWithWarnings)
+        }
+        Err(err) => Err(RebuildFailed {
+            message: err.to_string(),
+            log: run.log,
+        }
+        .into()),
+    }
+}
+
+// Settings and paths in effect after flags, environment variables and defaults are
+// applied, for debugging which of the overrides took hold
+#[derive(Debug, Serialize)]
+pub struct ResolvedConfig {
+    // Installable passed to nixos-rebuild
+    pub flake: String,
+    // Base flake input of the generated flake, if it can be read
+    pub base_flake: Option<String>,
+    pub action: String,
+    pub escalation: String,
+    pub state_dir: PathBuf,
+    pub modules_json: PathBuf,
+    pub state_file: PathBuf,
+    pub enabled_at_file: PathBuf,
+    pub history_file: PathBuf,
+    pub applied_boot_file: PathBuf,
+    pub rebuild_log: PathBuf,
+    pub policy_file: PathBuf,
+    pub pending_file: PathBuf,
+    pub lock_file: PathBuf,
+}
+
+impl ResolvedConfig {
+    // Resolve everything the options and the state directory point at
+    pub fn resolve(options: &ApplyOptions) -> Self {
+        let flake = match &options.flake {
+            Some(flake) => flake.clone(),
+            None => paths::state_dir().display().to_string(),
+        };
+        Self {
+            flake: format!("{flake}#runtime"),
+            base_flake: base_flake_ref().ok(),
+            action: options.action.to_string(),
+            escalation: escalation().to_string(),
+            state_dir: paths::state_dir().to_path_buf(),
+            modules_json: paths::modules_json(),
+            state_file: paths::state_file(),
+            enabled_at_file: paths::enabled_at_file(),
+            history_file: paths::history_file(),
+            applied_boot_file: paths::applied_boot_file(),
+            rebuild_log: paths::rebuild_log(),
+            policy_file: paths::policy_file(),
+            pending_file: paths::pending_file(),
+            lock_file: paths::lock_file(),
+        }
+    }
+
+    // Print one aligned line per setting
+    pub fn print(&self) {
+        let paths = [
+            ("state dir", &self.state_dir),
+            ("registry", &self.modules_json),
+            ("state file", &self.state_file),
+            ("enabled at", &self.enabled_at_file),
+            ("history", &self.history_file),
+            ("boot ID", &self.applied_boot_file),
+            ("rebuild log", &self.rebuild_log),
+            ("policy", &self.policy_file),
+            ("pending", &self.pending_file),
+            ("lock", &self.lock_file),
+        ];
+        println!("  {:<13}{}", "flake:", self.flake);
+        println!(
+            "  {:<13}{}",
+            "base flake:",
+            self.base_flake.as_deref().unwrap_or("(unknown)")
+        );
+        println!("  {:<13}{}", "action:", self.action);
+        println!("  {:<13}{}", "escalation:", self.escalation);
+        for (label, path) in paths {
+            println!("  {:<13}{}", format!("{label}:"), path.display());
+        }
+    }
+}
+
+// Set the program used to gain elevated privileges, from the flag, then the
+// environment, then the default; only the first call has any effect
+pub fn init_escalation(program: Option<&str>) {
+    let program = program
+        .map(ToString::to_string)
+        .or_else(|| env_vars::value(env_vars::SUDO))
+        .unwrap_or_else(|| DEFAULT_ESCALATION.to_string());
+    let _ = ESCALATION.set(program);
+}
+
+// Program used to gain elevated privileges
+fn escalation() -> &'static str {
+    ESCALATION.get_or_init(|| DEFAULT_ESCALATION.to_string())
+}
+
+// Locate a program in this process's PATH
+fn find_program(program: &str) -> Option<PathBuf> {
+    find_in_path(program, env::var_os("PATH").as_deref())
+}
+
+// Fail before running anything if a program needed to apply the configuration is
+// missing, naming it rather than reporting a bare "No such file or directory"
+fn require_programs() -> Result<()> {
+    for program in ["nix", "nixos-rebuild"] {
+        if find_program(program).is_none() {
+            return Err(NothingApplied(format!(
+                "'{program}' was not found in PATH; Nix must be installed and available to \
+                 apply modules, nothing was applied"
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// Whether this process runs in an SSH session, directly or forwarded through escalation
+pub fn in_ssh_session(forwarded: bool) -> bool {
+    forwarded || env::var_os("SSH_CONNECTION").is_some()
+}
+
+// Activating over SSH can lock the user out if a module changes networking, so ask
+// first on a terminal and warn otherwise; booting into the configuration later is
+// the safer route
+fn check_remote_activation(options: &ApplyOptions, warnings: &mut Vec<String>) -> Result<()> {
+    // With --target-host the activation always happens over SSH, whatever the local session
+    let remote = options.target_host.is_some() || options.remote_session;
+    if !remote || !options.action.activates_now() || options.yes {
+        return Ok(());
+    }
+
+    let risk = format!(
+        "activating with '{}' over SSH, where a module changing networking could \
+         cut off the session",
+        options.action
+    );
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+        warnings.push(format!(
+            "{risk}; pass --action boot to activate on the next reboot instead"
+        ));
+        return Ok(());
+    }
+
+    eprint!("{risk}; go ahead? [y/N] ");
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("failed to read the answer")?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Ok(());
+    }
+    Err(NothingApplied(
+        "activation over SSH not confirmed, nothing was applied; pass --yes to skip \
+         the question, or --action boot to activate on the next reboot"
+            .to_string(),
+    )
+    .into())
+}
+
+// Whether a path lives on tmpfs, which a reboot empties
+fn is_tmpfs(path: &Path) -> bool {
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let found = unsafe { libc::statfs(path.as_ptr(), &raw mut stat) } == 0;
+    found && stat.f_type == libc::TMPFS_MAGIC
+}
+
+// Booting into the configuration leaves module states to be confirmed after the
+// reboot, so refuse if the reboot would wipe the state that tracks them
+fn check_boot_state_dir(options: &ApplyOptions) -> Result<()> {
+    let state_dir = paths::state_dir();
+    if options.action != RebuildAction::Boot || !is_tmpfs(state_dir) {
+        return Ok(());
+    }
+    Err(NothingApplied(format!(
+        "refusing --action boot with the state directory {} on tmpfs: the reboot \
+         would wipe the state of the booted modules; use --state-dir on persistent \
+         storage, or --action switch, nothing was applied",
+        state_dir.display()
+    ))
+    .into())
+}
 
 // Ensure we have sudo access when needed
-pub fn require_sudo(action: &str, args: &[String], force: bool) -> Result<()> {
+pub fn require_sudo() -> Result<()> {
     if unsafe { libc::geteuid() } != 0 {
         println!("info: elevated privileges are required for this action");
 
         let program = env::current_exe().context("failed to get current executable path")?;
-        let program_path = program.to_str().unwrap_or("runtime-module");
+        let escalation = escalation();
+        let escalation_path = find_program(escalation).ok_or_else(|| {
+            anyhow!("privilege escalation program '{escalation}' not found in PATH")
+        })?;
 
-        // Prepare arguments for sudo command
-        let mut sudo_args = vec![program_path];
+        // Re-run the current program with the original arguments, so global
+        // flags and command options are forwarded unchanged
+        // The state directory is passed explicitly, as sudo doesn't keep the environment,
+        // and so are the SSH session and the settings from the environment
+        let mut command = Command::new(escalation_path);
+        command
+            .arg(program)
+            .arg("--state-dir")
+            .arg(paths::state_dir());
+        if in_ssh_session(false) {
+            command.arg("--ssh-session");
+        }
+        forward_env(&mut command, "--action", env_vars::ACTION);
+        forward_env(&mut command, "--file-mode", env_vars::FILE_MODE);
+        forward_env(&mut command, "--file-group", env_vars::FILE_GROUP);
+        let status = command
+            .args(env::args_os().skip(1))
+            .status()
+            .with_context(|| format!("failed to execute {escalation}"))?;
+
+        exit(status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
+
+// Pass a variable from the environment on as its flag, unless the flag was given
+fn forward_env(command: &mut Command, flag: &str, var: &str) {
+    let given = env::args().any(|arg| {
+        arg == flag
+            || arg
+                .strip_prefix(flag)
+                .is_some_and(|rest| rest.starts_with('='))
+    });
+    if let Some(value) = env_vars::value(var).filter(|_| !given) {
+        command.arg(flag).arg(value);
+    }
+}
+
+/// Look up a group ID by group name, or take a numeric ID as-is
+///
+/// # Errors
+///
+/// Returns an error if no group has the name.
+pub fn resolve_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name =
+        std::ffi::CString::new(group).map_err(|_| anyhow!("invalid group name '{group}'"))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(anyhow!("group '{group}' not found"));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+// ID of the current boot, which changes on every reboot
+pub fn boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+}
+
+// Look up the machine's hostname
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).into_owned();
+    (!name.is_empty()).then_some(name)
+}
+
+// Start a detached watcher that rolls back unconfirmed changes
+pub fn spawn_confirmation_watcher() -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let program = env::current_exe().context("failed to get current executable path")?;
+
+    // Run in a new session so closing the terminal doesn't stop the watcher
+    let mut command = Command::new(program);
+    command
+        .arg("--state-dir")
+        .arg(paths::state_dir())
+        .arg("confirm-watch")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+
+    command
+        .spawn()
+        .context("failed to start confirmation watcher")?;
+    Ok(())
+}
+
+// Read the base flake reference from the generated runtime flake
+fn base_flake_ref() -> Result<String> {
+    let flake_path = paths::state_dir().join("flake.nix");
+    let content = fs::read_to_string(&flake_path)
+        .with_context(|| format!("failed to read {}", flake_path.display()))?;
+
+    content
+        .lines()
+        .find_map(|line| {
+            let value = line.trim().strip_prefix("inputs.base.url")?;
+            let value = value.trim_start().strip_prefix('=')?.trim();
+            Some(value.trim_end_matches(';').trim_matches('"').to_string())
+        })
+        .ok_or_else(|| anyhow!("no base flake input found in {}", flake_path.display()))
+}
+
+// Flake reference that will be built: the one given with --flake, otherwise the
+// base flake of the runtime flake
+fn built_flake_ref(options: &ApplyOptions) -> Result<String> {
+    match &options.flake {
+        Some(flake) => Ok(flake.clone()),
+        None => base_flake_ref(),
+    }
+}
+
+// Short git revision of the flake that will be built, if it is a local git repository
+pub fn flake_revision(options: &ApplyOptions) -> Option<String> {
+    let flake_dir = local_flake_dir(&built_flake_ref(options).ok()?)?;
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(&flake_dir)
+        .args(["rev-parse", "--short", "HEAD"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    let revision = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !revision.is_empty()).then_some(revision)
+}
+
+// Fail if the git working tree of the flake that will be built has uncommitted changes
+pub fn require_clean_flake(options: &ApplyOptions) -> Result<()> {
+    let flake_ref = built_flake_ref(options)?;
+    let flake_dir = local_flake_dir(&flake_ref).ok_or_else(|| {
+        anyhow!("cannot check for uncommitted changes in non-local flake '{flake_ref}'")
+    })?;
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(&flake_dir)
+        .args(["status", "--porcelain"])
+        .output()
+        .context("failed to run git status")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "failed to check {} for uncommitted changes:\n{}",
+            flake_dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+
+    let changes = String::from_utf8_lossy(&output.stdout);
+    if !changes.trim().is_empty() {
+        return Err(anyhow!(
+            "flake at {} has uncommitted changes:\n{}",
+            flake_dir.display(),
+            changes.trim_end()
+        ));
+    }
+
+    Ok(())
+}
+
+// Check that nix can be run
+pub fn check_nix_available() -> CheckResult {
+    let result = Command::new("nix")
+        .arg("--version")
+        .output()
+        .context("failed to run nix")
+        .and_then(|output| {
+            if output.status.success() {
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            } else {
+                Err(anyhow!("nix --version exited with {}", output.status))
+            }
+        });
+    CheckResult::from_result("nix_available", result)
+}
+
+// Check that the flake to build is available
+pub fn check_runtime_flake(options: &ApplyOptions) -> CheckResult {
+    let result = match &options.flake {
+        Some(flake) => Ok(format!("using --flake {flake}")),
+        None => ensure_flake_dir(paths::state_dir())
+            .map(|()| format!("found {}", paths::state_dir().join("flake.nix").display())),
+    };
+    CheckResult::from_result("runtime_flake_present", result)
+}
+
+// Evaluate the runtime configuration without activating it
+fn check_configuration(flake: &str) -> Result<()> {
+    println!("checking configuration...");
 
-        // Add force flag if requested
-        if force {
-            sudo_args.push("--force");
+    let toplevel = format!("{flake}#nixosConfigurations.runtime.config.system.build.toplevel");
+    let output = Command::new("nix")
+        .args([
+            "build",
+            "--dry-run",
+            "--accept-flake-config",
+            "--impure",
+            &toplevel,
+        ])
+        .output()
+        .context("failed to run nix build --dry-run")?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(NothingApplied(format!(
+            "configuration check failed, nothing was applied:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))
+        .into())
+    }
+}
+
+// Evaluate a flake output to JSON
+pub fn eval_flake_json(installable: &str) -> Result<String> {
+    println!("evaluating {installable}...");
+
+    let output = Command::new("nix")
+        .args(["eval", "--json", "--accept-flake-config", installable])
+        .output()
+        .context("failed to run nix eval")?;
+
+    if output.status.success() {
+        String::from_utf8(output.stdout).context("nix eval produced invalid UTF-8")
+    } else {
+        Err(anyhow!(
+            "failed to evaluate {installable}:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))
+    }
+}
+
+// Run `nix flake update`, continuing with a warning if it fails unless the update is strict
+fn update_flake(options: &ApplyOptions, warnings: &mut Vec<String>) -> Result<()> {
+    println!("updating flake...");
+    let mut update = Command::new("nix");
+    update.args(["flake", "update", "--accept-flake-config", "--impure"]);
+    if let Some(flake) = &options.flake {
+        update.args(["--flake", flake]);
+    }
+    let (update_success, log) = if options.verbose {
+        let status = update.status().context("failed to run nix flake update")?;
+        (status.success(), None)
+    } else {
+        // Keep the noisy output off the terminal, but log it for later diagnosis
+        let output = update.output().context("failed to run nix flake update")?;
+        let mut log = output.stdout;
+        log.extend_from_slice(&output.stderr);
+        if let Err(err) = fs::write(paths::flake_update_log(), &log) {
+            warnings.push(format!("failed to write flake update log: {err}"));
         }
+        let log = String::from_utf8_lossy(&log).trim_end().to_string();
+        (output.status.success(), Some(log))
+    };
 
-        sudo_args.push(action);
-        sudo_args.extend(args.iter().map(String::as_str));
+    check_interrupted()?;
+    if update_success {
+        return Ok(());
+    }
 
-        // Execute sudo with the current program
-        let status = Command::new("sudo")
-            .args(&sudo_args)
-            .status()
-            .context("failed to execute sudo command")?;
+    // Don't build against inputs that failed to update
+    if options.strict_update {
+        let mut message = "flake update failed, nothing was applied".to_string();
+        if let Some(log) = log {
+            message.push_str(&format!(":\n{log}"));
+        }
+        return Err(NothingApplied(message).into());
+    }
 
-        exit(status.code().unwrap_or(1));
+    // We continue despite warnings from flake update
+    if let Some(log) = log {
+        eprintln!("{log}");
     }
+    warnings.push("flake update returned non-zero status".to_string());
 
     Ok(())
 }
 
+// Profile holding the system generations the boot loader lists
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+// Add a pre-built system to the system profile as its newest generation
+fn set_system_profile(toplevel: &Path) -> Result<()> {
+    let status = Command::new("nix-env")
+        .args(["-p", SYSTEM_PROFILE, "--set"])
+        .arg(toplevel)
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(NothingApplied(format!(
+            "failed to set {SYSTEM_PROFILE} to {} ({status}), nothing was applied",
+            toplevel.display()
+        ))
+        .into()),
+        Err(e) => Err(NothingApplied(format!(
+            "failed to run nix-env to set {SYSTEM_PROFILE}: {e}, nothing was applied"
+        ))
+        .into()),
+    }
+}
+
 // Apply the current configuration
-pub fn apply_configuration() -> Result<()> {
+pub fn apply_configuration(
+    options: &ApplyOptions,
+    warnings: &mut Vec<String>,
+) -> Result<ApplyOutcome> {
+    check_boot_state_dir(options)?;
+    check_remote_activation(options, warnings)?;
+    if options.verbose {
+        println!("resolved configuration:");
+        ResolvedConfig::resolve(options).print();
+    }
     println!("applying configuration...");
+    install_interrupt_handler();
+
+    // A pre-built system only needs activating, after making it the current system
+    // generation for the boot loader entry, as nixos-rebuild does for switch and boot
+    if let Some(toplevel) = &options.toplevel {
+        if options.action != RebuildAction::Test {
+            set_system_profile(toplevel)?;
+        }
+        let switch = toplevel.join("bin/switch-to-configuration");
+        return run_activation(
+            "switch-to-configuration",
+            Command::new(&switch).arg(options.action.to_string()),
+            options,
+            warnings,
+        );
+    }
+
+    // Check the tools and the runtime flake are there before running any nix command
+    require_programs()?;
+    let state_dir = paths::state_dir();
+    if options.flake.is_none() {
+        ensure_flake_dir(state_dir)?;
+    }
 
     // Change to the system modules directory
-    env::set_current_dir(SYSTEM_MODULES_DIR).with_context(|| {
-        format!("failed to change to system modules directory: {SYSTEM_MODULES_DIR}")
+    env::set_current_dir(state_dir).with_context(|| {
+        format!(
+            "failed to change to system modules directory: {}",
+            state_dir.display()
+        )
     })?;
 
-    // Update flake before rebuild
-    println!("updating flake...");
-    let update_status = Command::new("nix")
-        .args(["flake", "update", "--accept-flake-config", "--impure"])
-        .status()
-        .context("failed to run nix flake update")?;
+    // Update flake before rebuild, unless skipped
+    if let Some(reason) = options.skip_update_reason() {
+        println!("skipping flake update ({reason})");
+    } else {
+        update_flake(options, warnings)?;
+    }
 
-    if !update_status.success() {
-        eprintln!("warning: flake update returned non-zero status");
-        // We continue despite warnings from flake update
+    // Optionally evaluate the configuration before touching the running system
+    if options.check {
+        let checked = check_configuration(options.flake());
+        check_interrupted()?;
+        checked?;
     }
 
     // Run nixos-rebuild
-    let rebuild_args = [
-        "test",
-        "--accept-flake-config",
-        "--impure",
-        "--flake",
-        ".#runtime",
+    let mut rebuild_args = vec![
+        options.action.to_string(),
+        "--accept-flake-config".to_string(),
+        "--impure".to_string(),
+        "--flake".to_string(),
+        format!("{}#runtime", options.flake()),
     ];
 
-    let rebuild_status = Command::new("nixos-rebuild")
-        .args(rebuild_args)
-        .status()
-        .context("failed to run nixos-rebuild")?;
+    // Tune build parallelism if requested
+    if let Some(cores) = options.cores {
+        rebuild_args.extend(["--cores".to_string(), cores.to_string()]);
+    }
+    if let Some(max_jobs) = options.max_jobs {
+        rebuild_args.extend(["--max-jobs".to_string(), max_jobs.to_string()]);
+    }
 
-    if rebuild_status.success() {
-        println!("configuration applied successfully");
-        Ok(())
-    } else {
-        Err(anyhow!(
-            "warning: configuration applied with warnings (some changes may not be fully applied)"
-        ))
+    // Offload the build or activation to other machines if requested
+    if let Some(build_host) = &options.build_host {
+        rebuild_args.extend(["--build-host".to_string(), build_host.clone()]);
+    }
+    if let Some(target_host) = &options.target_host {
+        rebuild_args.extend(["--target-host".to_string(), target_host.clone()]);
+    }
+
+    run_activation(
+        "nixos-rebuild",
+        Command::new("nixos-rebuild").args(&rebuild_args),
+        options,
+        warnings,
+    )
+}
+
+// Run a module's health check through the shell, with its combined output as the detail
+pub fn run_health_check(module: &str, command: &str) -> CheckResult {
+    let output = match Command::new("sh").args(["-c", command]).output() {
+        Ok(output) => output,
+        Err(e) => {
+            return CheckResult {
+                check: module.to_string(),
+                ok: false,
+                detail: format!("failed to run '{command}': {e}"),
+            };
+        }
+    };
+    let mut detail = String::from_utf8_lossy(&output.stdout).into_owned();
+    detail.push_str(&String::from_utf8_lossy(&output.stderr));
+    let detail = detail.trim_end().to_string();
+
+    CheckResult {
+        check: module.to_string(),
+        ok: output.status.success(),
+        detail: if output.status.success() || !detail.is_empty() {
+            detail
+        } else {
+            format!("'{command}' failed with {}", output.status)
+        },
     }
 }
@@ -1,8 +1,20 @@
 #[cfg(test)]
 mod tests {
     use anyhow::{Result, anyhow};
-    use runtime_modules::{Module, ModuleFile, ModuleRegistry, ModuleState};
+    use runtime_modules::{
+        ApplyOutcome, CheckResult, ColorChoice, DEFAULT_FILE_MODE, EnablePolicy, HistoryEntry,
+        Module, ModuleFile, ModuleOrigin, ModuleRegistry, ModuleState, ModuleStatus,
+        PendingConfirmation, RebuildAction, RebuildDecision, RebuildReason, ResetKeep,
+        RollbackOptions, StateChanges, StateFile, activation_outcome, append_line, canonical_dir,
+        check_system_toplevel, dot_id, ensure_flake_dir, ensure_writable_dir, filter_history,
+        find_in_path, format_timestamp, fuzzy_score, local_flake_dir, parse_duration,
+        parse_file_mode, parse_setting, registry_schema, relative_import, render_metrics,
+        render_table, resolve_flake_ref, resolve_module_indices, resolve_module_prefixes,
+        run_captured, status_check_passes, to_json, validate_module_name, write_atomic,
+    };
+    use std::collections::BTreeMap;
     use std::io::{self, Write};
+    use std::path::Path;
     use tempfile::NamedTempFile;
 
     // Helper to create a test registry
@@ -13,18 +25,21 @@ mod tests {
                 path: "/path/to/test1".to_string(),
                 desc: String::new(),
                 state: ModuleState::Disabled,
+                ..Default::default()
             },
             Module {
                 name: "test2".to_string(),
                 path: "/path/to/test2".to_string(),
                 desc: String::new(),
                 state: ModuleState::Disabled,
+                ..Default::default()
             },
             Module {
                 name: "test3".to_string(),
                 path: "/path/to/test3".to_string(),
                 desc: String::new(),
                 state: ModuleState::Disabled,
+                ..Default::default()
             },
         ];
 
@@ -54,12 +69,14 @@ mod tests {
                 path: "/path/to/test1".to_string(),
                 desc: String::new(),
                 state: ModuleState::Disabled,
+                ..Default::default()
             },
             Module {
                 name: "test2".to_string(),
                 path: "/path/to/test2".to_string(),
                 desc: String::new(),
                 state: ModuleState::Disabled,
+                ..Default::default()
             },
         ];
 
@@ -106,6 +123,38 @@ mod tests {
         Ok(())
     }
 
+    // Test loading a large generated registry
+    #[test]
+    fn test_registry_from_large_file() -> Result<()> {
+        let modules: Vec<_> = (0..5000)
+            .map(|i| {
+                serde_json::json!({
+                    "name": format!("module-{i}"),
+                    "path": format!("/nix/store/module-{i}"),
+                    "desc": "x".repeat(200),
+                    "state": if i % 2 == 0 { "Enabled" } else { "Disabled" },
+                    "tags": ["generated"]
+                })
+            })
+            .collect();
+        let mut temp_file = NamedTempFile::new()?;
+        write!(temp_file, "{}", serde_json::json!({ "modules": modules }))?;
+
+        let registry = ModuleRegistry::from_file(temp_file.path())?;
+
+        assert_eq!(registry.modules.len(), 5000);
+        assert_eq!(registry.modules[4999].path, "/nix/store/module-4999");
+        assert_eq!(registry.get_state("module-4998"), ModuleState::Enabled);
+        assert_eq!(registry.get_lookup_map().map(|map| map.len()), Some(5000));
+        assert_eq!(registry.enabled_modules().len(), 2500);
+
+        // Trailing garbage is still rejected
+        write!(temp_file, "garbage")?;
+        assert!(ModuleRegistry::from_file(temp_file.path()).is_err());
+
+        Ok(())
+    }
+
     // Test loading ModuleFile from file
     #[test]
     fn test_module_file_from_file() -> Result<()> {
@@ -158,7 +207,8 @@ mod tests {
         assert!(module_file.is_module_enabled("test1"));
 
         // Disable the same module
-        module_file.disable_modules(&["test1".to_string()]);
+        let removed = module_file.disable_modules(&["test1".to_string()]);
+        assert_eq!(removed, vec!["test1".to_string()]);
         assert!(!module_file.is_module_enabled("test1"));
         assert_eq!(module_file.active_modules.len(), 0);
 
@@ -167,14 +217,22 @@ mod tests {
         assert!(module_file.is_module_enabled("test1"));
         assert!(module_file.is_module_enabled("test2"));
 
-        // Disable in reverse order
-        module_file.disable_modules(&["test2".to_string()]);
+        // Disable in reverse order, ignoring modules that aren't enabled
+        let removed = module_file.disable_modules(&["test2".to_string(), "test3".to_string()]);
+        assert_eq!(removed, vec!["test2".to_string()]);
         assert!(module_file.is_module_enabled("test1"));
         assert!(!module_file.is_module_enabled("test2"));
 
         module_file.disable_modules(&["test1".to_string()]);
         assert!(!module_file.is_module_enabled("test1"));
         assert_eq!(module_file.active_modules.len(), 0);
+
+        // Disabling again removes nothing
+        assert!(
+            module_file
+                .disable_modules(&["test1".to_string()])
+                .is_empty()
+        );
     }
 
     // Test multiple operations sequence
@@ -276,4 +334,1916 @@ mod tests {
         registry.mark_uncertain(&["test1".to_string()]);
         assert_eq!(registry.get_state("test1"), ModuleState::Uncertain);
     }
+
+    // Test a failed reset leaves every previously active module uncertain
+    #[test]
+    fn test_failed_reset_marks_uncertain() {
+        let mut registry = create_test_registry();
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["test1".to_string(), "test2".to_string()]);
+        registry.confirm_states(module_file.active_modules());
+        let previous = ModuleFile::parse_active_modules(&module_file.generate_content());
+
+        // Reset empties the module file, then the rebuild fails
+        module_file.disable_modules(&previous);
+        registry.mark_failed_transition(&previous, module_file.active_modules());
+
+        assert_eq!(registry.get_state("test1"), ModuleState::Uncertain);
+        assert_eq!(registry.get_state("test2"), ModuleState::Uncertain);
+        assert_eq!(registry.get_state("test3"), ModuleState::Disabled);
+    }
+
+    // Test splitting a desired state into added, removed, unchanged and skipped modules
+    #[test]
+    fn test_module_file_changes() -> Result<()> {
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&[
+            "test1".to_string(),
+            "test2".to_string(),
+            "test3".to_string(),
+        ]);
+        module_file.set_settings(
+            &["test3".to_string()],
+            &[("key".to_string(), "a".to_string())],
+        );
+
+        let desired: StateFile = serde_json::from_str(
+            r#"{"enabled": ["test1", "test3", "test4"], "settings": {"test3": {"key": "b"}, "test5": {"key": "c"}}}"#,
+        )?;
+        let changes = module_file.changes(&desired);
+        assert_eq!(
+            changes,
+            StateChanges {
+                added: vec!["test4".to_string()],
+                removed: vec!["test2".to_string()],
+                settings_changed: vec!["test3".to_string()],
+                unchanged: vec!["test1".to_string()],
+                skipped: vec!["test5".to_string()],
+            }
+        );
+        assert!(!changes.is_empty());
+
+        // Moving to the current state changes nothing
+        let changes = module_file.changes(&module_file.state());
+        assert!(changes.is_empty());
+        assert_eq!(changes.unchanged, ["test1", "test2", "test3"]);
+        Ok(())
+    }
+
+    // Test that JSON syntax errors point at where the file went wrong
+    #[test]
+    fn test_json_error_location() -> Result<()> {
+        let mut registry_file = NamedTempFile::new()?;
+        write!(
+            registry_file,
+            "{{\n  \"modules\": [\n    {{\"name\": }}\n  ]\n}}\n"
+        )?;
+        let err = ModuleRegistry::from_file(registry_file.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("line 3 column 14"), "{err:#}");
+
+        let mut policy_file = NamedTempFile::new()?;
+        write!(policy_file, "{{\n  \"allow\": [\"ssh\" \"gaming\"]\n}}\n")?;
+        let Err(err) = EnablePolicy::from_file(policy_file.path()) else {
+            return Err(anyhow!("expected a parse error"));
+        };
+        assert!(format!("{err:#}").contains("line 2 column 19"), "{err:#}");
+
+        let mut state_file = NamedTempFile::new()?;
+        write!(
+            state_file,
+            "{{\n  \"enabled\": [\n    \"test1\",,\n  ]\n}}\n"
+        )?;
+        let Err(err) = ModuleFile::from_file(state_file.path()) else {
+            return Err(anyhow!("expected a parse error"));
+        };
+        assert!(format!("{err:#}").contains("line 3"), "{err:#}");
+
+        let err = StateFile::from_file(state_file.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("line 3"), "{err:#}");
+        Ok(())
+    }
+
+    // Test reading a hand-edited JSON5 state file and saving it back as strict JSON
+    #[cfg(feature = "json5")]
+    #[test]
+    fn test_module_file_json5_roundtrip() -> Result<()> {
+        let mut temp_file = NamedTempFile::new()?;
+        write!(
+            temp_file,
+            "{{\n  // kept for the demo\n  enabled: [\"test1\", \"test2\",],\n}}\n"
+        )?;
+
+        let module_file = ModuleFile::from_file(temp_file.path())?;
+        assert_eq!(module_file.active_modules(), ["test1", "test2"]);
+
+        module_file.save(temp_file.path())?;
+        let content = std::fs::read_to_string(temp_file.path())?;
+        assert!(serde_json::from_str::<StateFile>(&content).is_ok());
+        let reloaded = ModuleFile::from_file(temp_file.path())?;
+        assert_eq!(reloaded.active_modules(), module_file.active_modules());
+
+        Ok(())
+    }
+
+    // Test finding and force-confirming states that disagree with the active set
+    #[test]
+    fn test_mismatched_modules() {
+        let mut registry = create_test_registry();
+        let active = vec!["test1".to_string()];
+        registry.set_state("test1", ModuleState::Uncertain);
+        registry.set_state("test3", ModuleState::Enabled);
+
+        assert_eq!(registry.mismatched_modules(&active), ["test1", "test3"]);
+
+        registry.confirm_states(&active);
+        assert!(registry.mismatched_modules(&active).is_empty());
+    }
+
+    // Test telling pending and staged changes apart using the registry states
+    #[test]
+    fn test_pending_changes() {
+        let mut registry = create_test_registry();
+        registry.set_state("test2", ModuleState::Enabled);
+        registry.set_state("test3", ModuleState::Uncertain);
+
+        let active = vec!["test1".to_string(), "test2".to_string()];
+        let diff = registry.pending_changes(&active, None);
+        assert_eq!(diff.enable, ["test1"]);
+        assert_eq!(diff.disable, ["test3"]);
+
+        // Uncertain modules failed a rebuild, so they don't count as staged
+        let staged = registry.staged_changes(&active, None);
+        assert_eq!(staged.enable, ["test1"]);
+        assert!(staged.disable.is_empty());
+
+        registry.confirm_states(&active);
+        assert!(registry.pending_changes(&active, None).is_empty());
+        assert!(registry.staged_changes(&active, None).is_empty());
+    }
+
+    // Test judging pending changes against the modules of the last activated rebuild,
+    // which outlive the registry states that activation resets
+    #[test]
+    fn test_pending_changes_applied() {
+        let mut registry = create_test_registry();
+        registry.set_state("test3", ModuleState::Uncertain);
+
+        let active = vec!["test1".to_string(), "test2".to_string()];
+        let applied = vec!["test1".to_string(), "gone".to_string()];
+        let diff = registry.pending_changes(&active, Some(&applied));
+        assert_eq!(diff.enable, ["test2"]);
+        assert_eq!(diff.disable, ["test3", "gone"]);
+
+        let staged = registry.staged_changes(&active, Some(&applied));
+        assert_eq!(staged.enable, ["test2"]);
+        assert_eq!(staged.disable, ["gone"]);
+
+        assert!(registry.staged_changes(&active, Some(&active)).is_empty());
+    }
+
+    // Test rejecting module names that aren't shell- and Nix-safe
+    #[test]
+    fn test_validate_module_name() {
+        for name in ["gaming", "rt.gaming", "media-production", "gtk+", "vm_2"] {
+            assert!(validate_module_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "",
+            "my module",
+            "it's",
+            "\"quoted\"",
+            "a;b",
+            "$(id)",
+            "`id`",
+            "a|b",
+            "--force",
+            "a\nb",
+        ] {
+            assert!(validate_module_name(name).is_err(), "{name}");
+        }
+    }
+
+    // Test registry validation reporting every problem at once
+    #[test]
+    fn test_registry_validate() {
+        let module = |name: &str, path: &str, deps: &[&str]| Module {
+            name: name.to_string(),
+            path: path.to_string(),
+            deps: deps.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        };
+
+        let registry = ModuleRegistry::new(vec![
+            module("base", "/nix/store/abc-base", &[]),
+            module("gpu", "", &["base"]),
+        ]);
+        assert!(registry.validate().is_empty());
+
+        let mut registry = ModuleRegistry::new(vec![
+            module("a", "/nix/store/abc-a", &["b"]),
+            module("b", "relative/b.nix", &["c"]),
+            module("c", "/etc/../c.nix", &["a", "missing"]),
+            module("a", "", &[]),
+            module("", "", &[]),
+        ]);
+        registry.sets.insert(
+            "media".to_string(),
+            vec!["a".to_string(), "ghost".to_string()],
+        );
+        assert_eq!(
+            registry.validate(),
+            [
+                "duplicate module name: a",
+                "module at index 4 has an empty name",
+                "module b has a path that isn't absolute: relative/b.nix",
+                "module c has a path that isn't absolute: /etc/../c.nix",
+                "module c has unknown dependency missing",
+                "set media has unknown member ghost",
+                "dependency cycle: a -> b -> c -> a",
+            ]
+        );
+    }
+
+    // Test the enable allowlist by name and tag
+    #[test]
+    fn test_enable_policy() -> Result<()> {
+        let mut registry = create_test_registry();
+        registry.modules[1].tags = vec!["media".to_string()];
+        let modules: Vec<String> = ["test1", "test2", "test3", "unknown"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        let policy: EnablePolicy =
+            serde_json::from_str(r#"{"allow": ["test1"], "allow_tags": ["media"]}"#)?;
+        assert_eq!(policy.denied(&registry, &modules), ["test3", "unknown"]);
+        assert!(policy.denied(&registry, &modules[..2]).is_empty());
+
+        // An empty policy allows nothing, a missing one isn't loaded at all
+        assert_eq!(EnablePolicy::default().denied(&registry, &modules).len(), 4);
+        let dir = tempfile::tempdir()?;
+        assert!(EnablePolicy::from_file(dir.path().join("policy.json"))?.is_none());
+        Ok(())
+    }
+
+    // Test the rebuild decision, preferring changes over uncertainty over force
+    #[test]
+    fn test_rebuild_decision() {
+        let mut decision = RebuildDecision::default();
+        assert_eq!(decision.reason(), RebuildReason::NoChanges);
+        assert!(!decision.reason().rebuilds());
+
+        decision.force = true;
+        assert_eq!(decision.reason(), RebuildReason::Forced);
+        decision.uncertain.push("gpu".to_string());
+        assert_eq!(decision.reason(), RebuildReason::Uncertain);
+        decision.changed = true;
+        assert_eq!(decision.reason(), RebuildReason::Changed);
+        assert!(decision.reason().rebuilds());
+    }
+
+    // Test module settings parsing and persistence
+    #[test]
+    fn test_module_settings() -> Result<()> {
+        // Keys must be simple identifiers
+        assert_eq!(
+            parse_setting("vendor=nvidia")?,
+            ("vendor".to_string(), "nvidia".to_string())
+        );
+        assert_eq!(parse_setting("opt_level=")?.1, "");
+        assert!(parse_setting("vendor").is_err());
+        assert!(parse_setting("1vendor=x").is_err());
+        assert!(parse_setting("ven.dor=x").is_err());
+
+        let mut module_file = ModuleFile::empty();
+        let modules = vec!["gpu".to_string()];
+        module_file.enable_modules(&modules);
+
+        // Setting a new value is a change, repeating it is not
+        let settings = vec![parse_setting("vendor=nvidia")?];
+        assert!(module_file.set_settings(&modules, &settings));
+        assert!(!module_file.set_settings(&modules, &settings));
+
+        // Settings survive a save/load roundtrip
+        let temp_file = NamedTempFile::new()?;
+        module_file.save(temp_file.path())?;
+        let loaded = ModuleFile::from_file(temp_file.path())?;
+        assert_eq!(loaded.settings["gpu"]["vendor"], "nvidia");
+
+        // Disabling a module drops its settings
+        module_file.disable_modules(&modules);
+        assert!(module_file.settings.is_empty());
+        assert!(!module_file.generate_content().contains("settings"));
+
+        Ok(())
+    }
+
+    // Test the recorded revision is kept on load and left out when unset
+    #[test]
+    fn test_state_file_revision() -> Result<()> {
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["gpu".to_string()]);
+        assert!(!module_file.generate_content().contains("revision"));
+
+        module_file.set_revision(Some("abc1234".to_string()));
+        let temp_file = NamedTempFile::new()?;
+        module_file.save(temp_file.path())?;
+        let loaded = ModuleFile::from_file(temp_file.path())?;
+        assert_eq!(loaded.state().revision.as_deref(), Some("abc1234"));
+        assert_eq!(loaded.active_modules(), ["gpu".to_string()]);
+
+        // The revision doesn't count as a change to the state
+        assert!(loaded.diff(&module_file.state()).is_empty());
+
+        Ok(())
+    }
+
+    // Test pending confirmation roundtrip and restoring the previous state
+    #[test]
+    fn test_pending_confirmation() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("pending.json");
+
+        // Nothing pending yet
+        assert!(PendingConfirmation::from_file(&path)?.is_none());
+
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["test1".to_string()]);
+        let options = RollbackOptions {
+            flake: Some("/etc/nixos".to_string()),
+            target_host: Some("root@remote".to_string()),
+            cores: Some(4),
+            ..RollbackOptions::default()
+        };
+        let pending = PendingConfirmation {
+            deadline: 42,
+            previous: module_file.state(),
+            options: options.clone(),
+        };
+        pending.save(&path)?;
+
+        // Changes after the snapshot are dropped when restoring it
+        module_file.enable_modules(&["test2".to_string()]);
+        let loaded = PendingConfirmation::from_file(&path)?.expect("pending confirmation");
+        assert_eq!(loaded.deadline, 42);
+        assert_eq!(loaded.options, options);
+        let restored = ModuleFile::from_state(loaded.previous);
+        assert_eq!(restored.active_modules, vec!["test1".to_string()]);
+
+        // Confirmations saved without the options roll back with the defaults
+        std::fs::write(&path, r#"{"deadline": 7, "previous": {"enabled": []}}"#)?;
+        let loaded = PendingConfirmation::from_file(&path)?.expect("pending confirmation");
+        assert_eq!(loaded.options, RollbackOptions::default());
+
+        Ok(())
+    }
+
+    // Test atomic writes replace the file without leaving temporaries behind
+    #[test]
+    fn test_write_atomic() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("output.json");
+
+        write_atomic(&path, "first")?;
+        write_atomic(&path, "second")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "second");
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+
+        // Writing into a missing directory fails
+        assert!(write_atomic(temp_dir.path().join("missing/output.json"), "x").is_err());
+
+        Ok(())
+    }
+
+    // Test resolving a symlinked state directory to the directory it points at
+    #[test]
+    fn test_canonical_dir_symlink() -> Result<()> {
+        let target = tempfile::tempdir()?;
+        let links = tempfile::tempdir()?;
+        let link = links.path().join("runtime-modules");
+        std::os::unix::fs::symlink(target.path(), &link)?;
+
+        let resolved = canonical_dir(&link);
+        assert_eq!(resolved, std::fs::canonicalize(target.path())?);
+
+        // Atomic saves land in the target, with nothing written next to the link
+        write_atomic(resolved.join("state.json"), "{}")?;
+        assert_eq!(std::fs::read_to_string(link.join("state.json"))?, "{}");
+        assert_eq!(std::fs::read_dir(target.path())?.count(), 1);
+        assert_eq!(std::fs::read_dir(links.path())?.count(), 1);
+
+        // A missing directory is only made absolute
+        let missing = links.path().join("missing");
+        assert_eq!(canonical_dir(&missing), missing);
+        Ok(())
+    }
+
+    // Test reading the Nix state file of older versions alongside the JSON one
+    #[test]
+    fn test_module_file_legacy_format() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let legacy_path = temp_dir.path().join("legacy.nix");
+        let current_path = temp_dir.path().join("state.json");
+
+        std::fs::write(
+            &legacy_path,
+            "# generated by runtime-modules\n{\n  imports = [\n    /nix/store/aaa-source/gaming # gaming\n    /nix/store/bbb-source/ # rt.podman\n  ];\n}\n",
+        )?;
+        std::fs::write(&current_path, r#"{"enabled": ["gaming", "rt.podman"]}"#)?;
+
+        let legacy = ModuleFile::from_file(&legacy_path)?;
+        let current = ModuleFile::from_file(&current_path)?;
+        assert_eq!(legacy.active_modules(), ["gaming", "rt.podman"]);
+        assert_eq!(legacy.active_modules(), current.active_modules());
+        assert_eq!(
+            ModuleFile::parse_active_modules(&std::fs::read_to_string(&legacy_path)?),
+            ["gaming", "rt.podman"]
+        );
+
+        // An empty legacy file has no modules, and saving writes JSON
+        std::fs::write(&legacy_path, "{\n  imports = [\n  ];\n}\n")?;
+        let empty = ModuleFile::from_file(&legacy_path)?;
+        assert!(empty.active_modules().is_empty());
+        legacy.save(&legacy_path)?;
+        let saved = StateFile::parse(&std::fs::read_to_string(&legacy_path)?)?;
+        assert_eq!(saved.enabled, ["gaming", "rt.podman"]);
+
+        // Content in neither format is still an error
+        std::fs::write(&legacy_path, "garbage")?;
+        assert!(ModuleFile::from_file(&legacy_path).is_err());
+        Ok(())
+    }
+
+    // Test detecting a state file that was edited by hand after it was saved
+    #[test]
+    fn test_is_hand_edited() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("state.json");
+        assert!(!ModuleFile::is_hand_edited(&path));
+
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["test1".to_string()]);
+        module_file.set_settings(
+            &["test1".to_string()],
+            &[("key".to_string(), "v".to_string())],
+        );
+        module_file.save(&path)?;
+        assert!(!ModuleFile::is_hand_edited(&path));
+
+        // Same modules, but reformatted by hand
+        std::fs::write(
+            &path,
+            r#"{"enabled": ["test1"], "settings": {"test1": {"key": "v"}}}"#,
+        )?;
+        assert!(ModuleFile::is_hand_edited(&path));
+
+        // A field the tool doesn't know would be dropped on save
+        std::fs::write(
+            &path,
+            module_file
+                .generate_content()
+                .replace("\"enabled\"", "\"note\": \"x\",\n  \"enabled\""),
+        )?;
+        assert!(ModuleFile::is_hand_edited(&path));
+
+        // An unparsable file is left to the usual load errors
+        std::fs::write(&path, "not json")?;
+        assert!(!ModuleFile::is_hand_edited(&path));
+        Ok(())
+    }
+
+    // Test marking modules as enabled until reboot
+    #[test]
+    fn test_module_file_ephemeral() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("state.json");
+        let modules = vec!["test1".to_string(), "test2".to_string()];
+
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&modules);
+        assert!(module_file.set_ephemeral(&modules, "boot-a"));
+        assert!(!module_file.set_ephemeral(&modules, "boot-a"));
+        assert!(module_file.is_ephemeral("test1"));
+        assert!(module_file.stale_ephemeral("boot-a").is_empty());
+        assert_eq!(module_file.stale_ephemeral("boot-b"), modules);
+
+        // Enabling for good clears the mark
+        assert!(module_file.clear_ephemeral(&["test2".to_string()]));
+        assert!(!module_file.clear_ephemeral(&["test2".to_string()]));
+        assert_eq!(module_file.ephemeral_modules(), vec!["test1".to_string()]);
+
+        // The marks survive a round trip through the state file
+        module_file.save(&path)?;
+        let loaded = ModuleFile::from_file(&path)?;
+        assert!(loaded.is_ephemeral("test1"));
+        assert!(!loaded.is_ephemeral("test2"));
+
+        // Disabling a module drops its mark
+        module_file.disable_modules(&["test1".to_string()]);
+        assert!(module_file.ephemeral_modules().is_empty());
+        Ok(())
+    }
+
+    // Test detecting a saved state file that drifted from the tracked state
+    #[test]
+    fn test_is_saved_at() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("state.json");
+        let base = ModuleFile::empty();
+
+        // A missing file is the base state
+        assert!(base.is_saved_at(&path));
+
+        // A freshly saved file matches, and saving it again writes nothing
+        assert!(base.save(&path)?);
+        assert!(base.is_saved_at(&path));
+        let modified = std::fs::metadata(&path)?.modified()?;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!base.save(&path)?);
+        assert_eq!(std::fs::metadata(&path)?.modified()?, modified);
+
+        // A hand-edited file parses to the same modules but no longer matches
+        std::fs::write(&path, r#"{"enabled":[],"extra":true}"#)?;
+        let loaded = ModuleFile::from_file(&path)?;
+        assert!(loaded.active_modules.is_empty());
+        assert!(!loaded.is_saved_at(&path));
+
+        // Non-empty state never matches a missing file
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["test1".to_string()]);
+        assert!(!module_file.is_saved_at(temp_dir.path().join("missing.json")));
+
+        Ok(())
+    }
+
+    // Test aligned status line formatting
+    #[test]
+    fn test_status_format_aligned() {
+        let status = |name: &str, state| ModuleStatus {
+            name: name.to_string(),
+            path: String::new(),
+            state,
+            desc: String::new(),
+            enabled_at: None,
+            pending: false,
+            ephemeral: false,
+        };
+
+        let short = status("gpu", ModuleState::Enabled);
+        let long = status("virtualization", ModuleState::Disabled);
+        let width = long.name.len();
+
+        assert_eq!(
+            short.format_aligned(width, false),
+            "gpu:            enabled"
+        );
+        assert_eq!(
+            long.format_aligned(width, false),
+            "virtualization: disabled"
+        );
+        let staged = ModuleStatus {
+            pending: true,
+            ephemeral: false,
+            ..status("gpu", ModuleState::Enabled)
+        };
+        assert_eq!(
+            staged.format_aligned(width, false),
+            "gpu:            enabled (pending rebuild)"
+        );
+        assert_eq!(
+            status("wine", ModuleState::Uncertain).format_aligned(width, true),
+            "wine:           \u{001b}[33muncertain\u{001b}[0m"
+        );
+    }
+
+    // Test merging descriptions from a side file
+    #[test]
+    fn test_merge_descriptions() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let desc_path = temp_dir.path().join("descriptions.json");
+        let registry_path = temp_dir.path().join("modules.json");
+
+        let mut registry = create_test_registry();
+        registry.modules[0].desc = "From registry".to_string();
+
+        // Missing file merges nothing
+        assert_eq!(registry.merge_descriptions_file(&desc_path)?, 0);
+
+        std::fs::write(
+            &desc_path,
+            r#"{"test1": "From file", "test2": "Second", "unknown": "Ignored"}"#,
+        )?;
+        assert_eq!(registry.merge_descriptions_file(&desc_path)?, 1);
+
+        // Registry descriptions take precedence over the side file
+        assert_eq!(registry.modules[0].desc, "From registry");
+        assert_eq!(registry.modules[1].desc, "Second");
+        assert_eq!(registry.modules[2].desc, "");
+
+        // Merged descriptions are not written back to the registry
+        registry.save(&registry_path)?;
+        let saved = ModuleRegistry::from_file(&registry_path)?;
+        assert_eq!(saved.modules[0].desc, "From registry");
+        assert_eq!(saved.modules[1].desc, "");
+
+        Ok(())
+    }
+
+    // Test dependency graph export
+    #[test]
+    fn test_dependency_graph() {
+        let mut registry = create_test_registry();
+        registry.modules[0].deps = vec!["test2".to_string()];
+        registry.modules[0].conflicts = vec!["test3".to_string()];
+
+        let graph = registry.dependency_graph();
+        assert_eq!(graph.nodes, vec!["test1", "test2", "test3"]);
+        assert_eq!(graph.deps["test1"], vec!["test2".to_string()]);
+        assert_eq!(graph.conflicts["test1"], vec!["test3".to_string()]);
+        assert!(!graph.deps.contains_key("test2"));
+
+        let dot = registry.to_dot();
+        assert!(dot.starts_with("digraph runtime_modules {"));
+        assert!(dot.contains("  \"test1\" -> \"test2\";"));
+        assert!(dot.contains("  \"test1\" -> \"test3\" [style=dashed"));
+    }
+
+    // Test quoting module names as DOT IDs
+    #[test]
+    fn test_dot_id() {
+        assert_eq!(dot_id("media"), "\"media\"");
+        assert_eq!(dot_id("say \"hi\""), r#""say \"hi\"""#);
+        assert_eq!(dot_id(r"back\slash"), r#""back\\slash""#);
+        assert_eq!(dot_id("two\nlines"), r#""two\nlines""#);
+        assert_eq!(dot_id("ünïcode"), "\"ünïcode\"");
+    }
+
+    // Test resolving local flake directories
+    #[test]
+    fn test_local_flake_dir() {
+        let dir = |flake_ref| local_flake_dir(flake_ref);
+
+        assert_eq!(dir("path:/etc/nixos"), Some("/etc/nixos".into()));
+        assert_eq!(
+            dir("git+file:///etc/nixos?ref=main"),
+            Some("/etc/nixos".into())
+        );
+        assert_eq!(dir("/etc/nixos#host"), Some("/etc/nixos".into()));
+        assert_eq!(dir("github:owner/repo"), None);
+        assert_eq!(dir("path:"), None);
+    }
+
+    // Test rendering imports relative to the flake, only under a base inside it
+    #[test]
+    fn test_relative_import() -> Result<()> {
+        let flake_dir = Path::new("/run/runtime-modules");
+        let base = Path::new("/run/runtime-modules/modules");
+        let import = |path: &str| relative_import(Path::new(path), base, flake_dir);
+
+        assert_eq!(
+            import("/run/runtime-modules/modules/foo.nix")?.as_deref(),
+            Some("./modules/foo.nix")
+        );
+        // The prefix has to match whole components
+        assert_eq!(import("/run/runtime-modules/modules-old/foo.nix")?, None);
+        assert_eq!(import("/etc/nixos/foo.nix")?, None);
+
+        let err = relative_import(
+            Path::new("/etc/nixos/foo.nix"),
+            Path::new("/etc"),
+            flake_dir,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not inside the flake directory"));
+        Ok(())
+    }
+
+    // Test building check results from outcomes
+    #[test]
+    fn test_check_result() {
+        let passed =
+            CheckResult::from_result("nix_available", Ok::<_, anyhow::Error>("2.24".into()));
+        assert!(passed.ok);
+        assert_eq!(passed.detail, "2.24");
+
+        let failed = CheckResult::from_result(
+            "registry_readable",
+            Err::<String, _>(anyhow!("missing").context("failed to load module registry")),
+        );
+        assert!(!failed.ok);
+        assert_eq!(failed.check, "registry_readable");
+        assert_eq!(failed.detail, "failed to load module registry: missing");
+    }
+
+    // Test expanding named module sets
+    #[test]
+    fn test_expand_set() -> Result<()> {
+        let mut registry = create_test_registry();
+        registry.sets.insert(
+            "pair".to_string(),
+            vec!["test1".to_string(), "test3".to_string()],
+        );
+        registry.sets.insert(
+            "broken".to_string(),
+            vec!["test1".to_string(), "nope".to_string()],
+        );
+
+        assert_eq!(registry.expand_set("pair")?, ["test1", "test3"]);
+        assert!(registry.expand_set("missing").is_err());
+        let err = registry.expand_set("broken").unwrap_err();
+        assert!(err.to_string().contains("nope"));
+
+        // Sets come along with introspected modules
+        let registry = ModuleRegistry::from_introspection(
+            r#"{"modules":[{"name":"rust"},{"name":"go"}],"sets":{"dev":["rust","go"]}}"#,
+        )?;
+        assert_eq!(registry.expand_set("dev")?, ["rust", "go"]);
+
+        Ok(())
+    }
+
+    // Test fuzzy matching for the interactive picker
+    #[test]
+    fn test_fuzzy_score() {
+        assert_eq!(fuzzy_score("", "gaming"), Some(0));
+        assert!(fuzzy_score("gmg", "Gaming").is_some());
+        assert_eq!(fuzzy_score("gx", "gaming"), None);
+
+        // Tighter and earlier matches rank first
+        let contiguous = fuzzy_score("gam", "gaming");
+        let spread = fuzzy_score("gam", "gpu-acceleration-media");
+        assert!(contiguous < spread);
+        assert!(fuzzy_score("pod", "podman") < fuzzy_score("pod", "rt.podman"));
+    }
+
+    // Test telling a clean activation from one with failed units and from a failure
+    #[test]
+    fn test_activation_outcome() -> Result<()> {
+        let switch = "switch-to-configuration";
+        assert_eq!(
+            activation_outcome(switch, Some(0), "")?,
+            ApplyOutcome::Applied
+        );
+        assert_eq!(
+            activation_outcome(switch, Some(4), "")?,
+            ApplyOutcome::AppliedWithWarnings
+        );
+        let err = activation_outcome(switch, Some(1), "").unwrap_err();
+        assert!(err.to_string().contains("exit code 1"));
+        assert!(activation_outcome(switch, None, "").is_err());
+
+        // nixos-rebuild exits with 1 after switch-to-configuration exited with 4
+        let log = "warning: error(s) occurred while switching to the new configuration\n";
+        assert_eq!(
+            activation_outcome("nixos-rebuild", Some(1), log)?,
+            ApplyOutcome::AppliedWithWarnings
+        );
+        assert!(activation_outcome("nixos-rebuild", Some(1), "error: build failed").is_err());
+        assert!(activation_outcome("nixos-rebuild", Some(4), "").is_err());
+        Ok(())
+    }
+
+    // Test that killing a child returns promptly even if a grandchild holds its output
+    #[test]
+    fn test_run_captured_kill_with_grandchild() -> Result<()> {
+        use std::process::Command;
+        use std::sync::atomic::AtomicI32;
+        use std::time::{Duration, Instant};
+
+        let child_pid = AtomicI32::new(0);
+        let started = Instant::now();
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30 & echo started; sleep 30"]);
+        let run = run_captured(&mut command, &child_pid, |elapsed| {
+            elapsed > Duration::from_millis(500)
+        })?;
+
+        assert!(run.killed);
+        assert!(run.log.contains("started"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(child_pid.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // Without a kill, the output is read to the end
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo done"]);
+        let run = run_captured(&mut command, &child_pid, |_| false)?;
+        assert!(!run.killed);
+        assert!(run.status.success());
+        assert_eq!(run.log, "done\n");
+        Ok(())
+    }
+
+    // Test that concurrent appenders never interleave records
+    #[test]
+    fn test_append_line_concurrent() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.jsonl");
+
+        let appenders: Vec<_> = (0..8)
+            .map(|writer| {
+                let path = path.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for record in 0..50 {
+                        let entry = HistoryEntry {
+                            time: record,
+                            action: format!("writer {writer}"),
+                            enabled: vec!["x".repeat(4096); 4],
+                            ok: true,
+                            staged: false,
+                        };
+                        append_line(&path, &serde_json::to_string(&entry)?)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for appender in appenders {
+            appender
+                .join()
+                .map_err(|_| anyhow!("appender panicked"))??;
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let entries = content
+            .lines()
+            .map(serde_json::from_str::<HistoryEntry>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries.len(), 8 * 50);
+        Ok(())
+    }
+
+    // Test reading the history file and filtering it by time and count
+    #[test]
+    fn test_history_filter() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.jsonl");
+        assert!(HistoryEntry::read_file(&path)?.is_empty());
+
+        for time in [100, 200, 300] {
+            let entry = HistoryEntry {
+                time,
+                action: "modules enabled".to_string(),
+                enabled: vec!["gaming".to_string()],
+                ok: true,
+                staged: time == 300,
+            };
+            append_line(&path, &serde_json::to_string(&entry)?)?;
+        }
+        let entries = HistoryEntry::read_file(&path)?;
+        assert_eq!(entries.len(), 3);
+        assert!(!entries[0].staged && entries[2].staged);
+
+        let times = |entries: Vec<HistoryEntry>| -> Vec<u64> {
+            entries.into_iter().map(|entry| entry.time).collect()
+        };
+        assert_eq!(
+            times(filter_history(entries.clone(), None, None)),
+            [100, 200, 300]
+        );
+        assert_eq!(
+            times(filter_history(entries.clone(), Some(200), None)),
+            [200, 300]
+        );
+        assert_eq!(times(filter_history(entries.clone(), None, Some(1))), [300]);
+        assert_eq!(
+            times(filter_history(entries.clone(), Some(100), Some(2))),
+            [200, 300]
+        );
+        assert!(filter_history(entries, Some(301), None).is_empty());
+
+        append_line(&path, "not json")?;
+        let err = HistoryEntry::read_file(&path).unwrap_err();
+        assert!(err.to_string().contains("line 4"));
+        Ok(())
+    }
+
+    // Test when output is colored, for each --color choice, NO_COLOR and terminal
+    #[test]
+    fn test_color_choice() -> Result<()> {
+        // (choice, NO_COLOR set, stdout is a terminal, expected)
+        let matrix = [
+            (ColorChoice::Auto, false, true, true),
+            (ColorChoice::Auto, false, false, false),
+            (ColorChoice::Auto, true, true, false),
+            (ColorChoice::Always, false, true, true),
+            (ColorChoice::Always, false, false, true),
+            (ColorChoice::Always, true, false, false),
+            (ColorChoice::Never, false, true, false),
+            (ColorChoice::Never, true, true, false),
+        ];
+        for (choice, no_color, is_terminal, expected) in matrix {
+            assert_eq!(
+                choice.enabled(no_color, is_terminal),
+                expected,
+                "{choice} with NO_COLOR={no_color} on a terminal={is_terminal}"
+            );
+        }
+
+        assert_eq!(ColorChoice::default(), ColorChoice::Auto);
+        assert_eq!("ALWAYS".parse::<ColorChoice>()?, ColorChoice::Always);
+        assert_eq!(ColorChoice::Never.to_string(), "never");
+        assert!("sometimes".parse::<ColorChoice>().is_err());
+        Ok(())
+    }
+
+    // Test parsing rebuild actions, as given by flag or environment
+    #[test]
+    fn test_rebuild_action() -> Result<()> {
+        assert_eq!(RebuildAction::default(), RebuildAction::Test);
+        assert_eq!("switch".parse::<RebuildAction>()?, RebuildAction::Switch);
+        assert_eq!("TEST".parse::<RebuildAction>()?, RebuildAction::Test);
+        assert_eq!(RebuildAction::Switch.to_string(), "switch");
+        assert_eq!("boot".parse::<RebuildAction>()?, RebuildAction::Boot);
+        assert!("dry-activate".parse::<RebuildAction>().is_err());
+        assert!("".parse::<RebuildAction>().is_err());
+
+        // Only boot leaves the running system alone
+        assert!(RebuildAction::Test.activates_now());
+        assert!(RebuildAction::Switch.activates_now());
+        assert!(!RebuildAction::Boot.activates_now());
+        Ok(())
+    }
+
+    // Test expanding unique module name prefixes
+    #[test]
+    fn test_resolve_module_prefixes() -> Result<()> {
+        let names: Vec<String> = ["media", "media-production", "gaming", "gpu"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let args =
+            |args: &[&str]| -> Vec<String> { args.iter().map(ToString::to_string).collect() };
+
+        // Unique prefixes expand, exact names win over longer matches
+        assert_eq!(
+            resolve_module_prefixes(&args(&["gam", "media-p", "media"]), &names)?,
+            ["gaming", "media-production", "media"]
+        );
+        // Unknown names are left for the caller to report
+        assert_eq!(resolve_module_prefixes(&args(&["vm"]), &names)?, ["vm"]);
+
+        let err = resolve_module_prefixes(&args(&["g"]), &names)
+            .err()
+            .ok_or_else(|| anyhow!("ambiguous prefix resolved"))?;
+        assert!(err.to_string().contains("gaming, gpu"));
+        assert!(resolve_module_prefixes(&args(&["med"]), &names).is_err());
+        Ok(())
+    }
+
+    // Test looking up programs in a PATH-style list
+    #[test]
+    fn test_find_in_path() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin = tempfile::tempdir()?;
+        let empty = tempfile::tempdir()?;
+        let nix = bin.path().join("nix");
+        std::fs::write(&nix, "#!/bin/sh\n")?;
+        std::fs::set_permissions(&nix, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::write(bin.path().join("nixos-rebuild"), "not executable")?;
+
+        let path = std::env::join_paths([empty.path(), bin.path()])?;
+        assert_eq!(find_in_path("nix", Some(&path)), Some(nix.clone()));
+        assert_eq!(find_in_path("nixos-rebuild", Some(&path)), None);
+        assert_eq!(find_in_path("nix", Some(empty.path().as_os_str())), None);
+        assert_eq!(find_in_path("nix", None), None);
+
+        // Paths are checked directly, without searching
+        assert_eq!(
+            find_in_path(&nix.to_string_lossy(), None),
+            Some(nix.clone())
+        );
+        Ok(())
+    }
+
+    // Test recognizing a built NixOS system
+    #[test]
+    fn test_check_system_toplevel() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let system = tempfile::tempdir()?;
+        assert!(check_system_toplevel(system.path().join("missing")).is_err());
+        assert!(check_system_toplevel(system.path()).is_err());
+
+        std::fs::write(system.path().join("nixos-version"), "25.05")?;
+        assert!(check_system_toplevel(system.path()).is_err());
+
+        let switch = system.path().join("bin/switch-to-configuration");
+        std::fs::create_dir(system.path().join("bin"))?;
+        std::fs::write(&switch, "#!/bin/sh\n")?;
+        assert!(check_system_toplevel(system.path()).is_err());
+        std::fs::set_permissions(&switch, std::fs::Permissions::from_mode(0o755))?;
+        assert_eq!(
+            check_system_toplevel(system.path())?,
+            system.path().canonicalize()?
+        );
+        Ok(())
+    }
+
+    // Test compact JSON output stays on one line
+    #[test]
+    fn test_to_json_compact() -> Result<()> {
+        let registry = create_test_registry();
+        let pretty = to_json(&registry.modules, true)?;
+        let compact = to_json(&registry.modules, false)?;
+        assert!(pretty.contains('\n'));
+        assert!(!compact.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&pretty)?,
+            serde_json::from_str::<serde_json::Value>(&compact)?
+        );
+        Ok(())
+    }
+
+    // Test ISO 8601 timestamp formatting
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1_760_599_261), "2025-10-16T07:21:01Z");
+        assert_eq!(format_timestamp(4_102_444_799), "2099-12-31T23:59:59Z");
+    }
+
+    // Test parsing human durations
+    #[test]
+    fn test_parse_duration() -> Result<()> {
+        let secs = |input| parse_duration(input).map(|duration| duration.as_secs());
+
+        assert_eq!(secs("90")?, 90);
+        assert_eq!(secs("90s")?, 90);
+        assert_eq!(secs("30m")?, 30 * 60);
+        assert_eq!(secs("24h")?, 24 * 60 * 60);
+        assert_eq!(secs("7d")?, 7 * 24 * 60 * 60);
+        assert_eq!(secs("1h30m")?, 90 * 60);
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("-5m").is_err());
+
+        Ok(())
+    }
+
+    // Test computing the diff to a desired state
+    #[test]
+    fn test_state_diff() -> Result<()> {
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["test1".to_string(), "test2".to_string()]);
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("desired.json");
+        std::fs::write(
+            &path,
+            r#"{"enabled": ["test2", "test3"], "settings": {"test2": {"vendor": "amd"}}}"#,
+        )?;
+        let desired = StateFile::from_file(&path)?;
+
+        let diff = module_file.diff(&desired);
+        assert_eq!(diff.enable, vec!["test3".to_string()]);
+        assert_eq!(diff.disable, vec!["test1".to_string()]);
+        assert_eq!(diff.settings_changed, vec!["test2".to_string()]);
+
+        // Reaching the desired state leaves nothing to do
+        let reached = ModuleFile::from_state(desired.clone());
+        assert!(reached.diff(&desired).is_empty());
+
+        // Invalid setting keys are rejected
+        std::fs::write(
+            &path,
+            r#"{"enabled": [], "settings": {"x": {"1bad": "y"}}}"#,
+        )?;
+        assert!(StateFile::from_file(&path).is_err());
+
+        Ok(())
+    }
+
+    // Test recovering enabled modules from registry states
+    #[test]
+    fn test_registry_enabled_modules() {
+        let mut registry = create_test_registry();
+        assert!(registry.enabled_modules().is_empty());
+
+        registry.set_state("test1", ModuleState::Enabled);
+        registry.set_state("test2", ModuleState::Uncertain);
+        registry.set_state("test3", ModuleState::Enabled);
+        assert_eq!(
+            registry.enabled_modules(),
+            vec!["test1".to_string(), "test3".to_string()]
+        );
+    }
+
+    // Test resolving numeric module indices from the last listing
+    #[test]
+    fn test_resolve_module_indices() -> Result<()> {
+        let names = |names: &[&str]| names.iter().map(|n| (*n).to_string()).collect::<Vec<_>>();
+        let current = names(&["gaming", "42", "rt.wine"]);
+
+        // Names and indices can be mixed; all-digit module names stay names
+        let resolved =
+            resolve_module_indices(&names(&["3", "gaming", "42"]), Some(&current), &current)?;
+        assert_eq!(resolved, names(&["rt.wine", "gaming", "42"]));
+
+        // Out of range indices are rejected
+        assert!(resolve_module_indices(&names(&["0"]), Some(&current), &current).is_err());
+        assert!(resolve_module_indices(&names(&["4"]), Some(&current), &current).is_err());
+
+        // Indices need a listing that matches the current modules
+        assert!(resolve_module_indices(&names(&["1"]), None, &current).is_err());
+        let stale = names(&["gaming", "rt.wine"]);
+        assert!(resolve_module_indices(&names(&["1"]), Some(&stale), &current).is_err());
+
+        // Without indices no listing is needed
+        assert_eq!(
+            resolve_module_indices(&names(&["gaming"]), None, &current)?,
+            names(&["gaming"])
+        );
+
+        Ok(())
+    }
+
+    // Test anchoring relative flake references to the original directory
+    #[test]
+    fn test_resolve_flake_ref() {
+        let cwd = std::path::Path::new("/home/user/config");
+        let resolve = |flake_ref| resolve_flake_ref(flake_ref, cwd);
+
+        assert_eq!(resolve("."), "/home/user/config");
+        assert_eq!(resolve("./runtime"), "/home/user/config/runtime");
+        assert_eq!(resolve("../other?dir=x"), "/home/user/other?dir=x");
+        assert_eq!(resolve("path:sub#attr"), "path:/home/user/config/sub#attr");
+
+        // Absolute paths and URLs pass through unchanged
+        assert_eq!(resolve("/etc/nixos"), "/etc/nixos");
+        assert_eq!(resolve("path:/etc/nixos"), "path:/etc/nixos");
+        assert_eq!(resolve("github:owner/repo"), "github:owner/repo");
+    }
+
+    // Test finding modules that depend on a module
+    #[test]
+    fn test_dependents() {
+        let mut registry = create_test_registry();
+        registry.modules[0].deps = vec!["test3".to_string()];
+        registry.modules[1].deps = vec!["test1".to_string(), "test3".to_string()];
+
+        assert_eq!(
+            registry.dependents("test3"),
+            vec!["test1".to_string(), "test2".to_string()]
+        );
+        assert_eq!(registry.dependents("test1"), vec!["test2".to_string()]);
+        assert!(registry.dependents("test2").is_empty());
+    }
+
+    // Test splitting protected modules from the ones that may be disabled
+    #[test]
+    fn test_split_protected() {
+        let registry = ModuleRegistry::new(vec![
+            Module {
+                name: "networking".to_string(),
+                protected: true,
+                ..Default::default()
+            },
+            Module {
+                name: "gaming".to_string(),
+                ..Default::default()
+            },
+        ]);
+        let modules = vec!["networking".to_string(), "gaming".to_string()];
+
+        // Protected modules are refused by default
+        assert!(registry.is_protected("networking"));
+        assert!(!registry.is_protected("gaming"));
+        let (removable, kept) = registry.split_protected(&modules, false);
+        assert_eq!(removable, vec!["gaming".to_string()]);
+        assert_eq!(kept, vec!["networking".to_string()]);
+
+        // Forcing allows removing them
+        let (removable, kept) = registry.split_protected(&modules, true);
+        assert_eq!(removable, modules);
+        assert!(kept.is_empty());
+    }
+
+    // Test refusing to replace a protected module unless forced
+    #[test]
+    fn test_check_replaceable() {
+        let registry = ModuleRegistry::new(vec![
+            Module {
+                name: "networking".to_string(),
+                protected: true,
+                ..Default::default()
+            },
+            Module {
+                name: "gaming".to_string(),
+                ..Default::default()
+            },
+        ]);
+
+        assert!(registry.check_replaceable("gaming", false).is_ok());
+        let err = registry
+            .check_replaceable("networking", false)
+            .expect_err("protected module replaced without forcing");
+        assert!(err.to_string().contains("--force-protected"));
+        assert!(registry.check_replaceable("networking", true).is_ok());
+    }
+
+    // Test keeping modules on reset through the registry flag, --keep and --keep-none
+    #[test]
+    fn test_split_reset() {
+        let registry = ModuleRegistry::new(vec![
+            Module {
+                name: "networking".to_string(),
+                protected: true,
+                ..Default::default()
+            },
+            Module {
+                name: "sshd".to_string(),
+                keep_on_reset: true,
+                ..Default::default()
+            },
+            Module {
+                name: "gaming".to_string(),
+                ..Default::default()
+            },
+            Module {
+                name: "audio".to_string(),
+                ..Default::default()
+            },
+        ]);
+        let modules = vec![
+            "networking".to_string(),
+            "sshd".to_string(),
+            "gaming".to_string(),
+            "audio".to_string(),
+        ];
+        assert!(registry.is_kept_on_reset("sshd"));
+        assert!(!registry.is_kept_on_reset("networking"));
+
+        // The flag keeps modules by default
+        let (removed, kept) = registry.split_reset(&modules, &ResetKeep::default());
+        assert_eq!(removed, ["gaming", "audio"]);
+        assert_eq!(kept, ["networking", "sshd"]);
+
+        // --keep adds to the flagged modules for one reset
+        let keep = ResetKeep {
+            keep: vec!["audio".to_string()],
+            ..ResetKeep::default()
+        };
+        let (removed, kept) = registry.split_reset(&modules, &keep);
+        assert_eq!(removed, ["gaming"]);
+        assert_eq!(kept, ["networking", "sshd", "audio"]);
+
+        // --keep-none overrides the flag, but protected modules still need forcing
+        let keep = ResetKeep {
+            keep_none: true,
+            ..ResetKeep::default()
+        };
+        let (removed, kept) = registry.split_reset(&modules, &keep);
+        assert_eq!(removed, ["sshd", "gaming", "audio"]);
+        assert_eq!(kept, ["networking"]);
+
+        let keep = ResetKeep {
+            force_protected: true,
+            keep_none: true,
+            ..ResetKeep::default()
+        };
+        let (removed, kept) = registry.split_reset(&modules, &keep);
+        assert_eq!(removed, modules);
+        assert!(kept.is_empty());
+    }
+
+    // Test reading a registry from flake introspection and comparing it with the previous one
+    #[test]
+    fn test_registry_from_introspection() -> Result<()> {
+        let previous = ModuleRegistry::new(vec![
+            Module {
+                name: "gaming".to_string(),
+                desc: "Games".to_string(),
+                state: ModuleState::Enabled,
+                ..Default::default()
+            },
+            Module {
+                name: "old".to_string(),
+                ..Default::default()
+            },
+            Module {
+                name: "same".to_string(),
+                ..Default::default()
+            },
+        ]);
+
+        let mut registry = ModuleRegistry::from_introspection(
+            r#"{"modules": [
+                {"name": "gaming", "desc": "Steam and friends"},
+                {"name": "same"},
+                {"name": "new", "path": "/nix/store/new"}
+            ]}"#,
+        )?;
+        registry.inherit_states(&previous);
+        assert_eq!(registry.get_state("gaming"), ModuleState::Enabled);
+        assert_eq!(registry.get_state("new"), ModuleState::Disabled);
+
+        let changes = registry.changes_since(&previous);
+        assert_eq!(changes.added, vec!["new".to_string()]);
+        assert_eq!(changes.removed, vec!["old".to_string()]);
+        assert_eq!(changes.changed, vec!["gaming".to_string()]);
+
+        // Plain lists are accepted, malformed shapes are not
+        assert!(ModuleRegistry::from_introspection(r#"[{"name": "a"}]"#).is_ok());
+        assert!(ModuleRegistry::from_introspection(r#"{"name": "a"}"#).is_err());
+        assert!(ModuleRegistry::from_introspection(r#"[{"desc": "no name"}]"#).is_err());
+        assert!(ModuleRegistry::from_introspection(r#"[{"name": ""}]"#).is_err());
+        assert!(ModuleRegistry::from_introspection(r#"[{"name": "a"}, {"name": "a"}]"#).is_err());
+        Ok(())
+    }
+
+    // Test deciding the origin of modules, explicitly or by the `rt.` prefix
+    #[test]
+    fn test_module_origin() -> Result<()> {
+        let registry: ModuleRegistry = serde_json::from_str(
+            r#"{"modules": [
+                {"name": "rt.gimp"},
+                {"name": "mine"},
+                {"name": "vendor-gpu", "origin": "upstream"},
+                {"name": "rt.local", "origin": "user"}
+            ]}"#,
+        )?;
+
+        // Without an origin the `rt.` prefix decides
+        assert!(registry.is_upstream("rt.gimp"));
+        assert!(!registry.is_upstream("mine"));
+
+        // An explicit origin takes precedence over the prefix
+        assert_eq!(registry.modules[2].origin, Some(ModuleOrigin::Upstream));
+        assert!(registry.is_upstream("vendor-gpu"));
+        assert!(!registry.is_upstream("rt.local"));
+        Ok(())
+    }
+
+    // Test which statuses pass a status check, with and without a threshold
+    #[test]
+    fn test_status_check_passes() {
+        let status = |name: &str, state: ModuleState| ModuleStatus {
+            name: name.to_string(),
+            path: String::new(),
+            state,
+            desc: String::new(),
+            enabled_at: None,
+            pending: false,
+            ephemeral: false,
+        };
+        let statuses = vec![
+            status("a", ModuleState::Enabled),
+            status("b", ModuleState::Enabled),
+            status("c", ModuleState::Disabled),
+            status("d", ModuleState::Uncertain),
+        ];
+
+        // By default any module that is not enabled fails the check
+        assert!(!status_check_passes(&statuses, None));
+        assert!(status_check_passes(&statuses[..2], None));
+
+        // A threshold replaces that rule, passing exactly at the threshold
+        assert!(status_check_passes(&statuses, Some(1)));
+        assert!(status_check_passes(&statuses, Some(2)));
+        assert!(!status_check_passes(&statuses, Some(3)));
+        assert!(status_check_passes(&statuses, Some(0)));
+    }
+
+    // Test normalizing a hand-edited state file
+    #[test]
+    fn test_module_file_normalize() -> Result<()> {
+        let state = StateFile::parse(
+            r#"{"enabled": ["ssh", "gaming", "ssh", "ssh"], "settings": {"cuda": {"a": "b"}, "ssh": {"port": "22"}}}"#,
+        )?;
+        let mut module_file = ModuleFile::from_state(state);
+
+        let report = module_file.normalize();
+        assert_eq!(report.duplicates, ["ssh"]);
+        assert!(report.reordered);
+        assert_eq!(report.dropped_settings, ["cuda"]);
+        assert_eq!(module_file.active_modules(), ["gaming", "ssh"]);
+        assert!(module_file.settings.contains_key("ssh"));
+
+        // A normalized file has nothing left to change
+        assert!(module_file.normalize().is_empty());
+        Ok(())
+    }
+
+    // Test normalizing drops paths and ephemeral marks of modules that aren't enabled,
+    // but keeps the paths of persisted modules
+    #[test]
+    fn test_module_file_normalize_paths() -> Result<()> {
+        let state = StateFile::parse(
+            r#"{"enabled": ["ssh"],
+                "paths": {"ssh": "/a.nix", "old": "/b.nix", "kept": "/c.nix"},
+                "persisted": ["kept"],
+                "ephemeral": {"ssh": "boot-a", "gone": "boot-a"}}"#,
+        )?;
+        let mut module_file = ModuleFile::from_state(state);
+
+        let report = module_file.normalize();
+        assert_eq!(report.dropped_paths, ["old"]);
+        assert_eq!(report.dropped_ephemeral, ["gone"]);
+        assert_eq!(
+            module_file.paths().keys().collect::<Vec<_>>(),
+            ["kept", "ssh"]
+        );
+        assert!(module_file.is_persisted("kept"));
+        assert_eq!(module_file.ephemeral_modules(), ["ssh"]);
+        assert!(module_file.normalize().is_empty());
+
+        // Forgetting a path also forgets that it was persisted
+        assert!(module_file.remove_path("kept"));
+        assert!(!module_file.is_persisted("kept"));
+        Ok(())
+    }
+
+    // Test rendering metrics, with per-module gauges only on request
+    #[test]
+    fn test_render_metrics() {
+        let status = |name: &str, state| ModuleStatus {
+            name: name.to_string(),
+            path: String::new(),
+            state,
+            desc: String::new(),
+            enabled_at: None,
+            pending: false,
+            ephemeral: false,
+        };
+        let statuses = vec![
+            status("gaming", ModuleState::Enabled),
+            status("ssh", ModuleState::Disabled),
+            status("odd\"name", ModuleState::Uncertain),
+        ];
+
+        let metrics = render_metrics(&statuses, 2, false);
+        assert!(metrics.contains("# TYPE runtime_modules_total gauge\nruntime_modules_total 3\n"));
+        assert!(metrics.contains("\nruntime_modules_enabled_total 1\n"));
+        assert!(metrics.contains("\nruntime_modules_uncertain_total 1\n"));
+        assert!(metrics.contains("\nruntime_modules_pending 2\n"));
+        assert!(!metrics.contains("module="));
+
+        let metrics = render_metrics(&statuses, 0, true);
+        assert!(metrics.contains("runtime_modules_module_enabled{module=\"gaming\"} 1\n"));
+        assert!(metrics.contains("runtime_modules_module_enabled{module=\"ssh\"} 0\n"));
+        assert!(metrics.contains("runtime_modules_module_enabled{module=\"odd\\\"name\"} 0\n"));
+    }
+
+    // Test resolving module paths back to names, failing on unknown paths
+    #[test]
+    fn test_registry_resolve_paths() -> Result<()> {
+        let registry = ModuleRegistry::from_reader(
+            r#"{"modules": [
+                {"name": "gaming", "path": "/nix/store/aaa-gaming"},
+                {"name": "ssh"}
+            ]}"#
+            .as_bytes(),
+        )?;
+
+        assert_eq!(
+            registry.name_for_path("/nix/store/aaa-gaming"),
+            Some("gaming")
+        );
+        assert_eq!(
+            registry.name_for_path("/nix/store/aaa-gaming/"),
+            Some("gaming")
+        );
+        assert_eq!(registry.name_for_path("/nix/store/bbb-unknown"), None);
+        assert_eq!(registry.name_for_path(""), None);
+
+        let no_paths = BTreeMap::new();
+        let args = vec!["ssh".to_string(), "/nix/store/aaa-gaming".to_string()];
+        assert_eq!(registry.resolve_paths(&args, &no_paths)?, ["ssh", "gaming"]);
+
+        let err = registry
+            .resolve_paths(&["/nix/store/bbb-unknown".to_string()], &no_paths)
+            .unwrap_err();
+        assert!(err.to_string().contains("/nix/store/bbb-unknown"));
+        Ok(())
+    }
+
+    // Test resolving paths with a registry that has no `path` fields, as older
+    // generated registries, through the paths recorded in the state file
+    #[test]
+    fn test_registry_resolve_state_paths() -> Result<()> {
+        let registry = ModuleRegistry::from_reader(
+            r#"{"modules": [{"name": "gaming"}, {"name": "ssh"}]}"#.as_bytes(),
+        )?;
+        let mut module_file = ModuleFile::empty();
+        module_file.set_path("temp-foo", "/nix/store/bbb-foo.nix");
+
+        assert_eq!(
+            registry.resolve_paths(
+                &["/nix/store/bbb-foo.nix/".to_string()],
+                module_file.paths()
+            )?,
+            ["temp-foo"]
+        );
+        assert!(
+            registry
+                .resolve_paths(&["/nix/store/aaa-gaming".to_string()], module_file.paths())
+                .is_err()
+        );
+        Ok(())
+    }
+
+    // Test resolving transitive dependencies, tolerating cycles and unknown modules
+    #[test]
+    fn test_registry_resolve_dependencies() -> Result<()> {
+        let registry = ModuleRegistry::from_reader(
+            r#"{"modules": [
+                {"name": "media", "deps": ["audio", "codecs"]},
+                {"name": "audio", "deps": ["codecs", "missing"]},
+                {"name": "codecs", "deps": ["media"]},
+                {"name": "ssh"}
+            ]}"#
+            .as_bytes(),
+        )?;
+
+        assert_eq!(
+            registry.resolve_dependencies(&["media".to_string()]),
+            ["media", "audio", "codecs", "missing"]
+        );
+        assert_eq!(
+            registry.resolve_dependencies(&["ssh".to_string(), "audio".to_string()]),
+            ["ssh", "audio", "codecs", "missing", "media"]
+        );
+        assert!(registry.resolve_dependencies(&[]).is_empty());
+        Ok(())
+    }
+
+    // Test answering the effective state of many modules at once
+    #[test]
+    fn test_registry_effective_states() {
+        let mut registry = create_test_registry();
+        registry.set_state("test3", ModuleState::Uncertain);
+        let active = vec!["test1".to_string(), "test3".to_string()];
+
+        let states = registry.effective_states(&active, &["test1", "test2", "test3", "missing"]);
+        assert_eq!(states["test1"], ModuleState::Enabled);
+        assert_eq!(states["test2"], ModuleState::Disabled);
+        assert_eq!(states["test3"], ModuleState::Uncertain);
+        assert_eq!(states["missing"], ModuleState::Disabled);
+        assert_eq!(states.len(), 4);
+
+        assert_eq!(
+            registry.effective_state("test2", true),
+            ModuleState::Enabled
+        );
+        assert_eq!(
+            registry.effective_state("test3", false),
+            ModuleState::Uncertain
+        );
+    }
+
+    // Test adding modules, with transient ones left out of the saved registry
+    #[test]
+    fn test_registry_add_module() -> Result<()> {
+        let mut registry = create_test_registry();
+        registry.init_lookup();
+
+        registry.add_module(Module {
+            name: "kept".to_string(),
+            path: "/nix/store/aaa-kept.nix".to_string(),
+            ..Module::default()
+        })?;
+        registry.add_transient_module(Module {
+            name: "temp-foo".to_string(),
+            path: "/nix/store/bbb-foo.nix".to_string(),
+            ..Module::default()
+        })?;
+        assert!(registry.verify_modules_exist(&["kept".to_string(), "temp-foo".to_string()]));
+        assert_eq!(
+            registry.name_for_path("/nix/store/bbb-foo.nix"),
+            Some("temp-foo")
+        );
+        assert!(registry.is_transient("temp-foo"));
+        assert!(!registry.is_transient("kept"));
+
+        let err = registry
+            .add_module(Module {
+                name: "test1".to_string(),
+                ..Module::default()
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("test1"));
+
+        let temp_file = NamedTempFile::new()?;
+        registry.save(temp_file.path())?;
+        let saved = ModuleRegistry::from_file(temp_file.path())?;
+        assert!(saved.verify_modules_exist(&["kept".to_string()]));
+        assert!(!saved.verify_modules_exist(&["temp-foo".to_string()]));
+        Ok(())
+    }
+
+    // Test parsing octal file modes
+    #[test]
+    fn test_parse_file_mode() -> Result<()> {
+        assert_eq!(parse_file_mode("644")?, 0o644);
+        assert_eq!(parse_file_mode("0640")?, 0o640);
+        assert_eq!(parse_file_mode("0o600")?, 0o600);
+        assert_eq!(parse_file_mode("2750")?, 0o2750);
+        for invalid in ["", "0o", "648", "rw-r--r--", "-644", "07777"] {
+            assert!(parse_file_mode(invalid).is_err(), "accepted {invalid:?}");
+        }
+        Ok(())
+    }
+
+    // Test saving the state file with configured permissions
+    #[test]
+    fn test_module_file_save_mode() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("state.json");
+
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["test1".to_string()]);
+        module_file.save(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode() & 0o7777;
+        assert_eq!(mode, DEFAULT_FILE_MODE);
+
+        module_file.enable_modules(&["test2".to_string()]);
+        module_file.set_file_mode(0o640, None);
+        module_file.save(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o640);
+
+        // Changing only the mode still applies it, without rewriting the content
+        module_file.set_file_mode(0o600, None);
+        assert!(!module_file.save(&path)?);
+        let mode = std::fs::metadata(&path)?.permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o600);
+
+        // A new file gets the mode right away, leaving no temporary file behind
+        let new_path = temp_dir.path().join("new.json");
+        assert!(module_file.save(&new_path)?);
+        let mode = std::fs::metadata(&new_path)?.permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o600);
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 2);
+        Ok(())
+    }
+
+    // Test recording the files of modules enabled with 'enable --path'
+    #[test]
+    fn test_module_file_paths() -> Result<()> {
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["temp-foo".to_string()]);
+        module_file.set_path("temp-foo", "/nix/store/bbb-foo.nix");
+
+        let state = StateFile::parse(&module_file.generate_content())?;
+        assert_eq!(state.paths["temp-foo"], "/nix/store/bbb-foo.nix");
+
+        let mut restored = ModuleFile::from_state(state);
+        assert_eq!(restored.paths(), module_file.paths());
+        assert!(restored.remove_path("temp-foo"));
+        assert!(!restored.remove_path("temp-foo"));
+        assert!(!restored.generate_content().contains("paths"));
+        Ok(())
+    }
+
+    // Test reading a registry from a reader, as with --registry-from-stdin
+    #[test]
+    fn test_registry_from_reader() -> Result<()> {
+        let input = r#"{"modules": [{"name": "ssh", "state": "Enabled"}]}"#;
+        let registry = ModuleRegistry::from_reader(input.as_bytes())?;
+        assert!(registry.has_lookup_map());
+        assert_eq!(registry.get_state("ssh"), ModuleState::Enabled);
+
+        assert!(ModuleRegistry::from_reader(&b"{\"modules\": ["[..]).is_err());
+        Ok(())
+    }
+
+    // Test grouping modules by tag, listing a module under each of its tags
+    #[test]
+    fn test_registry_tag_groups() -> Result<()> {
+        let registry = ModuleRegistry::from_introspection(
+            r#"[
+                {"name": "gaming", "tags": ["games", "gpu", "games"]},
+                {"name": "cuda", "tags": ["gpu"]},
+                {"name": "ssh"}
+            ]"#,
+        )?;
+
+        let (groups, untagged) = registry.tag_groups();
+        assert_eq!(groups.keys().collect::<Vec<_>>(), ["games", "gpu"]);
+        assert_eq!(groups["games"], ["gaming"]);
+        assert_eq!(groups["gpu"], ["gaming", "cuda"]);
+        assert_eq!(untagged, ["ssh"]);
+        Ok(())
+    }
+
+    // Test reading health check commands from registry entries
+    #[test]
+    fn test_registry_health_check() -> Result<()> {
+        let registry = ModuleRegistry::from_introspection(
+            r#"[
+                {"name": "ssh", "health_check": "systemctl is-active --quiet sshd"},
+                {"name": "gaming"}
+            ]"#,
+        )?;
+
+        assert_eq!(
+            registry.health_check("ssh"),
+            Some("systemctl is-active --quiet sshd")
+        );
+        assert_eq!(registry.health_check("gaming"), None);
+        assert_eq!(registry.health_check("missing"), None);
+        Ok(())
+    }
+
+    // Test that the registry schema describes every serialized module field
+    #[test]
+    fn test_registry_schema_covers_module_fields() -> Result<()> {
+        let module = Module {
+            name: "gaming".to_string(),
+            path: "/nix/store/aaa-gaming".to_string(),
+            desc: "Games".to_string(),
+            state: ModuleState::Enabled,
+            deps: vec!["audio".to_string()],
+            conflicts: vec!["headless".to_string()],
+            protected: true,
+            keep_on_reset: true,
+            origin: Some(ModuleOrigin::User),
+            tags: vec!["games".to_string()],
+            health_check: Some("systemctl is-active --quiet steam".to_string()),
+        };
+        let serialized = serde_json::to_value(&module)?;
+        let schema = registry_schema();
+        let properties = &schema["$defs"]["module"]["properties"];
+
+        for field in serialized
+            .as_object()
+            .ok_or_else(|| anyhow!("module is not an object"))?
+            .keys()
+        {
+            assert!(properties.get(field).is_some(), "schema lacks {field}");
+        }
+        assert!(schema["properties"].get("modules").is_some());
+        Ok(())
+    }
+
+    // Test the enabled-order and sorted views of the active modules
+    #[test]
+    fn test_module_file_active_modules_accessors() {
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&["zeta".to_string(), "alpha".to_string()]);
+
+        assert_eq!(
+            module_file.active_modules(),
+            &["zeta".to_string(), "alpha".to_string()]
+        );
+        assert_eq!(
+            module_file.enabled_modules(),
+            vec!["alpha".to_string(), "zeta".to_string()]
+        );
+    }
+
+    // Test refusing a read-only state directory
+    #[test]
+    fn test_ensure_writable_dir() -> Result<()> {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        assert!(ensure_writable_dir(dir.path()).is_ok());
+
+        // A read-only directory is refused with an explanation
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555))?;
+        let err = ensure_writable_dir(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("is not writable"));
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755))?;
+
+        // So is a missing one
+        assert!(ensure_writable_dir(dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    // Test requiring a flake.nix in the flake directory
+    #[test]
+    fn test_ensure_flake_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        let err = ensure_flake_dir(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("no flake.nix found"));
+
+        std::fs::write(dir.path().join("flake.nix"), "{ }")?;
+        assert!(ensure_flake_dir(dir.path()).is_ok());
+        Ok(())
+    }
+
+    // Test rendering tables, truncating long cells to the available width
+    #[test]
+    fn test_render_table() {
+        let rows = vec![
+            vec![
+                "1".to_string(),
+                "gaming".to_string(),
+                "Steam and friends".to_string(),
+            ],
+            vec!["2".to_string(), "rt.gimp".to_string(), String::new()],
+        ];
+
+        let table = render_table(&["#", "Name", "Description"], &rows, 80, true);
+        assert_eq!(
+            table,
+            "+---+---------+-------------------+\n\
+             | # | Name    | Description       |\n\
+             +---+---------+-------------------+\n\
+             | 1 | gaming  | Steam and friends |\n\
+             | 2 | rt.gimp |                   |\n\
+             +---+---------+-------------------+\n"
+        );
+
+        // Long descriptions are truncated to the available width
+        let table = render_table(&["#", "Name", "Description"], &rows, 30, false);
+        assert!(table.lines().all(|line| line.chars().count() <= 30));
+        assert!(table.contains("│ Steam and f… │"));
+
+        // Without room, the last column keeps one character and the ellipsis
+        let table = render_table(&["#", "Name", "Description"], &rows, 18, false);
+        assert!(table.contains("│ S… │"));
+        let table = render_table(&["#", "Name", "Description"], &rows, 18, true);
+        assert!(table.contains("| S... |"));
+    }
+
+    // Test confirming states reports unknown modules and can be repeated
+    #[test]
+    fn test_confirm_states_orphans_and_idempotence() {
+        let mut registry = create_test_registry();
+        let active = vec![
+            "test1".to_string(),
+            "orphan".to_string(),
+            "test3".to_string(),
+        ];
+
+        let unknown = registry.confirm_states(&active);
+        assert_eq!(unknown, vec!["orphan".to_string()]);
+        assert_eq!(registry.get_state("test1"), ModuleState::Enabled);
+        assert_eq!(registry.get_state("test2"), ModuleState::Disabled);
+        assert_eq!(registry.get_state("test3"), ModuleState::Enabled);
+
+        // Applying the same input again changes nothing
+        let states: Vec<ModuleState> = registry.modules.iter().map(|m| m.state.clone()).collect();
+        assert_eq!(registry.confirm_states(&active), unknown);
+        let again: Vec<ModuleState> = registry.modules.iter().map(|m| m.state.clone()).collect();
+        assert_eq!(states, again);
+    }
+
+    // Test parsing module states case-insensitively
+    #[test]
+    fn test_module_state_from_str() -> Result<()> {
+        assert_eq!("enabled".parse::<ModuleState>()?, ModuleState::Enabled);
+        assert_eq!("Disabled".parse::<ModuleState>()?, ModuleState::Disabled);
+        assert_eq!("UNCERTAIN".parse::<ModuleState>()?, ModuleState::Uncertain);
+        assert!("on".parse::<ModuleState>().is_err());
+        Ok(())
+    }
+
+    // Test that displayed module states parse back to themselves
+    #[test]
+    fn test_module_state_display_round_trip() -> Result<()> {
+        for state in [
+            ModuleState::Enabled,
+            ModuleState::Disabled,
+            ModuleState::Uncertain,
+        ] {
+            let text = state.to_string();
+            assert_eq!(text, text.to_lowercase());
+            assert_eq!(text.parse::<ModuleState>()?, state);
+        }
+        assert_eq!("Enabled".parse::<ModuleState>()?.to_string(), "enabled");
+        Ok(())
+    }
+
+    // Test selecting modules by tag
+    #[test]
+    fn test_enable_and_disable_by_tag() {
+        let registry = ModuleRegistry::new(vec![
+            Module {
+                name: "obs".to_string(),
+                tags: vec!["media".to_string()],
+                ..Default::default()
+            },
+            Module {
+                name: "kdenlive".to_string(),
+                tags: vec!["media".to_string(), "editing".to_string()],
+                ..Default::default()
+            },
+            Module {
+                name: "gaming".to_string(),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(
+            registry.modules_with_tag("media"),
+            vec!["obs".to_string(), "kdenlive".to_string()]
+        );
+        assert!(registry.modules_with_tag("missing").is_empty());
+
+        let mut module_file = ModuleFile::empty();
+        module_file.enable_modules(&registry.modules_with_tag("media"));
+        module_file.enable_modules(&["gaming".to_string()]);
+
+        let removed = module_file.disable_modules(&registry.modules_with_tag("media"));
+        assert_eq!(removed.len(), 2);
+        assert_eq!(module_file.active_modules(), &["gaming".to_string()]);
+    }
 }